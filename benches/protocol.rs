@@ -0,0 +1,145 @@
+//! Per-event cost of the event path, measured against the 125µs between
+//! two reports of a mouse polling at 8 kHz.
+//!
+//! `cargo bench --bench protocol`, no external harness needed.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    net::{IpAddr, Ipv4Addr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use lan_mouse::{
+    config::{Client, Clients, Config},
+    event::{self, KeyboardEvent, PointerEvent},
+    protocol::{Connection, Decode, Encode, Position},
+};
+
+/// counts allocations to show the event path does not allocate
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// one second of reports at 8 kHz
+const EVENTS: u64 = 8000;
+const REPORT_INTERVAL: Duration = Duration::from_micros(125);
+
+fn motion(i: u64) -> event::Event {
+    event::Event::Pointer(PointerEvent::Motion {
+        time: i as u32,
+        dx: 1.25,
+        dy: -0.5,
+    })
+}
+
+fn bench(name: &str, events: u64, mut f: impl FnMut(u64)) {
+    // warm up, buffers grow to their final size
+    for i in 0..events / 10 {
+        f(i);
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for i in 0..events {
+        f(i);
+    }
+    let per_event = start.elapsed() / events as u32;
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{:<24} {:>8.0?}/event {:>6.2}% of 125µs {:>6.2} allocations/event",
+        name,
+        per_event,
+        100.0 * per_event.as_secs_f64() / REPORT_INTERVAL.as_secs_f64(),
+        allocations as f64 / events as f64,
+    );
+}
+
+fn config(port: u16, peer: u16) -> Config {
+    let client = Client {
+        host_name: None,
+        ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        port: Some(peer),
+        keyboard: None,
+    };
+    Config {
+        client: Clients {
+            left: Some(client),
+            right: None,
+            top: None,
+            bottom: None,
+        },
+        port: Some(port),
+        metrics_port: None,
+        evdev: None,
+        batch_delay: None,
+    }
+}
+
+fn main() {
+    let mut buf = Vec::new();
+    bench("encode", EVENTS, |i| {
+        buf.clear();
+        motion(i).encode_into(&mut buf);
+        black_box(&buf);
+    });
+
+    let encoded = motion(0).encode();
+    bench("decode", EVENTS, |_| {
+        black_box(event::Event::decode(black_box(&encoded)).unwrap());
+    });
+
+    // motion and frame over loopback, from send_event to receive_event
+    let sender = Connection::new(config(42170, 42171));
+    let receiver = Connection::new(config(42171, 42170));
+    sender.peers().switch_to(Position::Left).unwrap();
+    let mut received = 0;
+    bench("loopback (motion+frame)", EVENTS, |i| {
+        sender.send_event(motion(i));
+        sender.send_event(event::Event::Pointer(PointerEvent::Frame));
+        while let Some(event) = receiver.receive_event() {
+            black_box(event);
+            received += 1;
+        }
+    });
+    assert!(received > 0, "nothing arrived over loopback");
+
+    // keys are numbered, acknowledged and kept until then
+    let sender = Connection::new(config(42172, 42173));
+    let receiver = Connection::new(config(42173, 42172));
+    sender.peers().switch_to(Position::Left).unwrap();
+    let mut received = 0;
+    bench("reliable (key)", EVENTS, |i| {
+        sender.send_event(event::Event::Keyboard(KeyboardEvent::Key {
+            time: i as u32,
+            key: 30,
+            state: (i % 2) as u32,
+        }));
+        while let Some(event) = receiver.receive_event() {
+            black_box(event);
+            received += 1;
+        }
+        // take in the acknowledgements
+        sender.dispatch();
+    });
+    assert!(received > 0, "no key arrived over loopback");
+}
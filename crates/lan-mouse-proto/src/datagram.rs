@@ -0,0 +1,177 @@
+//! Datagrams carrying events.
+//!
+//! A datagram is a single encoded event, a [`BATCH`] of events or one of the
+//! headers below in front of either. Every datagram to a peer is wrapped
+//! in a [`Sequenced`] header, key, button, modifier and control events
+//! additionally in a [`Reliable`] one, which the receiver answers with an [`Ack`]:
+//!
+//! ```text
+//! [SEQUENCED session seq] [RELIABLE session first seq] event
+//! [SEQUENCED session seq] [BATCH] event event ...
+//! [SEQUENCED session seq] [ACK session next]
+//! ```
+//!
+//! Sessions are picked at random by the sender, so a restarted sender starting
+//! over at sequence number 0 is not mistaken for sending duplicates.
+
+use alloc::vec::Vec;
+
+use crate::{event::event_len, u32_at, DecodeError};
+
+/// datagram type of a reliable event: `u32` session, `u32` first, `u32` seq, event
+pub const RELIABLE: u8 = 7;
+/// datagram type of an acknowledgement: `u32` session, `u32` next expected seq
+pub const ACK: u8 = 8;
+/// datagram type of a numbered datagram: `u32` session, `u32` seq, datagram
+pub const SEQUENCED: u8 = 9;
+/// datagram type of a batch: the encoded events back to back
+pub const BATCH: u8 = 11;
+/// protocol version that introduced [`BATCH`]
+pub const BATCH_SINCE: u32 = 4;
+
+/// Largest batch, below common MTUs with room for the headers.
+pub const MAX_BATCH: usize = 1200;
+
+/// Header of a numbered datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sequenced {
+    pub session: u32,
+    pub seq: u32,
+}
+
+impl Sequenced {
+    pub const LEN: usize = 9;
+
+    /// split a numbered datagram into header and the datagram inside
+    pub fn parse(buf: &[u8]) -> Option<(Sequenced, &[u8])> {
+        if buf.len() < Self::LEN || buf[0] != SEQUENCED {
+            return None;
+        }
+        let header = Sequenced {
+            session: u32_at(buf, 1),
+            seq: u32_at(buf, 5),
+        };
+        Some((header, &buf[Self::LEN..]))
+    }
+
+    /// append the header, the datagram follows
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.push(SEQUENCED);
+        buf.extend_from_slice(&self.session.to_ne_bytes());
+        buf.extend_from_slice(&self.seq.to_ne_bytes());
+    }
+}
+
+/// Header of a reliable event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reliable {
+    pub session: u32,
+    /// oldest sequence number the sender still waits for an ack of
+    pub first: u32,
+    pub seq: u32,
+}
+
+impl Reliable {
+    pub const LEN: usize = 13;
+
+    /// split a reliable datagram into header and event
+    pub fn parse(buf: &[u8]) -> Option<(Reliable, &[u8])> {
+        if buf.len() < Self::LEN || buf[0] != RELIABLE {
+            return None;
+        }
+        let header = Reliable {
+            session: u32_at(buf, 1),
+            first: u32_at(buf, 5),
+            seq: u32_at(buf, 9),
+        };
+        Some((header, &buf[Self::LEN..]))
+    }
+
+    /// append the header, the event follows
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.push(RELIABLE);
+        buf.extend_from_slice(&self.session.to_ne_bytes());
+        buf.extend_from_slice(&self.first.to_ne_bytes());
+        buf.extend_from_slice(&self.seq.to_ne_bytes());
+    }
+}
+
+/// Acknowledgement of all reliable events of `session` before `next`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ack {
+    pub session: u32,
+    pub next: u32,
+}
+
+impl Ack {
+    pub const LEN: usize = 9;
+
+    pub fn parse(buf: &[u8]) -> Option<Ack> {
+        if buf.len() < Self::LEN || buf[0] != ACK {
+            return None;
+        }
+        Some(Ack {
+            session: u32_at(buf, 1),
+            next: u32_at(buf, 5),
+        })
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.push(ACK);
+        buf.extend_from_slice(&self.session.to_ne_bytes());
+        buf.extend_from_slice(&self.next.to_ne_bytes());
+    }
+}
+
+/// The encoded events of a datagram, a batch or a single event.
+pub fn events(datagram: &[u8]) -> Events<'_> {
+    match datagram.first() {
+        Some(&BATCH) => Events {
+            rest: &datagram[1..],
+            batch: true,
+        },
+        _ => Events {
+            rest: datagram,
+            batch: false,
+        },
+    }
+}
+
+/// Iterator returned by [`events`], decode the items with [`crate::Decode`].
+pub struct Events<'a> {
+    rest: &'a [u8],
+    batch: bool,
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Result<&'a [u8], DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.batch {
+            // a single event, possibly empty
+            self.batch = true;
+            return Some(Ok(core::mem::take(&mut self.rest)));
+        }
+        let event_type = *self.rest.first()?;
+        let len = match event_len(event_type) {
+            Ok(len) if len <= self.rest.len() => Ok(len),
+            Ok(_) => Err(DecodeError::Truncated {
+                event_type,
+                len: self.rest.len(),
+            }),
+            Err(e) => Err(e),
+        };
+        match len {
+            Ok(len) => {
+                let (event, rest) = self.rest.split_at(len);
+                self.rest = rest;
+                Some(Ok(event))
+            }
+            Err(e) => {
+                // the rest cannot be split up
+                self.rest = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
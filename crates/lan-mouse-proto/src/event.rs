@@ -0,0 +1,404 @@
+//! Backend neutral input events and their encoding.
+//!
+//! Capture backends translate their native events into these,
+//! emulation backends translate them back into native requests.
+//! Values follow the Linux evdev / Wayland conventions:
+//! buttons and keys are evdev codes, times are in milliseconds.
+//!
+//! Every event is encoded as a `u8` event type followed by its fields,
+//! the length is fixed per event type.
+
+use alloc::vec::Vec;
+
+use crate::{u32_at, Decode, DecodeError, Encode};
+
+pub const BUTTON_RELEASED: u32 = 0;
+pub const BUTTON_PRESSED: u32 = 1;
+
+pub const KEY_RELEASED: u32 = 0;
+pub const KEY_PRESSED: u32 = 1;
+
+pub const AXIS_VERTICAL: u32 = 0;
+pub const AXIS_HORIZONTAL: u32 = 1;
+
+// keymap formats (wl_keyboard.keymap_format)
+pub const KEYMAP_NONE: u32 = 0;
+pub const KEYMAP_XKB_V1: u32 = 1;
+
+// evdev button codes (linux/input-event-codes.h)
+pub const BTN_LEFT: u32 = 0x110;
+pub const BTN_RIGHT: u32 = 0x111;
+pub const BTN_MIDDLE: u32 = 0x112;
+pub const BTN_SIDE: u32 = 0x113;
+pub const BTN_EXTRA: u32 = 0x114;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PointerEvent {
+    /// relative motion
+    Motion {
+        time: u32,
+        dx: f64,
+        dy: f64,
+    },
+    /// absolute position, from 0 to 1 across the screens of the receiver
+    Absolute {
+        time: u32,
+        x: f64,
+        y: f64,
+    },
+    Button {
+        time: u32,
+        button: u32,
+        state: u32,
+    },
+    /// scroll, `value` is in the same coordinate space as motion
+    Axis {
+        time: u32,
+        axis: u32,
+        value: f64,
+    },
+    /// end of a group of pointer events that belong together
+    Frame,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardEvent {
+    Key {
+        time: u32,
+        key: u32,
+        state: u32,
+    },
+    Modifiers {
+        mods_depressed: u32,
+        mods_latched: u32,
+        mods_locked: u32,
+        group: u32,
+    },
+    /// key resolved to a keysym by the sender, for peers in keysym mode
+    Keysym {
+        time: u32,
+        keysym: u32,
+        state: u32,
+    },
+}
+
+/// State of the grab, handled by the receiver itself instead of being emulated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlEvent {
+    /// the pointer entered the receiver
+    Enter,
+    /// the pointer left the receiver,
+    /// keys and buttons still held down are to be released
+    Leave,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Pointer(PointerEvent),
+    Keyboard(KeyboardEvent),
+    Control(ControlEvent),
+}
+
+impl Event {
+    /// short description of the event type, safe to log
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Pointer(PointerEvent::Motion { .. }) => "motion",
+            Self::Pointer(PointerEvent::Absolute { .. }) => "absolute",
+            Self::Pointer(PointerEvent::Button { .. }) => "button",
+            Self::Pointer(PointerEvent::Axis { .. }) => "axis",
+            Self::Pointer(PointerEvent::Frame) => "frame",
+            Self::Keyboard(KeyboardEvent::Key { .. }) => "key",
+            Self::Keyboard(KeyboardEvent::Modifiers { .. }) => "modifiers",
+            Self::Keyboard(KeyboardEvent::Keysym { .. }) => "keysym",
+            Self::Control(ControlEvent::Enter) => "enter",
+            Self::Control(ControlEvent::Leave) => "leave",
+        }
+    }
+
+    /// protocol version that introduced the event,
+    /// peers speaking an older one cannot decode it
+    pub fn since(&self) -> u32 {
+        match self {
+            Self::Pointer(PointerEvent::Absolute { .. }) => 4,
+            Self::Control(_) => 4,
+            _ => 1,
+        }
+    }
+
+    /// Append the encoding understood by peers speaking `version`,
+    /// which must not be older than [`Event::since`]:
+    /// motion is encoded as [`MOTION_F64`] before [`MOTION_SINCE`].
+    pub fn encode_for(&self, version: u32, buf: &mut Vec<u8>) {
+        match *self {
+            Self::Pointer(PointerEvent::Motion { time, dx, dy }) if version < MOTION_SINCE => {
+                buf.push(MOTION_F64);
+                buf.extend_from_slice(&time.to_ne_bytes());
+                buf.extend_from_slice(&dx.to_ne_bytes());
+                buf.extend_from_slice(&dy.to_ne_bytes());
+            }
+            _ => self.encode_into(buf),
+        }
+    }
+}
+
+/// Event classes an emulation backend can reproduce,
+/// advertised to peers so they skip everything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub pointer: bool,
+    pub keyboard: bool,
+}
+
+impl Capabilities {
+    pub const ALL: Self = Capabilities {
+        pointer: true,
+        keyboard: true,
+    };
+
+    pub const NONE: Self = Capabilities {
+        pointer: false,
+        keyboard: false,
+    };
+
+    pub fn supports(&self, e: &Event) -> bool {
+        match e {
+            Event::Pointer(_) => self.pointer,
+            Event::Keyboard(_) => self.keyboard,
+            Event::Control(_) => true,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+const CAPABILITY_POINTER: u32 = 1;
+const CAPABILITY_KEYBOARD: u32 = 2;
+
+impl From<Capabilities> for u32 {
+    fn from(c: Capabilities) -> Self {
+        let mut bits = 0;
+        if c.pointer {
+            bits |= CAPABILITY_POINTER;
+        }
+        if c.keyboard {
+            bits |= CAPABILITY_KEYBOARD;
+        }
+        bits
+    }
+}
+
+impl From<u32> for Capabilities {
+    fn from(bits: u32) -> Self {
+        Capabilities {
+            pointer: bits & CAPABILITY_POINTER != 0,
+            keyboard: bits & CAPABILITY_KEYBOARD != 0,
+        }
+    }
+}
+
+// event types on the wire
+/// motion with `f64` deltas, still found in older recordings
+pub const MOTION_F64: u8 = 0;
+pub const BUTTON: u8 = 1;
+pub const AXIS: u8 = 2;
+pub const FRAME: u8 = 3;
+pub const KEY: u8 = 4;
+pub const MODIFIERS: u8 = 5;
+pub const KEYSYM: u8 = 6;
+/// motion with 24.8 fixed-point deltas
+pub const MOTION: u8 = 10;
+/// protocol version that introduced [`MOTION`]
+pub const MOTION_SINCE: u32 = 4;
+pub const ABSOLUTE: u8 = 12;
+pub const ENTER: u8 = 13;
+pub const LEAVE: u8 = 14;
+
+impl Encode for Event {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Self::Pointer(PointerEvent::Motion { time, dx, dy }) => {
+                buf.push(MOTION);
+                buf.extend_from_slice(&time.to_ne_bytes());
+                buf.extend_from_slice(&to_fixed(dx).to_ne_bytes());
+                buf.extend_from_slice(&to_fixed(dy).to_ne_bytes());
+            }
+            Self::Pointer(PointerEvent::Absolute { time, x, y }) => {
+                buf.push(ABSOLUTE);
+                buf.extend_from_slice(&time.to_ne_bytes());
+                buf.extend_from_slice(&to_unit(x).to_ne_bytes());
+                buf.extend_from_slice(&to_unit(y).to_ne_bytes());
+            }
+            Self::Pointer(PointerEvent::Button {
+                time,
+                button,
+                state,
+            }) => {
+                buf.push(BUTTON);
+                buf.extend_from_slice(&time.to_ne_bytes());
+                buf.extend_from_slice(&button.to_ne_bytes());
+                buf.push(state as u8);
+            }
+            Self::Pointer(PointerEvent::Axis { time, axis, value }) => {
+                buf.push(AXIS);
+                buf.extend_from_slice(&time.to_ne_bytes());
+                buf.push(axis as u8);
+                buf.extend_from_slice(&value.to_ne_bytes());
+            }
+            Self::Pointer(PointerEvent::Frame) => buf.push(FRAME),
+            Self::Keyboard(KeyboardEvent::Key { time, key, state }) => {
+                buf.push(KEY);
+                buf.extend_from_slice(&time.to_ne_bytes());
+                buf.extend_from_slice(&key.to_ne_bytes());
+                buf.push(state as u8);
+            }
+            Self::Keyboard(KeyboardEvent::Modifiers {
+                mods_depressed,
+                mods_latched,
+                mods_locked,
+                group,
+            }) => {
+                buf.push(MODIFIERS);
+                buf.extend_from_slice(&mods_depressed.to_ne_bytes());
+                buf.extend_from_slice(&mods_latched.to_ne_bytes());
+                buf.extend_from_slice(&mods_locked.to_ne_bytes());
+                buf.extend_from_slice(&group.to_ne_bytes());
+            }
+            Self::Keyboard(KeyboardEvent::Keysym {
+                time,
+                keysym,
+                state,
+            }) => {
+                buf.push(KEYSYM);
+                buf.extend_from_slice(&time.to_ne_bytes());
+                buf.extend_from_slice(&keysym.to_ne_bytes());
+                buf.push(state as u8);
+            }
+            Self::Control(ControlEvent::Enter) => buf.push(ENTER),
+            Self::Control(ControlEvent::Leave) => buf.push(LEAVE),
+        }
+    }
+}
+
+fn f64_at(buf: &[u8], i: usize) -> f64 {
+    f64::from_ne_bytes(buf[i..i + 8].try_into().unwrap())
+}
+
+/// `v` moved half a unit away from zero, so the `as` cast after rounds
+/// instead of truncating (`f64::round` needs `std`)
+fn round(v: f64) -> f64 {
+    if v < 0.0 {
+        v - 0.5
+    } else {
+        v + 0.5
+    }
+}
+
+/// Motion deltas on the wire: 24.8 fixed-point like `wl_fixed_t`,
+/// sub-pixel precision at less than half the size of an `f64`.
+fn to_fixed(v: f64) -> i32 {
+    round(v * 256.0) as i32
+}
+
+fn from_fixed(v: i32) -> f64 {
+    v as f64 / 256.0
+}
+
+/// Absolute positions on the wire: fractions of the screen in units of `1 / u32::MAX`.
+fn to_unit(v: f64) -> u32 {
+    round(v.clamp(0.0, 1.0) * u32::MAX as f64) as u32
+}
+
+fn from_unit(v: u32) -> f64 {
+    v as f64 / u32::MAX as f64
+}
+
+/// button and key states, axes
+fn enum_at(buf: &[u8], i: usize) -> Result<u32, DecodeError> {
+    match buf[i] {
+        v @ 0..=1 => Ok(v as u32),
+        v => Err(DecodeError::InvalidEnumValue(v)),
+    }
+}
+
+/// encoded length of an event of type `event_type`
+pub fn event_len(event_type: u8) -> Result<usize, DecodeError> {
+    let len = match event_type {
+        MOTION_F64 => 21,
+        BUTTON => 10,
+        AXIS => 14,
+        FRAME => 1,
+        KEY => 10,
+        MODIFIERS => 17,
+        KEYSYM => 10,
+        MOTION => 13,
+        ABSOLUTE => 13,
+        ENTER | LEAVE => 1,
+        t => return Err(DecodeError::InvalidEventType(t)),
+    };
+    Ok(len)
+}
+
+impl Decode for Event {
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let event_type = *buf.first().ok_or(DecodeError::Empty)?;
+        let len = event_len(event_type)?;
+        if buf.len() < len {
+            return Err(DecodeError::Truncated {
+                event_type,
+                len: buf.len(),
+            });
+        }
+        let event = match event_type {
+            MOTION_F64 => Self::Pointer(PointerEvent::Motion {
+                time: u32_at(buf, 1),
+                dx: f64_at(buf, 5),
+                dy: f64_at(buf, 13),
+            }),
+            MOTION => Self::Pointer(PointerEvent::Motion {
+                time: u32_at(buf, 1),
+                dx: from_fixed(u32_at(buf, 5) as i32),
+                dy: from_fixed(u32_at(buf, 9) as i32),
+            }),
+            ABSOLUTE => Self::Pointer(PointerEvent::Absolute {
+                time: u32_at(buf, 1),
+                x: from_unit(u32_at(buf, 5)),
+                y: from_unit(u32_at(buf, 9)),
+            }),
+            BUTTON => Self::Pointer(PointerEvent::Button {
+                time: u32_at(buf, 1),
+                button: u32_at(buf, 5),
+                state: enum_at(buf, 9)?,
+            }),
+            AXIS => Self::Pointer(PointerEvent::Axis {
+                time: u32_at(buf, 1),
+                axis: enum_at(buf, 5)?,
+                value: f64_at(buf, 6),
+            }),
+            FRAME => Self::Pointer(PointerEvent::Frame),
+            KEY => Self::Keyboard(KeyboardEvent::Key {
+                time: u32_at(buf, 1),
+                key: u32_at(buf, 5),
+                state: enum_at(buf, 9)?,
+            }),
+            MODIFIERS => Self::Keyboard(KeyboardEvent::Modifiers {
+                mods_depressed: u32_at(buf, 1),
+                mods_latched: u32_at(buf, 5),
+                mods_locked: u32_at(buf, 9),
+                group: u32_at(buf, 13),
+            }),
+            KEYSYM => Self::Keyboard(KeyboardEvent::Keysym {
+                time: u32_at(buf, 1),
+                keysym: u32_at(buf, 5),
+                state: enum_at(buf, 9)?,
+            }),
+            ENTER => Self::Control(ControlEvent::Enter),
+            _ => Self::Control(ControlEvent::Leave),
+        };
+        Ok(event)
+    }
+}
@@ -0,0 +1,171 @@
+//! Peer information exchanged through [`DataRequest::Handshake`].
+//!
+//! The requesting side sends its own [`PeerInfo`] along with the request
+//! and receives the one of the peer in return, so both sides know
+//! each other after a single round trip.
+//!
+//! [`DataRequest::Handshake`]: crate::message::DataRequest::Handshake
+
+use alloc::{string::String, vec::Vec};
+
+#[cfg(feature = "serde")]
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{event::Capabilities, Decode, DecodeError, Encode};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub hostname: String,
+    /// [`crate::PROTOCOL_VERSION`] of the peer
+    pub version: u32,
+    /// event classes the peer can emulate
+    pub capabilities: Capabilities,
+    /// name of the capture or emulation backend in use
+    pub backend: String,
+    pub outputs: Vec<Output>,
+    /// format of the keymaps the peer offers (server)
+    /// or accepts (client), see [`crate::event::KEYMAP_XKB_V1`]
+    pub keymap_format: u32,
+    /// keyboard mode the peer wants to receive, see [`KeyboardMode`]
+    pub keyboard: KeyboardMode,
+}
+
+/// An output in the global compositor space.
+/// Position and size are in physical pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Output {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub scale: f64,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            scale: 1.0,
+        }
+    }
+}
+
+/// How key events are exchanged with a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum KeyboardMode {
+    /// keycodes, interpreted with the keymap of the server
+    #[default]
+    Keycode,
+    /// keysyms, mapped onto the keymap of the client
+    Keysym,
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_ne_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+impl Encode for PeerInfo {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.version.to_ne_bytes());
+        put_str(buf, &self.hostname);
+        buf.extend_from_slice(&u32::from(self.capabilities).to_ne_bytes());
+        put_str(buf, &self.backend);
+        buf.extend_from_slice(&self.keymap_format.to_ne_bytes());
+        buf.extend_from_slice(&(self.outputs.len() as u32).to_ne_bytes());
+        for o in &self.outputs {
+            buf.extend_from_slice(&o.x.to_ne_bytes());
+            buf.extend_from_slice(&o.y.to_ne_bytes());
+            buf.extend_from_slice(&o.width.to_ne_bytes());
+            buf.extend_from_slice(&o.height.to_ne_bytes());
+            buf.extend_from_slice(&o.scale.to_ne_bytes());
+        }
+        let keyboard: u32 = match self.keyboard {
+            KeyboardMode::Keycode => 0,
+            KeyboardMode::Keysym => 1,
+        };
+        buf.extend_from_slice(&keyboard.to_ne_bytes());
+    }
+}
+
+/// reads the fields of an encoded [`PeerInfo`] in order
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        if self.0.len() < N {
+            return Err(DecodeError::InvalidPeerInfo);
+        }
+        let (head, tail) = self.0.split_at(N);
+        self.0 = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.take().map(u32::from_ne_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, DecodeError> {
+        self.take().map(i32::from_ne_bytes)
+    }
+
+    fn f64(&mut self) -> Result<f64, DecodeError> {
+        self.take().map(f64::from_ne_bytes)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.u32()? as usize;
+        if self.0.len() < len {
+            return Err(DecodeError::InvalidPeerInfo);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(String::from_utf8_lossy(head).into_owned())
+    }
+}
+
+impl Decode for PeerInfo {
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader(buf);
+        let version = r.u32()?;
+        let hostname = r.string()?;
+        let capabilities = Capabilities::from(r.u32()?);
+        let backend = r.string()?;
+        let keymap_format = r.u32()?;
+        let n = r.u32()?;
+        let mut outputs = Vec::new();
+        for _ in 0..n {
+            outputs.push(Output {
+                x: r.i32()?,
+                y: r.i32()?,
+                width: r.u32()?,
+                height: r.u32()?,
+                scale: r.f64()?,
+            });
+        }
+        // absent for peers predating the keysym mode
+        let keyboard = if r.0.is_empty() {
+            KeyboardMode::Keycode
+        } else {
+            match r.u32()? {
+                0 => KeyboardMode::Keycode,
+                1 => KeyboardMode::Keysym,
+                _ => return Err(DecodeError::InvalidPeerInfo),
+            }
+        };
+        Ok(PeerInfo {
+            hostname,
+            version,
+            capabilities,
+            backend,
+            outputs,
+            keymap_format,
+            keyboard,
+        })
+    }
+}
@@ -0,0 +1,99 @@
+//! Wire format of the lan-mouse protocol.
+//!
+//! Everything needed to talk to lan-mouse peers, e.g. from a macro pad
+//! or a test injector: the input [`event`]s, the [`datagram`]s carrying them,
+//! the [`message`]s of the control channel and the [`handshake`].
+//! This crate only encodes and decodes, sockets, timers and the state
+//! of retransmissions are up to the user (lan-mouse keeps them in its `protocol` module).
+//!
+//! The core builds without `std` (only `alloc`), the `std` feature (default)
+//! implements `std::error::Error` for the error types.
+//! All integers are in native byte order, the tests in `tests/` are the specification.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+
+pub mod datagram;
+pub mod event;
+pub mod handshake;
+pub mod message;
+
+/// Version of the wire protocol, increased on incompatible changes.
+///
+/// 1. peer info exchanged in a handshake
+/// 2. requests on a persistent control channel ([`message::Message`])
+/// 3. reliable key, button and modifier events, numbered datagrams
+/// 4. batches, fixed-point motion, absolute motion, enter and leave
+pub const PROTOCOL_VERSION: u32 = 4;
+
+pub trait Encode {
+    /// append the encoding to `buf`, reusing its allocation
+    fn encode_into(&self, buf: &mut Vec<u8>);
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    Truncated {
+        event_type: u8,
+        len: usize,
+    },
+    InvalidEventType(u8),
+    InvalidEnumValue(u8),
+    InvalidRequest(u32),
+    InvalidPeerInfo,
+    InvalidMessage,
+    FrameTooLarge(usize),
+    /// newer peers may send messages we do not know
+    UnknownMessage(u8),
+    UnknownPush(u32),
+    /// the request `id` has a type we do not know
+    UnknownRequest {
+        id: u32,
+        req: u32,
+    },
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty packet"),
+            Self::Truncated { event_type, len } => {
+                write!(f, "event type {} truncated to {} bytes", event_type, len)
+            }
+            Self::InvalidEventType(t) => write!(f, "invalid event type {}", t),
+            Self::InvalidEnumValue(v) => write!(f, "invalid enum value {}", v),
+            Self::InvalidRequest(r) => write!(f, "invalid request {}", r),
+            Self::InvalidPeerInfo => write!(f, "invalid peer info"),
+            Self::InvalidMessage => write!(f, "invalid message"),
+            Self::FrameTooLarge(len) => write!(f, "frame of {} bytes too large", len),
+            Self::UnknownMessage(t) => write!(f, "unknown message type {}", t),
+            Self::UnknownPush(k) => write!(f, "unknown push {}", k),
+            Self::UnknownRequest { id, req } => write!(f, "request {}: unknown type {}", id, req),
+        }
+    }
+}
+
+/// `u32` at `i`, the caller checked the length
+fn u32_at(buf: &[u8], i: usize) -> u32 {
+    u32::from_ne_bytes(buf[i..i + 4].try_into().unwrap())
+}
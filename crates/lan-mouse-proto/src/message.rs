@@ -0,0 +1,275 @@
+//! Messages exchanged on the control channel.
+//!
+//! Every message is a frame of a `u32` length followed by that many bytes:
+//! a `u8` message type and its body, see the message table in DOC.md.
+//! Requests carry an id chosen by the sender, which the response repeats,
+//! so any number of requests can be in flight on one channel.
+//! The control channel is a TCP connection, since protocol version 2.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::{self, Display};
+
+use crate::{Decode, DecodeError, Encode};
+
+/// upper bound for the length of a frame, keymaps are usually below 100 KiB
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// first message of the dialing side, the port it listens on
+    Hello { port: u16 },
+    Request {
+        id: u32,
+        req: DataRequest,
+        payload: Vec<u8>,
+    },
+    /// the requested data, `None` if nothing is offered
+    Response { id: u32, data: Option<Vec<u8>> },
+    /// the request could not be answered
+    Error { id: u32, error: RemoteError },
+    /// sent unsolicited
+    Push(Push),
+}
+
+/// Changes the peer is told about without asking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Push {
+    /// a new keymap is offered, identified by its [`keymap_hash`]
+    KeyMapChanged(u64),
+    /// new clipboard contents are offered
+    ClipboardChanged,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum DataRequest {
+    KeyMap,
+    Clipboard,
+    /// the [`Capabilities`](crate::event::Capabilities) of the emulation backend
+    Capabilities,
+    /// exchange of [`PeerInfo`](crate::handshake::PeerInfo), the request carries the one of the sender
+    Handshake,
+}
+
+impl TryFrom<u32> for DataRequest {
+    type Error = DecodeError;
+
+    fn try_from(idx: u32) -> Result<Self, Self::Error> {
+        match idx {
+            0 => Ok(Self::KeyMap),
+            1 => Ok(Self::Clipboard),
+            2 => Ok(Self::Capabilities),
+            3 => Ok(Self::Handshake),
+            _ => Err(DecodeError::InvalidRequest(idx)),
+        }
+    }
+}
+
+impl TryFrom<[u8; 4]> for DataRequest {
+    type Error = DecodeError;
+
+    fn try_from(buf: [u8; 4]) -> Result<Self, Self::Error> {
+        DataRequest::try_from(u32::from_ne_bytes(buf))
+    }
+}
+
+impl From<DataRequest> for u32 {
+    fn from(d: DataRequest) -> Self {
+        match d {
+            DataRequest::KeyMap => 0,
+            DataRequest::Clipboard => 1,
+            DataRequest::Capabilities => 2,
+            DataRequest::Handshake => 3,
+        }
+    }
+}
+
+/// Content hash identifying a keymap (64 bit FNV-1a),
+/// stable across hosts and builds.
+pub fn keymap_hash(keymap: &[u8]) -> u64 {
+    keymap.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the request type is not known to the peer
+    UnknownRequest,
+    /// the payload of the request is malformed
+    InvalidPayload,
+    Other(u32),
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(c: ErrorCode) -> Self {
+        match c {
+            ErrorCode::UnknownRequest => 1,
+            ErrorCode::InvalidPayload => 2,
+            ErrorCode::Other(c) => c,
+        }
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(c: u32) -> Self {
+        match c {
+            1 => Self::UnknownRequest,
+            2 => Self::InvalidPayload,
+            c => Self::Other(c),
+        }
+    }
+}
+
+/// Error response of a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl RemoteError {
+    pub fn new(code: ErrorCode, message: impl Display) -> Self {
+        RemoteError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RemoteError {}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer answered {:?}: {}", self.code, self.message)
+    }
+}
+
+const HELLO: u8 = 0;
+const REQUEST: u8 = 1;
+const RESPONSE: u8 = 2;
+const ERROR: u8 = 3;
+const PUSH: u8 = 4;
+
+const PUSH_KEYMAP_CHANGED: u32 = 0;
+const PUSH_CLIPBOARD_CHANGED: u32 = 1;
+
+impl Encode for Message {
+    /// the complete frame, including the length
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.extend_from_slice(&[0u8; 4]);
+        match self {
+            Self::Hello { port } => {
+                buf.push(HELLO);
+                buf.extend_from_slice(&port.to_ne_bytes());
+            }
+            Self::Request { id, req, payload } => {
+                buf.push(REQUEST);
+                buf.extend_from_slice(&id.to_ne_bytes());
+                buf.extend_from_slice(&u32::from(*req).to_ne_bytes());
+                buf.extend_from_slice(payload);
+            }
+            Self::Response { id, data } => {
+                buf.push(RESPONSE);
+                buf.extend_from_slice(&id.to_ne_bytes());
+                buf.push(data.is_some() as u8);
+                buf.extend_from_slice(data.as_deref().unwrap_or_default());
+            }
+            Self::Error { id, error } => {
+                buf.push(ERROR);
+                buf.extend_from_slice(&id.to_ne_bytes());
+                buf.extend_from_slice(&u32::from(error.code).to_ne_bytes());
+                buf.extend_from_slice(error.message.as_bytes());
+            }
+            Self::Push(Push::KeyMapChanged(hash)) => {
+                buf.push(PUSH);
+                buf.extend_from_slice(&PUSH_KEYMAP_CHANGED.to_ne_bytes());
+                buf.extend_from_slice(&hash.to_ne_bytes());
+            }
+            Self::Push(Push::ClipboardChanged) => {
+                buf.push(PUSH);
+                buf.extend_from_slice(&PUSH_CLIPBOARD_CHANGED.to_ne_bytes());
+            }
+        }
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_ne_bytes());
+    }
+}
+
+/// The first complete frame in `buf` without its length
+/// and the number of bytes it takes up, `None` if incomplete.
+pub fn split_frame(buf: &[u8]) -> Result<Option<(&[u8], usize)>, DecodeError> {
+    let len = match buf.get(..4) {
+        Some(len) => u32::from_ne_bytes(len.try_into().unwrap()) as usize,
+        None => return Ok(None),
+    };
+    if len > MAX_FRAME {
+        return Err(DecodeError::FrameTooLarge(len));
+    }
+    Ok(buf.get(4..4 + len).map(|frame| (frame, 4 + len)))
+}
+
+fn u32_at(buf: &[u8], i: usize) -> Result<u32, DecodeError> {
+    match buf.get(i..i + 4) {
+        Some(b) => Ok(u32::from_ne_bytes(b.try_into().unwrap())),
+        None => Err(DecodeError::InvalidMessage),
+    }
+}
+
+impl Decode for Message {
+    /// decode a frame as returned by [`split_frame`]
+    fn decode(buf: &[u8]) -> Result<Self, DecodeError> {
+        let msg_type = *buf.first().ok_or(DecodeError::Empty)?;
+        let msg = match msg_type {
+            HELLO => match buf.get(1..3) {
+                Some(port) => Self::Hello {
+                    port: u16::from_ne_bytes(port.try_into().unwrap()),
+                },
+                None => return Err(DecodeError::InvalidMessage),
+            },
+            REQUEST => {
+                let id = u32_at(buf, 1)?;
+                let req = u32_at(buf, 5)?;
+                let req = DataRequest::try_from(req)
+                    .map_err(|_| DecodeError::UnknownRequest { id, req })?;
+                Self::Request {
+                    id,
+                    req,
+                    payload: buf[9..].to_vec(),
+                }
+            }
+            RESPONSE => {
+                let id = u32_at(buf, 1)?;
+                let data = match buf.get(5) {
+                    Some(0) => None,
+                    Some(1) => Some(buf[6..].to_vec()),
+                    _ => return Err(DecodeError::InvalidMessage),
+                };
+                Self::Response { id, data }
+            }
+            ERROR => Self::Error {
+                id: u32_at(buf, 1)?,
+                error: RemoteError {
+                    code: ErrorCode::from(u32_at(buf, 5)?),
+                    message: String::from_utf8_lossy(&buf[9..]).into_owned(),
+                },
+            },
+            PUSH => match u32_at(buf, 1)? {
+                PUSH_KEYMAP_CHANGED => match buf.get(5..13) {
+                    Some(hash) => Self::Push(Push::KeyMapChanged(u64::from_ne_bytes(
+                        hash.try_into().unwrap(),
+                    ))),
+                    None => return Err(DecodeError::InvalidMessage),
+                },
+                PUSH_CLIPBOARD_CHANGED => Self::Push(Push::ClipboardChanged),
+                kind => return Err(DecodeError::UnknownPush(kind)),
+            },
+            t => return Err(DecodeError::UnknownMessage(t)),
+        };
+        Ok(msg)
+    }
+}
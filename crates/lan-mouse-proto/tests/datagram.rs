@@ -0,0 +1,147 @@
+//! Headers of datagrams and batches of events.
+
+use lan_mouse_proto::{
+    datagram::{self, Ack, Reliable, Sequenced, ACK, BATCH, RELIABLE, SEQUENCED},
+    event::{self, Event, PointerEvent},
+    Decode, DecodeError, Encode,
+};
+
+#[test]
+fn datagram_types() {
+    assert_eq!(RELIABLE, 7);
+    assert_eq!(ACK, 8);
+    assert_eq!(SEQUENCED, 9);
+    assert_eq!(BATCH, 11);
+}
+
+#[test]
+fn sequenced() {
+    let header = Sequenced {
+        session: 0xdeadbeef,
+        seq: 42,
+    };
+    let mut buf = vec![];
+    header.write(&mut buf);
+    let wire = [
+        &[SEQUENCED][..],
+        &0xdeadbeefu32.to_ne_bytes(),
+        &42u32.to_ne_bytes(),
+    ]
+    .concat();
+    assert_eq!(buf, wire);
+    assert_eq!(buf.len(), Sequenced::LEN);
+    buf.push(event::FRAME);
+    assert_eq!(Sequenced::parse(&buf), Some((header, &[event::FRAME][..])));
+}
+
+#[test]
+fn reliable() {
+    let header = Reliable {
+        session: 1,
+        first: 2,
+        seq: 3,
+    };
+    let mut buf = vec![];
+    header.write(&mut buf);
+    let wire = [
+        &[RELIABLE][..],
+        &1u32.to_ne_bytes(),
+        &2u32.to_ne_bytes(),
+        &3u32.to_ne_bytes(),
+    ]
+    .concat();
+    assert_eq!(buf, wire);
+    assert_eq!(buf.len(), Reliable::LEN);
+    assert_eq!(Reliable::parse(&buf), Some((header, &[][..])));
+}
+
+#[test]
+fn ack() {
+    let ack = Ack {
+        session: 1,
+        next: 5,
+    };
+    let mut buf = vec![];
+    ack.write(&mut buf);
+    let wire = [&[ACK][..], &1u32.to_ne_bytes(), &5u32.to_ne_bytes()].concat();
+    assert_eq!(buf, wire);
+    assert_eq!(buf.len(), Ack::LEN);
+    assert_eq!(Ack::parse(&buf), Some(ack));
+}
+
+#[test]
+fn short_or_other_headers() {
+    let mut buf = vec![];
+    Sequenced { session: 1, seq: 1 }.write(&mut buf);
+    assert_eq!(Sequenced::parse(&buf[..8]), None);
+    assert_eq!(Reliable::parse(&buf), None);
+    assert_eq!(Ack::parse(&buf), None);
+    assert_eq!(Sequenced::parse(&[event::FRAME]), None);
+}
+
+#[test]
+fn single_event() {
+    let frame = [event::FRAME];
+    let events: Vec<_> = datagram::events(&frame).collect();
+    assert_eq!(events, [Ok(&frame[..])]);
+}
+
+#[test]
+fn batch() {
+    let motion = Event::Pointer(PointerEvent::Motion {
+        time: 1,
+        dx: 1.0,
+        dy: 0.0,
+    });
+    let frame = Event::Pointer(PointerEvent::Frame);
+    let mut buf = vec![BATCH];
+    motion.encode_into(&mut buf);
+    motion.encode_into(&mut buf);
+    frame.encode_into(&mut buf);
+    assert_eq!(buf.len(), 1 + 13 + 13 + 1);
+    let events: Vec<_> = datagram::events(&buf)
+        .map(|e| Event::decode(e.unwrap()).unwrap())
+        .collect();
+    assert_eq!(events, [motion, motion, frame]);
+}
+
+#[test]
+fn empty_batch() {
+    assert_eq!(datagram::events(&[BATCH]).count(), 0);
+}
+
+#[test]
+fn truncated_batch() {
+    let mut buf = vec![BATCH, event::FRAME];
+    Event::Pointer(PointerEvent::Motion {
+        time: 1,
+        dx: 1.0,
+        dy: 0.0,
+    })
+    .encode_into(&mut buf);
+    buf.truncate(10);
+    let events: Vec<_> = datagram::events(&buf).collect();
+    assert_eq!(
+        events,
+        [
+            Ok(&[event::FRAME][..]),
+            Err(DecodeError::Truncated {
+                event_type: event::MOTION,
+                len: 8
+            })
+        ]
+    );
+}
+
+#[test]
+fn unknown_event_in_batch() {
+    let buf = [BATCH, event::FRAME, 0xff, event::FRAME];
+    let events: Vec<_> = datagram::events(&buf).collect();
+    assert_eq!(
+        events,
+        [
+            Ok(&[event::FRAME][..]),
+            Err(DecodeError::InvalidEventType(0xff))
+        ]
+    );
+}
@@ -0,0 +1,338 @@
+//! Wire format of events: a `u8` event type and fixed length fields.
+
+use lan_mouse_proto::{
+    event::{self, Capabilities, ControlEvent, Event, KeyboardEvent, PointerEvent},
+    Decode, DecodeError, Encode,
+};
+
+fn assert_wire(e: Event, wire: &[u8]) {
+    assert_eq!(e.encode(), wire, "encoding of {:?}", e);
+    assert_eq!(Event::decode(wire), Ok(e), "decoding of {:?}", wire);
+    assert_eq!(event::event_len(wire[0]), Ok(wire.len()));
+}
+
+#[test]
+fn motion_is_fixed_point() {
+    let e = Event::Pointer(PointerEvent::Motion {
+        time: 7,
+        dx: 1.5,
+        dy: -2.25,
+    });
+    let wire = [
+        &[event::MOTION][..],
+        &7u32.to_ne_bytes(),
+        &384i32.to_ne_bytes(),
+        &(-576i32).to_ne_bytes(),
+    ]
+    .concat();
+    assert_wire(e, &wire);
+}
+
+#[test]
+fn motion_rounds_to_nearest() {
+    let e = Event::Pointer(PointerEvent::Motion {
+        time: 0,
+        dx: 0.3 / 256.0,
+        dy: -0.7 / 256.0,
+    });
+    let wire = e.encode();
+    assert_eq!(wire[5..9], 0i32.to_ne_bytes());
+    assert_eq!(wire[9..13], (-1i32).to_ne_bytes());
+}
+
+#[test]
+fn legacy_motion_decodes() {
+    let wire = [
+        &[event::MOTION_F64][..],
+        &3u32.to_ne_bytes(),
+        &0.1f64.to_ne_bytes(),
+        &(-0.2f64).to_ne_bytes(),
+    ]
+    .concat();
+    let e = Event::Pointer(PointerEvent::Motion {
+        time: 3,
+        dx: 0.1,
+        dy: -0.2,
+    });
+    assert_eq!(Event::decode(&wire), Ok(e));
+    assert_eq!(event::event_len(event::MOTION_F64), Ok(21));
+}
+
+#[test]
+fn motion_for_older_peers() {
+    let e = Event::Pointer(PointerEvent::Motion {
+        time: 3,
+        dx: 0.1,
+        dy: -0.2,
+    });
+    let wire = [
+        &[event::MOTION_F64][..],
+        &3u32.to_ne_bytes(),
+        &0.1f64.to_ne_bytes(),
+        &(-0.2f64).to_ne_bytes(),
+    ]
+    .concat();
+    for version in 1..event::MOTION_SINCE {
+        let mut buf = vec![];
+        e.encode_for(version, &mut buf);
+        assert_eq!(buf, wire, "encoding for version {}", version);
+    }
+    let mut buf = vec![];
+    e.encode_for(event::MOTION_SINCE, &mut buf);
+    assert_eq!(buf, e.encode());
+    assert_eq!(buf[0], event::MOTION);
+}
+
+#[test]
+fn unchanged_for_older_peers() {
+    let key = Event::Keyboard(KeyboardEvent::Key {
+        time: 0,
+        key: 1,
+        state: 1,
+    });
+    let mut buf = vec![];
+    key.encode_for(1, &mut buf);
+    assert_eq!(buf, key.encode());
+}
+
+#[test]
+fn absolute_is_fraction_of_u32() {
+    let e = Event::Pointer(PointerEvent::Absolute {
+        time: 1,
+        x: 0.0,
+        y: 1.0,
+    });
+    let wire = [
+        &[event::ABSOLUTE][..],
+        &1u32.to_ne_bytes(),
+        &0u32.to_ne_bytes(),
+        &u32::MAX.to_ne_bytes(),
+    ]
+    .concat();
+    assert_wire(e, &wire);
+}
+
+#[test]
+fn absolute_is_clamped() {
+    let e = Event::Pointer(PointerEvent::Absolute {
+        time: 1,
+        x: -0.5,
+        y: 2.0,
+    });
+    let wire = e.encode();
+    assert_eq!(wire[5..9], 0u32.to_ne_bytes());
+    assert_eq!(wire[9..13], u32::MAX.to_ne_bytes());
+}
+
+#[test]
+fn button() {
+    let e = Event::Pointer(PointerEvent::Button {
+        time: 2,
+        button: event::BTN_LEFT,
+        state: event::BUTTON_PRESSED,
+    });
+    let wire = [
+        &[event::BUTTON][..],
+        &2u32.to_ne_bytes(),
+        &0x110u32.to_ne_bytes(),
+        &[1],
+    ]
+    .concat();
+    assert_wire(e, &wire);
+}
+
+#[test]
+fn axis() {
+    let e = Event::Pointer(PointerEvent::Axis {
+        time: 4,
+        axis: event::AXIS_HORIZONTAL,
+        value: -10.5,
+    });
+    let wire = [
+        &[event::AXIS][..],
+        &4u32.to_ne_bytes(),
+        &[1],
+        &(-10.5f64).to_ne_bytes(),
+    ]
+    .concat();
+    assert_wire(e, &wire);
+}
+
+#[test]
+fn frame() {
+    assert_wire(Event::Pointer(PointerEvent::Frame), &[event::FRAME]);
+}
+
+#[test]
+fn key() {
+    let e = Event::Keyboard(KeyboardEvent::Key {
+        time: 5,
+        key: 30,
+        state: event::KEY_RELEASED,
+    });
+    let wire = [
+        &[event::KEY][..],
+        &5u32.to_ne_bytes(),
+        &30u32.to_ne_bytes(),
+        &[0],
+    ]
+    .concat();
+    assert_wire(e, &wire);
+}
+
+#[test]
+fn modifiers() {
+    let e = Event::Keyboard(KeyboardEvent::Modifiers {
+        mods_depressed: 1,
+        mods_latched: 2,
+        mods_locked: 16,
+        group: 1,
+    });
+    let wire = [
+        &[event::MODIFIERS][..],
+        &1u32.to_ne_bytes(),
+        &2u32.to_ne_bytes(),
+        &16u32.to_ne_bytes(),
+        &1u32.to_ne_bytes(),
+    ]
+    .concat();
+    assert_wire(e, &wire);
+}
+
+#[test]
+fn keysym() {
+    let e = Event::Keyboard(KeyboardEvent::Keysym {
+        time: 6,
+        keysym: 0x61,
+        state: event::KEY_PRESSED,
+    });
+    let wire = [
+        &[event::KEYSYM][..],
+        &6u32.to_ne_bytes(),
+        &0x61u32.to_ne_bytes(),
+        &[1],
+    ]
+    .concat();
+    assert_wire(e, &wire);
+}
+
+#[test]
+fn enter_and_leave() {
+    assert_wire(Event::Control(ControlEvent::Enter), &[event::ENTER]);
+    assert_wire(Event::Control(ControlEvent::Leave), &[event::LEAVE]);
+}
+
+#[test]
+fn event_types() {
+    let types = [
+        (event::MOTION_F64, 0),
+        (event::BUTTON, 1),
+        (event::AXIS, 2),
+        (event::FRAME, 3),
+        (event::KEY, 4),
+        (event::MODIFIERS, 5),
+        (event::KEYSYM, 6),
+        (event::MOTION, 10),
+        (event::ABSOLUTE, 12),
+        (event::ENTER, 13),
+        (event::LEAVE, 14),
+    ];
+    for (t, expected) in types {
+        assert_eq!(t, expected);
+    }
+    // taken by datagram headers
+    for t in [7, 8, 9, 11] {
+        assert_eq!(event::event_len(t), Err(DecodeError::InvalidEventType(t)));
+    }
+}
+
+#[test]
+fn empty() {
+    assert_eq!(Event::decode(&[]), Err(DecodeError::Empty));
+}
+
+#[test]
+fn truncated() {
+    let wire = Event::Keyboard(KeyboardEvent::Key {
+        time: 0,
+        key: 1,
+        state: 1,
+    })
+    .encode();
+    assert_eq!(
+        Event::decode(&wire[..9]),
+        Err(DecodeError::Truncated {
+            event_type: event::KEY,
+            len: 9
+        })
+    );
+}
+
+#[test]
+fn trailing_bytes_are_ignored() {
+    let mut wire = Event::Pointer(PointerEvent::Frame).encode();
+    wire.push(0xff);
+    assert_eq!(
+        Event::decode(&wire),
+        Ok(Event::Pointer(PointerEvent::Frame))
+    );
+}
+
+#[test]
+fn invalid_state() {
+    let wire = [
+        &[event::BUTTON][..],
+        &0u32.to_ne_bytes(),
+        &0x110u32.to_ne_bytes(),
+        &[2],
+    ]
+    .concat();
+    assert_eq!(Event::decode(&wire), Err(DecodeError::InvalidEnumValue(2)));
+}
+
+#[test]
+fn since() {
+    let frame = Event::Pointer(PointerEvent::Frame);
+    let key = Event::Keyboard(KeyboardEvent::Key {
+        time: 0,
+        key: 1,
+        state: 1,
+    });
+    let absolute = Event::Pointer(PointerEvent::Absolute {
+        time: 0,
+        x: 0.0,
+        y: 0.0,
+    });
+    let motion = Event::Pointer(PointerEvent::Motion {
+        time: 0,
+        dx: 0.0,
+        dy: 0.0,
+    });
+    assert_eq!(frame.since(), 1);
+    assert_eq!(key.since(), 1);
+    // sent as MOTION_F64 to older peers
+    assert_eq!(motion.since(), 1);
+    assert_eq!(absolute.since(), 4);
+    assert_eq!(Event::Control(ControlEvent::Enter).since(), 4);
+    assert_eq!(Event::Control(ControlEvent::Leave).since(), 4);
+}
+
+#[test]
+fn capability_bits() {
+    assert_eq!(u32::from(Capabilities::NONE), 0);
+    assert_eq!(u32::from(Capabilities::ALL), 3);
+    let pointer = Capabilities {
+        pointer: true,
+        keyboard: false,
+    };
+    assert_eq!(u32::from(pointer), 1);
+    // unknown bits of newer peers are ignored
+    assert_eq!(
+        Capabilities::from(0xfffe),
+        Capabilities {
+            pointer: false,
+            keyboard: true,
+        }
+    );
+    assert!(Capabilities::NONE.supports(&Event::Control(ControlEvent::Enter)));
+}
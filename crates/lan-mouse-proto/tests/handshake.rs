@@ -0,0 +1,91 @@
+//! Peer information exchanged in the handshake.
+
+use lan_mouse_proto::{
+    event::{self, Capabilities},
+    handshake::{KeyboardMode, Output, PeerInfo},
+    Decode, DecodeError, Encode, PROTOCOL_VERSION,
+};
+
+fn info() -> PeerInfo {
+    PeerInfo {
+        hostname: "host".into(),
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::ALL,
+        backend: "x11".into(),
+        outputs: vec![Output {
+            x: -1920,
+            y: 0,
+            width: 1920,
+            height: 1080,
+            scale: 1.5,
+        }],
+        keymap_format: event::KEYMAP_XKB_V1,
+        keyboard: KeyboardMode::Keysym,
+    }
+}
+
+fn wire(info: &PeerInfo) -> Vec<u8> {
+    let o = &info.outputs[0];
+    [
+        &info.version.to_ne_bytes()[..],
+        &4u32.to_ne_bytes(),
+        b"host",
+        &3u32.to_ne_bytes(),
+        &3u32.to_ne_bytes(),
+        b"x11",
+        &1u32.to_ne_bytes(),
+        &1u32.to_ne_bytes(),
+        &o.x.to_ne_bytes(),
+        &o.y.to_ne_bytes(),
+        &o.width.to_ne_bytes(),
+        &o.height.to_ne_bytes(),
+        &o.scale.to_ne_bytes(),
+        &1u32.to_ne_bytes(),
+    ]
+    .concat()
+}
+
+#[test]
+fn version() {
+    assert_eq!(PROTOCOL_VERSION, 4);
+}
+
+#[test]
+fn peer_info() {
+    let info = info();
+    assert_eq!(info.encode(), wire(&info));
+    assert_eq!(PeerInfo::decode(&wire(&info)), Ok(info));
+}
+
+#[test]
+fn keyboard_mode_is_optional() {
+    // peers predating the keysym mode
+    let info = info();
+    let mut wire = wire(&info);
+    wire.truncate(wire.len() - 4);
+    let decoded = PeerInfo::decode(&wire).unwrap();
+    assert_eq!(decoded.keyboard, KeyboardMode::Keycode);
+}
+
+#[test]
+fn invalid_keyboard_mode() {
+    let info = info();
+    let mut wire = wire(&info);
+    let len = wire.len();
+    wire[len - 4..].copy_from_slice(&2u32.to_ne_bytes());
+    assert_eq!(PeerInfo::decode(&wire), Err(DecodeError::InvalidPeerInfo));
+}
+
+#[test]
+fn truncated() {
+    let wire = wire(&info());
+    // everything before the keyboard mode is required
+    for len in 0..wire.len() - 4 {
+        assert_eq!(
+            PeerInfo::decode(&wire[..len]),
+            Err(DecodeError::InvalidPeerInfo),
+            "{} bytes",
+            len
+        );
+    }
+}
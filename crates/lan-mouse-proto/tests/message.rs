@@ -0,0 +1,146 @@
+//! Frames of the control channel.
+
+use lan_mouse_proto::{
+    message::{self, keymap_hash, DataRequest, ErrorCode, Message, Push, RemoteError, MAX_FRAME},
+    Decode, DecodeError, Encode,
+};
+
+/// `msg` encodes to a frame with `body` and decodes back
+fn assert_wire(msg: Message, body: &[u8]) {
+    let frame = [&(body.len() as u32).to_ne_bytes()[..], body].concat();
+    assert_eq!(msg.encode(), frame, "encoding of {:?}", msg);
+    let (decoded, len) = message::split_frame(&frame).unwrap().unwrap();
+    assert_eq!(len, frame.len());
+    assert_eq!(decoded, body);
+    assert_eq!(Message::decode(decoded), Ok(msg));
+}
+
+#[test]
+fn hello() {
+    assert_wire(
+        Message::Hello { port: 4242 },
+        &[&[0][..], &4242u16.to_ne_bytes()].concat(),
+    );
+}
+
+#[test]
+fn request() {
+    let msg = Message::Request {
+        id: 9,
+        req: DataRequest::Handshake,
+        payload: b"info".to_vec(),
+    };
+    let body = [&[1][..], &9u32.to_ne_bytes(), &3u32.to_ne_bytes(), b"info"].concat();
+    assert_wire(msg, &body);
+}
+
+#[test]
+fn response() {
+    let msg = Message::Response {
+        id: 9,
+        data: Some(b"keymap".to_vec()),
+    };
+    let body = [&[2][..], &9u32.to_ne_bytes(), &[1], b"keymap"].concat();
+    assert_wire(msg, &body);
+    let msg = Message::Response { id: 9, data: None };
+    let body = [&[2][..], &9u32.to_ne_bytes(), &[0]].concat();
+    assert_wire(msg, &body);
+}
+
+#[test]
+fn error() {
+    let msg = Message::Error {
+        id: 1,
+        error: RemoteError::new(ErrorCode::UnknownRequest, "no"),
+    };
+    let body = [&[3][..], &1u32.to_ne_bytes(), &1u32.to_ne_bytes(), b"no"].concat();
+    assert_wire(msg, &body);
+}
+
+#[test]
+fn push() {
+    let msg = Message::Push(Push::KeyMapChanged(0x0123456789abcdef));
+    let body = [
+        &[4][..],
+        &0u32.to_ne_bytes(),
+        &0x0123456789abcdefu64.to_ne_bytes(),
+    ]
+    .concat();
+    assert_wire(msg, &body);
+    let msg = Message::Push(Push::ClipboardChanged);
+    let body = [&[4][..], &1u32.to_ne_bytes()].concat();
+    assert_wire(msg, &body);
+}
+
+#[test]
+fn requests() {
+    let requests = [
+        (DataRequest::KeyMap, 0),
+        (DataRequest::Clipboard, 1),
+        (DataRequest::Capabilities, 2),
+        (DataRequest::Handshake, 3),
+    ];
+    for (req, n) in requests {
+        assert_eq!(u32::from(req), n);
+        assert_eq!(DataRequest::try_from(n), Ok(req));
+    }
+    assert_eq!(
+        DataRequest::try_from(4),
+        Err(DecodeError::InvalidRequest(4))
+    );
+}
+
+#[test]
+fn error_codes() {
+    assert_eq!(u32::from(ErrorCode::UnknownRequest), 1);
+    assert_eq!(u32::from(ErrorCode::InvalidPayload), 2);
+    assert_eq!(ErrorCode::from(77), ErrorCode::Other(77));
+}
+
+#[test]
+fn incomplete_frames() {
+    let frame = Message::Hello { port: 1 }.encode();
+    for len in 0..frame.len() {
+        assert_eq!(message::split_frame(&frame[..len]), Ok(None));
+    }
+}
+
+#[test]
+fn frames_back_to_back() {
+    let mut buf = Message::Hello { port: 1 }.encode();
+    Message::Push(Push::ClipboardChanged).encode_into(&mut buf);
+    let (_, len) = message::split_frame(&buf).unwrap().unwrap();
+    let (frame, _) = message::split_frame(&buf[len..]).unwrap().unwrap();
+    assert_eq!(
+        Message::decode(frame),
+        Ok(Message::Push(Push::ClipboardChanged))
+    );
+}
+
+#[test]
+fn frame_too_large() {
+    let len = (MAX_FRAME as u32 + 1).to_ne_bytes();
+    assert_eq!(
+        message::split_frame(&len),
+        Err(DecodeError::FrameTooLarge(MAX_FRAME + 1))
+    );
+}
+
+#[test]
+fn unknown() {
+    assert_eq!(Message::decode(&[5]), Err(DecodeError::UnknownMessage(5)));
+    let push = [&[4][..], &2u32.to_ne_bytes()].concat();
+    assert_eq!(Message::decode(&push), Err(DecodeError::UnknownPush(2)));
+    let request = [&[1][..], &9u32.to_ne_bytes(), &4u32.to_ne_bytes()].concat();
+    assert_eq!(
+        Message::decode(&request),
+        Err(DecodeError::UnknownRequest { id: 9, req: 4 })
+    );
+    assert_eq!(Message::decode(&[0, 1]), Err(DecodeError::InvalidMessage));
+}
+
+#[test]
+fn keymap_hash_is_fnv1a() {
+    assert_eq!(keymap_hash(b""), 0xcbf29ce484222325);
+    assert_eq!(keymap_hash(b"a"), 0xaf63dc4c8601ec8c);
+}
@@ -1,147 +1,138 @@
 use lan_mouse::{
+    client::Client,
     config::Config,
-    protocol::{self, DataRequest},
-};
-use std::{
-    io::{BufWriter, Write},
-    os::unix::prelude::AsRawFd,
-};
-
-use wayland_protocols_wlr::virtual_pointer::v1::client::{
-    zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1 as VpManager,
-    zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1 as Vp,
+    dbus, emulation, logging,
+    poll::{self, Shutdown, Timer},
+    protocol,
+    recording::{self, Recorder},
+    stats,
 };
+use std::{error::Error, os::unix::prelude::AsRawFd, process, time::Duration};
 
-use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::{
-    zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1 as VkManager,
-    zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1 as Vk,
-};
-
-use wayland_client::{
-    delegate_noop,
-    globals::{registry_queue_init, GlobalListContents},
-    protocol::{wl_keyboard, wl_pointer, wl_registry, wl_seat},
-    Connection, Dispatch, EventQueue, QueueHandle,
-};
+use tracing::{error, info, warn};
 
-use tempfile;
+/// interval of the housekeeping timer
+const TICK: Duration = Duration::from_secs(1);
 
-// App State, implements Dispatch event handlers
-struct App;
-
-fn main() {
-    let config = Config::new("config.toml").unwrap();
-    let conn = Connection::connect_to_env().unwrap();
-    let (globals, queue) = registry_queue_init::<App>(&conn).unwrap();
-    let qh = queue.handle();
-
-    let vpm: VpManager = globals.bind(&qh, 1..=1, ()).unwrap();
-    let vkm: VkManager = globals.bind(&qh, 1..=1, ()).unwrap();
-    let seat: wl_seat::WlSeat = globals.bind(&qh, 7..=8, ()).unwrap();
+#[derive(Default)]
+struct Args {
+    log: logging::LogOptions,
+    /// record received events into this file
+    record: Option<String>,
+    /// replay events from this file instead of receiving them
+    replay: Option<String>,
+    /// replay speed, 0 replays without delay
+    speed: f64,
+}
 
-    let pointer: Vp = vpm.create_virtual_pointer(None, &qh, ());
-    let keyboard: Vk = vkm.create_virtual_keyboard(&seat, &qh, ());
-    let connection = protocol::Connection::new(config);
-    let data = loop {
-        match connection.receive_data(DataRequest::KeyMap) {
-            Some(data) => break data,
-            None => {}
+impl Args {
+    fn parse() -> Result<Args, Box<dyn Error>> {
+        let mut opts = Args {
+            speed: 1.0,
+            ..Default::default()
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if opts.log.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--record" => opts.record = Some(args.next().ok_or("--record: missing path")?),
+                "--replay" => opts.replay = Some(args.next().ok_or("--replay: missing path")?),
+                "--speed" => opts.speed = args.next().ok_or("--speed: missing value")?.parse()?,
+                _ => return Err(format!("unknown argument \"{}\"", arg).into()),
+            }
         }
-    };
-    // TODO use shm_open
-    let f = tempfile::tempfile().unwrap();
-    let mut buf = BufWriter::new(&f);
-    buf.write_all(&data[..]).unwrap();
-    buf.flush().unwrap();
-    keyboard.keymap(1, f.as_raw_fd(), data.len() as u32);
-    loop {
-        receive_event(&connection, &pointer, &keyboard, &queue).unwrap();
+        Ok(opts)
     }
 }
 
-/// main loop handling udp packets
-fn receive_event(
-    connection: &protocol::Connection,
-    pointer: &Vp,
-    keyboard: &Vk,
-    q: &EventQueue<App>,
-) -> std::io::Result<()> {
-    let event = if let Some(event) = connection.receive_event() {
-        event
-    } else {
-        return Ok(());
+fn main() {
+    // before any thread is spawned
+    let shutdown = match Shutdown::new() {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            eprintln!("could not set up signal handling: {}", e);
+            process::exit(1);
+        }
     };
-    match event {
-        protocol::Event::Pointer(e) => match e {
-            wl_pointer::Event::Motion {
-                time,
-                surface_x,
-                surface_y,
-            } => {
-                pointer.motion(time, surface_x, surface_y);
-                pointer.frame();
-            }
-            wl_pointer::Event::Button {
-                serial: _,
-                time: t,
-                button: b,
-                state: s,
-            } => {
-                pointer.button(t, b, s.into_result().unwrap());
-                pointer.frame();
-            }
-            wl_pointer::Event::Axis {
-                time: t,
-                axis: a,
-                value: v,
-            } => {
-                pointer.axis(t, a.into_result().unwrap(), v);
-                pointer.frame();
-            }
-            wl_pointer::Event::Frame => {
-                pointer.frame();
-            }
-            _ => todo!(),
-        },
-        protocol::Event::Keyboard(e) => match e {
-            wl_keyboard::Event::Key {
-                serial: _,
-                time: t,
-                key: k,
-                state: s,
-            } => {
-                keyboard.key(t, k, u32::from(s));
-            }
-            wl_keyboard::Event::Modifiers {
-                serial: _,
-                mods_depressed,
-                mods_latched,
-                mods_locked,
-                group,
-            } => {
-                keyboard.modifiers(mods_depressed, mods_latched, mods_locked, group);
-            }
-            _ => todo!(),
-        },
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = logging::init(args.log) {
+        eprintln!("could not initialize logging: {}", e);
+        process::exit(1);
     }
-    q.flush().unwrap();
-    Ok(())
-}
+    let _span = tracing::info_span!("client").entered();
+    let mut emulation = match emulation::create() {
+        Ok(emulation) => emulation,
+        Err(e) => {
+            error!("could not create emulation backend: {}", e);
+            process::exit(1);
+        }
+    };
 
-delegate_noop!(App: Vp);
-delegate_noop!(App: Vk);
-delegate_noop!(App: VpManager);
-delegate_noop!(App: VkManager);
-delegate_noop!(App: wl_seat::WlSeat);
+    if let Some(path) = args.replay {
+        if let Err(e) = recording::replay(&path, args.speed, emulation.as_mut()) {
+            error!("replay failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
-impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for App {
-    fn event(
-        _: &mut App,
-        _: &wl_registry::WlRegistry,
-        _: wl_registry::Event,
-        _: &GlobalListContents,
-        _: &Connection,
-        _: &QueueHandle<App>,
-    ) {
+    let config = match Config::new("config.toml") {
+        Ok(config) => config,
+        Err(e) => {
+            error!("could not read config.toml: {}", e);
+            process::exit(1);
+        }
+    };
+    let metrics_port = config.metrics_port;
+    let connection = protocol::Connection::new(config);
+    if let Some(port) = metrics_port {
+        if let Err(e) = stats::serve_http(connection.stats(), port) {
+            warn!("could not serve metrics: {}", e);
+        }
+    }
+    let _dbus = dbus::serve(dbus::Role::Client, connection.peers(), connection.stats())
+        .map_err(|e| warn!("could not start dbus service: {}", e))
+        .ok();
+    let recorder = match args.record.map(|path| Recorder::create(&path)).transpose() {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            error!("could not create recording: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut client = Client::new(connection, emulation, recorder);
+    let timer = match Timer::new(TICK) {
+        Ok(timer) => timer,
+        Err(e) => {
+            error!("could not create timer: {}", e);
+            process::exit(1);
+        }
+    };
+    let fds = poll::readable([timer.as_raw_fd(), shutdown.as_raw_fd()]);
+    loop {
+        let mut ready = fds.clone();
+        ready.extend(client.fds());
+        if let Err(e) = poll::wait(&ready, client.timeout()) {
+            error!("poll failed: {}", e);
+            process::exit(1);
+        }
+        if shutdown.requested() {
+            info!("shutting down");
+            client.flush();
+            return;
+        }
+        timer.expirations();
+        if let Err(e) = client.dispatch() {
+            error!("emulation backend failed: {}", e);
+            process::exit(1);
+        }
     }
 }
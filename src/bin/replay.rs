@@ -0,0 +1,115 @@
+use lan_mouse::{
+    config::Config,
+    logging,
+    protocol::{self, DataRequest, Position},
+    recording::{Player, Record},
+};
+use memmap::Mmap;
+
+use std::{
+    error::Error,
+    io::{BufWriter, Write},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use tracing::{error, info};
+
+/// how often the request channel checks whether playback ended
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Send a recorded session to a peer:
+///
+/// `replay <recording> [--to <position>] [--speed <factor>]`
+struct Args {
+    log: logging::LogOptions,
+    path: String,
+    to: Position,
+    speed: f64,
+}
+
+impl Args {
+    fn parse() -> Result<Args, Box<dyn Error>> {
+        let mut log = logging::LogOptions::default();
+        let mut path = None;
+        let mut to = Position::Right;
+        let mut speed = 1.0;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if log.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+            match arg.as_str() {
+                "--to" => to = args.next().ok_or("--to: missing position")?.parse()?,
+                "--speed" => speed = args.next().ok_or("--speed: missing value")?.parse()?,
+                _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+                _ => return Err(format!("unknown argument \"{}\"", arg).into()),
+            }
+        }
+        let path = path.ok_or("usage: replay <recording> [--to <position>] [--speed <factor>]")?;
+        Ok(Args {
+            log,
+            path,
+            to,
+            speed,
+        })
+    }
+}
+
+fn main() {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = logging::init(args.log) {
+        eprintln!("could not initialize logging: {}", e);
+        process::exit(1);
+    }
+    if let Err(e) = run(&args.path, args.to, args.speed) {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(path: &str, to: Position, speed: f64) -> Result<(), Box<dyn Error>> {
+    let config = Config::new("config.toml")?;
+    let connection = protocol::Connection::new(config);
+    connection.peers().switch_to(to)?;
+    let player = Player::open(path)?;
+    info!(path, position = %to, speed, "replaying recording");
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        // serve the offered keymap while playing
+        s.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                connection.drive(POLL_INTERVAL);
+            }
+        });
+        let result = player.play(speed, |record| match record {
+            Record::Event(event) => connection.send_event(event),
+            Record::KeyMap(data) => match offer_keymap(&connection, &data) {
+                Ok(()) => info!(len = data.len(), "offering recorded keymap"),
+                Err(e) => error!("could not offer keymap: {}", e),
+            },
+        });
+        done.store(true, Ordering::Relaxed);
+        result
+    })?;
+    Ok(())
+}
+
+fn offer_keymap(connection: &protocol::Connection, data: &[u8]) -> std::io::Result<()> {
+    let f = tempfile::tempfile()?;
+    let mut buf = BufWriter::new(&f);
+    buf.write_all(data)?;
+    buf.flush()?;
+    drop(buf);
+    let mmap = unsafe { Mmap::map(&f)? };
+    connection.offer_data(DataRequest::KeyMap, mmap);
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use std::{env, error::Error, io, os::unix::prelude::RawFd};
+
+use memmap::Mmap;
+use tracing::{info, warn};
+
+use crate::{
+    config,
+    event::{self, Event},
+    protocol::Position,
+};
+
+#[cfg(feature = "evdev")]
+pub mod evdev;
+pub mod memory;
+#[cfg(feature = "libei")]
+pub mod portal;
+pub mod wayland;
+#[cfg(feature = "x11")]
+pub mod x11;
+
+// xkb modifier masks of the default keymap
+pub const MOD_SHIFT: u32 = 1;
+pub const MOD_CTRL: u32 = 4;
+pub const MOD_ALT: u32 = 8;
+pub const MOD_SUPER: u32 = 64;
+
+/// Depressed modifiers of the release shortcut, ctrl shift super alt.
+/// Ends the grab in every backend.
+pub const RELEASE_MODIFIERS: u32 = MOD_SHIFT | MOD_CTRL | MOD_ALT | MOD_SUPER;
+
+pub enum CaptureEvent {
+    /// the pointer crossed the edge towards the peer at `Position`,
+    /// pointer and keyboard are grabbed
+    Begin(Position),
+    /// input captured while grabbed
+    Input(Event),
+    /// the grab ended, e.g. through the release shortcut
+    End,
+    /// keymap of the captured keyboard, offered to peers
+    KeyMap(Mmap),
+}
+
+/// A source of local input events.
+///
+/// Backends watch the screen edges at the given positions,
+/// grab pointer and keyboard when one is crossed and
+/// report everything in a backend neutral format.
+pub trait InputCapture {
+    /// short name of the backend, advertised to peers
+    fn name(&self) -> &'static str;
+
+    /// format of the keymaps reported as [`CaptureEvent::KeyMap`]
+    fn keymap_format(&self) -> u32 {
+        event::KEYMAP_NONE
+    }
+
+    /// file descriptors that become readable when input is pending,
+    /// see [`crate::poll`]
+    fn fds(&self) -> Vec<RawFd>;
+
+    /// Process pending input without blocking.
+    /// Events can be retrieved with [`InputCapture::next_event`] afterwards.
+    fn dispatch(&mut self) -> io::Result<()>;
+
+    /// next pending event, if any
+    fn next_event(&mut self) -> Option<CaptureEvent>;
+
+    /// Grab pointer and keyboard for the peer at `pos` as if its edge was
+    /// crossed, reported as [`CaptureEvent::Begin`] like any other grab.
+    /// Not every backend can grab without the pointer entering the edge.
+    fn grab(&mut self, _pos: Position) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} capture can only grab at the screen edges", self.name()),
+        ))
+    }
+
+    /// ungrab pointer and keyboard, reported as [`CaptureEvent::End`]
+    fn release(&mut self) -> io::Result<()>;
+}
+
+/// Create the capture backend for the current session:
+/// evdev if configured, otherwise layer-shell or the InputCapture
+/// portal on wayland and edge windows with XInput2 on X11.
+pub fn create(
+    positions: &[Position],
+    evdev: Option<&config::Evdev>,
+) -> Result<Box<dyn InputCapture>, Box<dyn Error>> {
+    if let Some(_config) = evdev {
+        #[cfg(feature = "evdev")]
+        {
+            let backend = evdev::EvdevCapture::new(positions, _config)?;
+            info!("using evdev capture");
+            return Ok(Box::new(backend));
+        }
+        #[cfg(not(feature = "evdev"))]
+        warn!("evdev capture configured but not compiled in");
+    }
+    if env::var_os("WAYLAND_DISPLAY").is_some() {
+        match wayland::LayerShellCapture::new(positions) {
+            Ok(backend) => {
+                info!("using layer-shell capture");
+                return Ok(Box::new(backend));
+            }
+            Err(e) => warn!("layer-shell capture unavailable: {}", e),
+        }
+        #[cfg(feature = "libei")]
+        match portal::PortalCapture::new(positions) {
+            Ok(backend) => {
+                info!("using InputCapture portal");
+                return Ok(Box::new(backend));
+            }
+            Err(e) => warn!("InputCapture portal unavailable: {}", e),
+        }
+    }
+    #[cfg(feature = "x11")]
+    if env::var_os("DISPLAY").is_some() {
+        let backend = x11::X11Capture::new(positions)?;
+        info!("using X11 capture");
+        return Ok(Box::new(backend));
+    }
+    Err("no capture backend available for this session".into())
+}
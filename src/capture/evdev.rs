@@ -0,0 +1,335 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use evdev::{Device, EventType, InputEvent, RelativeAxisType};
+use tracing::{info, warn};
+
+use crate::{
+    config,
+    event::{self, Event, KeyboardEvent, PointerEvent},
+    protocol::Position,
+};
+
+use super::{
+    CaptureEvent, InputCapture, MOD_ALT, MOD_CTRL, MOD_SHIFT, MOD_SUPER, RELEASE_MODIFIERS,
+};
+
+/// scroll distance of one wheel click, as reported by libinput
+const SCROLL_STEP: f64 = 15.0;
+
+// linux/input-event-codes.h
+const SYN_REPORT: u16 = 0;
+const BTN_MISC: u16 = 0x100;
+const BTN_GAMEPAD: u16 = 0x130;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_LEFTALT: u16 = 56;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_RIGHTALT: u16 = 100;
+const KEY_LEFTMETA: u16 = 125;
+const KEY_RIGHTMETA: u16 = 126;
+
+/// Capture backend reading physical devices from `/dev/input/event*`.
+///
+/// The devices are not grabbed until an edge is crossed, so local
+/// input keeps working. Since the real cursor position is unknown,
+/// a virtual cursor is moved by the relative motion of the devices
+/// to detect edge crossings. Once crossed, the devices are grabbed
+/// with `EVIOCGRAB` until released.
+pub struct EvdevCapture {
+    devices: Vec<(PathBuf, Device)>,
+    positions: Vec<Position>,
+    size: (f64, f64),
+    cursor: (f64, f64),
+    /// motion since the last `SYN_REPORT`
+    motion: (f64, f64),
+    modifiers: u32,
+    grabbed: bool,
+    pending: VecDeque<CaptureEvent>,
+}
+
+/// whether `device` is selected by `spec`, which is either its path,
+/// a `vendor:product` id in hex or the device name
+fn matches(spec: &str, path: &Path, device: &Device) -> bool {
+    if path.as_os_str() == spec {
+        return true;
+    }
+    let id = device.input_id();
+    if spec.eq_ignore_ascii_case(&format!("{:04x}:{:04x}", id.vendor(), id.product())) {
+        return true;
+    }
+    device.name() == Some(spec)
+}
+
+fn timestamp(e: &InputEvent) -> u32 {
+    e.timestamp()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u32
+}
+
+fn modifier(key: u16) -> u32 {
+    match key {
+        KEY_LEFTSHIFT | KEY_RIGHTSHIFT => MOD_SHIFT,
+        KEY_LEFTCTRL | KEY_RIGHTCTRL => MOD_CTRL,
+        KEY_LEFTALT | KEY_RIGHTALT => MOD_ALT,
+        KEY_LEFTMETA | KEY_RIGHTMETA => MOD_SUPER,
+        _ => 0,
+    }
+}
+
+impl EvdevCapture {
+    pub fn new(positions: &[Position], config: &config::Evdev) -> Result<Self, Box<dyn Error>> {
+        let mut available = vec![];
+        let mut devices = vec![];
+        for (path, device) in evdev::enumerate() {
+            let id = device.input_id();
+            let description = format!(
+                "{} ({:04x}:{:04x}, {})",
+                device.name().unwrap_or("unnamed"),
+                id.vendor(),
+                id.product(),
+                path.display()
+            );
+            if config.devices.iter().any(|s| matches(s, &path, &device)) {
+                info!(device = description, "capturing device");
+                devices.push((path, device));
+            } else {
+                available.push(description);
+            }
+        }
+        if devices.is_empty() {
+            return Err(format!(
+                "none of the configured devices found, available devices: {}",
+                available.join(", ")
+            )
+            .into());
+        }
+        let size = (config.width as f64, config.height as f64);
+        Ok(EvdevCapture {
+            devices,
+            positions: positions.to_vec(),
+            size,
+            cursor: (size.0 / 2., size.1 / 2.),
+            motion: (0., 0.),
+            modifiers: 0,
+            grabbed: false,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn grab_devices(&mut self, pos: Position) {
+        info!(position = %pos, "grabbing devices");
+        for (path, device) in self.devices.iter_mut() {
+            if let Err(e) = device.grab() {
+                warn!(path = %path.display(), "could not grab device: {}", e);
+            }
+        }
+        self.grabbed = true;
+        self.pending.push_back(CaptureEvent::Begin(pos));
+    }
+
+    fn ungrab(&mut self) {
+        if !self.grabbed {
+            return;
+        }
+        info!("releasing devices");
+        for (path, device) in self.devices.iter_mut() {
+            if let Err(e) = device.ungrab() {
+                warn!(path = %path.display(), "could not release device: {}", e);
+            }
+        }
+        self.grabbed = false;
+        self.modifiers = 0;
+        self.pending.push_back(CaptureEvent::End);
+    }
+
+    /// Forget unplugged devices. Ends the grab, so the peer
+    /// releases the keys that were held on them.
+    fn remove(&mut self, removed: &[usize]) {
+        if removed.is_empty() {
+            return;
+        }
+        for &i in removed.iter().rev() {
+            let (path, _) = self.devices.remove(i);
+            warn!(path = %path.display(), "device removed");
+        }
+        if self.devices.is_empty() {
+            warn!("no devices left to capture");
+        }
+        self.ungrab();
+    }
+
+    fn input(&mut self, e: Event) {
+        if self.grabbed {
+            self.pending.push_back(CaptureEvent::Input(e));
+        }
+    }
+
+    /// move the virtual cursor, returns the edge that was crossed
+    fn move_cursor(&mut self, dx: f64, dy: f64) -> Option<Position> {
+        let (x, y) = (self.cursor.0 + dx, self.cursor.1 + dy);
+        let crossed = if x < 0. {
+            Some(Position::Left)
+        } else if x >= self.size.0 {
+            Some(Position::Right)
+        } else if y < 0. {
+            Some(Position::Top)
+        } else if y >= self.size.1 {
+            Some(Position::Bottom)
+        } else {
+            None
+        };
+        self.cursor = (x.clamp(0., self.size.0 - 1.), y.clamp(0., self.size.1 - 1.));
+        crossed.filter(|pos| self.positions.contains(pos))
+    }
+
+    fn handle(&mut self, e: InputEvent) {
+        let time = timestamp(&e);
+        match e.event_type() {
+            EventType::SYNCHRONIZATION if e.code() == SYN_REPORT => {
+                let (dx, dy) = std::mem::take(&mut self.motion);
+                if dx == 0. && dy == 0. {
+                    return;
+                }
+                if self.grabbed {
+                    self.input(Event::Pointer(PointerEvent::Motion { time, dx, dy }));
+                    self.input(Event::Pointer(PointerEvent::Frame));
+                } else if let Some(pos) = self.move_cursor(dx, dy) {
+                    self.grab_devices(pos);
+                }
+            }
+            EventType::RELATIVE => match RelativeAxisType(e.code()) {
+                RelativeAxisType::REL_X => self.motion.0 += e.value() as f64,
+                RelativeAxisType::REL_Y => self.motion.1 += e.value() as f64,
+                // the vertical wheel turns up for positive values
+                RelativeAxisType::REL_WHEEL => self.input(Event::Pointer(PointerEvent::Axis {
+                    time,
+                    axis: event::AXIS_VERTICAL,
+                    value: -e.value() as f64 * SCROLL_STEP,
+                })),
+                RelativeAxisType::REL_HWHEEL => self.input(Event::Pointer(PointerEvent::Axis {
+                    time,
+                    axis: event::AXIS_HORIZONTAL,
+                    value: e.value() as f64 * SCROLL_STEP,
+                })),
+                _ => {}
+            },
+            EventType::KEY => {
+                let (code, value) = (e.code(), e.value() as u32);
+                if (BTN_MISC..BTN_GAMEPAD).contains(&code) {
+                    self.input(Event::Pointer(PointerEvent::Button {
+                        time,
+                        button: code as u32,
+                        state: value,
+                    }));
+                    return;
+                }
+                // autorepeat is up to the peer
+                if value != event::KEY_PRESSED && value != event::KEY_RELEASED {
+                    return;
+                }
+                self.input(Event::Keyboard(KeyboardEvent::Key {
+                    time,
+                    key: code as u32,
+                    state: value,
+                }));
+                let modifier = modifier(code);
+                if modifier == 0 {
+                    return;
+                }
+                match value {
+                    event::KEY_PRESSED => self.modifiers |= modifier,
+                    _ => self.modifiers &= !modifier,
+                }
+                self.input(Event::Keyboard(KeyboardEvent::Modifiers {
+                    mods_depressed: self.modifiers,
+                    mods_latched: 0,
+                    mods_locked: 0,
+                    group: 0,
+                }));
+                if self.grabbed && self.modifiers == RELEASE_MODIFIERS {
+                    info!("release shortcut pressed");
+                    self.ungrab();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl InputCapture for EvdevCapture {
+    fn name(&self) -> &'static str {
+        "evdev"
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        self.devices.iter().map(|(_, d)| d.as_raw_fd()).collect()
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        // find the readable devices, reading the others would block
+        let mut fds: Vec<_> = self
+            .devices
+            .iter()
+            .map(|(_, device)| libc::pollfd {
+                fd: device.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 0) } < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::Interrupted => Ok(()),
+                _ => Err(e),
+            };
+        }
+        let mut removed = vec![];
+        for (i, fd) in fds.iter().enumerate() {
+            if fd.revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+                removed.push(i);
+                continue;
+            }
+            if fd.revents & libc::POLLIN == 0 {
+                continue;
+            }
+            let events: Vec<_> = match self.devices[i].1.fetch_events() {
+                Ok(events) => events.collect(),
+                Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+                    removed.push(i);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            for e in events {
+                self.handle(e);
+            }
+        }
+        self.remove(&removed);
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<CaptureEvent> {
+        self.pending.pop_front()
+    }
+
+    fn grab(&mut self, pos: Position) -> io::Result<()> {
+        if !self.grabbed {
+            self.grab_devices(pos);
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        self.ungrab();
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    collections::VecDeque,
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+    sync::mpsc::TryRecvError,
+};
+
+use crate::{
+    event,
+    poll::{self, Receiver, Sender},
+    protocol::Position,
+};
+
+use super::{CaptureEvent, InputCapture};
+
+/// In-memory capture backend, fed through a channel.
+///
+/// Behaves like a real backend: [`CaptureEvent::Input`] is only
+/// reported after a [`CaptureEvent::Begin`] and until released.
+pub struct MemoryCapture {
+    rx: Receiver<CaptureEvent>,
+    pending: VecDeque<CaptureEvent>,
+    grabbed: bool,
+}
+
+/// create a capture backend together with the sender used to inject events
+pub fn new() -> io::Result<(MemoryCapture, Sender<CaptureEvent>)> {
+    let (tx, rx) = poll::channel()?;
+    let capture = MemoryCapture {
+        rx,
+        pending: VecDeque::new(),
+        grabbed: false,
+    };
+    Ok((capture, tx))
+}
+
+impl MemoryCapture {
+    fn queue(&mut self, e: CaptureEvent) {
+        match e {
+            CaptureEvent::Begin(_) => self.grabbed = true,
+            CaptureEvent::End => self.grabbed = false,
+            CaptureEvent::Input(_) if !self.grabbed => return,
+            _ => {}
+        }
+        self.pending.push_back(e);
+    }
+}
+
+impl InputCapture for MemoryCapture {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn keymap_format(&self) -> u32 {
+        event::KEYMAP_XKB_V1
+    }
+
+    /// readable while injected events are pending
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.rx.as_raw_fd()]
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(e) => self.queue(e),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, e)),
+            }
+        }
+    }
+
+    fn next_event(&mut self) -> Option<CaptureEvent> {
+        self.pending.pop_front()
+    }
+
+    fn grab(&mut self, pos: Position) -> io::Result<()> {
+        if !self.grabbed {
+            self.queue(CaptureEvent::Begin(pos));
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        if self.grabbed {
+            self.queue(CaptureEvent::End);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,501 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::{
+        net::UnixStream,
+        prelude::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+    },
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+use memmap::Mmap;
+use tracing::{debug, info, warn};
+use zbus::{
+    blocking,
+    zvariant::{OwnedFd, OwnedObjectPath, OwnedValue, Structure, Value},
+};
+
+use crate::{
+    ei::{self, Context, Devices, Interface, Message},
+    event::{self, Event, KeyboardEvent, PointerEvent},
+    portal::{self, Portal},
+    protocol::Position,
+};
+
+use super::{CaptureEvent, InputCapture, RELEASE_MODIFIERS};
+
+const INPUT_CAPTURE: &str = "org.freedesktop.portal.InputCapture";
+
+/// capabilities for `InputCapture.CreateSession`
+const CAPABILITY_KEYBOARD: u32 = 1;
+const CAPABILITY_POINTER: u32 = 2;
+
+// events of ei_pointer, ei_button, ei_scroll, ei_keyboard
+const POINTER_MOTION_RELATIVE: u32 = 1;
+const BUTTON_BUTTON: u32 = 1;
+const SCROLL_SCROLL: u32 = 1;
+const SCROLL_DISCRETE: u32 = 2;
+const KEYBOARD_KEYMAP: u32 = 1;
+const KEYBOARD_KEY: u32 = 2;
+const KEYBOARD_MODIFIERS: u32 = 3;
+
+/// scroll distance of one wheel click, as reported by libinput
+const SCROLL_STEP: f64 = 15.0;
+/// `scroll_discrete` units per wheel click
+const DISCRETE_STEP: f64 = 120.0;
+
+/// signals of the InputCapture session
+enum Signal {
+    Activated {
+        activation_id: Option<u32>,
+        barrier_id: Option<u32>,
+    },
+    Deactivated,
+    Disabled,
+    ZonesChanged,
+}
+
+/// Capture backend using the InputCapture portal.
+///
+/// Pointer barriers are placed at the outer edges of the screen for
+/// every configured peer. Once the compositor reports a barrier hit,
+/// input is captured and delivered through the EIS connection of the
+/// session until released.
+pub struct PortalCapture {
+    portal: Portal,
+    proxy: blocking::Proxy<'static>,
+    session: OwnedObjectPath,
+    ctx: Context,
+    positions: Vec<Position>,
+    barriers: HashMap<u32, Position>,
+    signals: Receiver<Signal>,
+    /// readable whenever a signal was received
+    wake: UnixStream,
+    devices: Devices,
+    activation: Option<u32>,
+    active: bool,
+    /// events of the current frame, timestamps are set on the frame event
+    frame: Vec<Event>,
+    pending: VecDeque<CaptureEvent>,
+}
+
+fn get_u32(options: &HashMap<String, OwnedValue>, key: &str) -> Option<u32> {
+    match options.get(key).map(|v| &**v) {
+        Some(Value::U32(v)) => Some(*v),
+        _ => None,
+    }
+}
+
+/// Barriers along the outer edges of the zones, as `(x1, y1, x2, y2)`.
+/// Only edges on the bounding box of all zones are used,
+/// so the pointer can still move between monitors.
+fn barriers(zones: &[(u32, u32, i32, i32)], positions: &[Position]) -> Vec<(Position, [i32; 4])> {
+    let min_x = zones.iter().map(|z| z.2).min().unwrap_or(0);
+    let min_y = zones.iter().map(|z| z.3).min().unwrap_or(0);
+    let max_x = zones.iter().map(|z| z.2 + z.0 as i32).max().unwrap_or(0);
+    let max_y = zones.iter().map(|z| z.3 + z.1 as i32).max().unwrap_or(0);
+    let mut barriers = vec![];
+    for &(w, h, x, y) in zones {
+        let (w, h) = (w as i32, h as i32);
+        for pos in positions {
+            let barrier = match pos {
+                Position::Left if x == min_x => [x, y, x, y + h - 1],
+                Position::Right if x + w == max_x => [x + w, y, x + w, y + h - 1],
+                Position::Top if y == min_y => [x, y, x + w - 1, y],
+                Position::Bottom if y + h == max_y => [x, y + h, x + w - 1, y + h],
+                _ => continue,
+            };
+            barriers.push((*pos, barrier));
+        }
+    }
+    barriers
+}
+
+/// set the time of a buffered event
+fn with_time(e: Event, t: u32) -> Event {
+    match e {
+        Event::Pointer(PointerEvent::Motion { dx, dy, .. }) => {
+            Event::Pointer(PointerEvent::Motion { time: t, dx, dy })
+        }
+        Event::Pointer(PointerEvent::Button { button, state, .. }) => {
+            Event::Pointer(PointerEvent::Button {
+                time: t,
+                button,
+                state,
+            })
+        }
+        Event::Pointer(PointerEvent::Axis { axis, value, .. }) => {
+            Event::Pointer(PointerEvent::Axis {
+                time: t,
+                axis,
+                value,
+            })
+        }
+        Event::Keyboard(KeyboardEvent::Key { key, state, .. }) => {
+            Event::Keyboard(KeyboardEvent::Key {
+                time: t,
+                key,
+                state,
+            })
+        }
+        e => e,
+    }
+}
+
+/// forward the signals of `session` to `tx`, waking up `wake` for each
+fn forward_signals(
+    proxy: blocking::Proxy<'static>,
+    session: OwnedObjectPath,
+    tx: mpsc::Sender<Signal>,
+    mut wake: UnixStream,
+) -> zbus::Result<()> {
+    let signals = proxy.receive_all_signals()?;
+    thread::spawn(move || {
+        for msg in signals {
+            let member = match msg.member() {
+                Some(member) => member.to_string(),
+                None => continue,
+            };
+            let (handle, options): (OwnedObjectPath, HashMap<String, OwnedValue>) = match msg.body()
+            {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!(member, "invalid signal: {}", e);
+                    continue;
+                }
+            };
+            if handle != session {
+                continue;
+            }
+            let signal = match member.as_str() {
+                "Activated" => Signal::Activated {
+                    activation_id: get_u32(&options, "activation_id"),
+                    barrier_id: get_u32(&options, "barrier_id"),
+                },
+                "Deactivated" => Signal::Deactivated,
+                "Disabled" => Signal::Disabled,
+                "ZonesChanged" => Signal::ZonesChanged,
+                _ => continue,
+            };
+            if tx.send(signal).is_err() || wake.write_all(&[0]).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+impl PortalCapture {
+    pub fn new(positions: &[Position]) -> Result<Self, Box<dyn Error>> {
+        let mut portal = Portal::new()?;
+        let proxy = portal.proxy(INPUT_CAPTURE)?;
+
+        let token = portal.token();
+        let session_token = portal.token();
+        let options = HashMap::from([
+            ("handle_token", Value::from(token.as_str())),
+            ("session_handle_token", Value::from(session_token.as_str())),
+            (
+                "capabilities",
+                Value::from(CAPABILITY_KEYBOARD | CAPABILITY_POINTER),
+            ),
+        ]);
+        let results = portal.request(&proxy, "CreateSession", &token, &("", options))?;
+        let session = portal::session_handle(&results)?;
+        info!(session = %session.as_str(), "InputCapture session created");
+
+        let options: HashMap<&str, Value> = HashMap::new();
+        let fd: OwnedFd = proxy.call("ConnectToEIS", &(&session, options))?;
+        let stream = unsafe { UnixStream::from_raw_fd(fd.into_raw_fd()) };
+        let ctx = Context::connect(
+            stream,
+            ei::CONTEXT_RECEIVER,
+            "lan-mouse",
+            &ei::INPUT_INTERFACES,
+        )?;
+        ctx.set_nonblocking(true)?;
+
+        let (tx, signals) = mpsc::channel();
+        let (wake, wake_tx) = UnixStream::pair()?;
+        wake.set_nonblocking(true)?;
+        forward_signals(proxy.clone(), session.clone(), tx, wake_tx)?;
+
+        let mut capture = PortalCapture {
+            portal,
+            proxy,
+            session,
+            ctx,
+            positions: positions.to_vec(),
+            barriers: HashMap::new(),
+            signals,
+            wake,
+            devices: Devices::default(),
+            activation: None,
+            active: false,
+            frame: vec![],
+            pending: VecDeque::new(),
+        };
+        capture.enable()?;
+        Ok(capture)
+    }
+
+    /// place barriers for the current zones and enable capturing
+    fn enable(&mut self) -> Result<(), Box<dyn Error>> {
+        let token = self.portal.token();
+        let options = HashMap::from([("handle_token", Value::from(token.as_str()))]);
+        let results =
+            self.portal
+                .request(&self.proxy, "GetZones", &token, &(&self.session, options))?;
+        let zone_set = get_u32(&results, "zone_set").ok_or("no zone set")?;
+        let zones: Vec<(u32, u32, i32, i32)> =
+            results.get("zones").ok_or("no zones")?.clone().try_into()?;
+        debug!(?zones, zone_set, "zones");
+
+        self.barriers.clear();
+        let barriers: Vec<HashMap<&str, Value>> = barriers(&zones, &self.positions)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (pos, [x1, y1, x2, y2]))| {
+                let id = i as u32 + 1;
+                self.barriers.insert(id, pos);
+                HashMap::from([
+                    ("barrier_id", Value::from(id)),
+                    ("position", Value::from(Structure::from((x1, y1, x2, y2)))),
+                ])
+            })
+            .collect();
+        let token = self.portal.token();
+        let options = HashMap::from([("handle_token", Value::from(token.as_str()))]);
+        let results = self.portal.request(
+            &self.proxy,
+            "SetPointerBarriers",
+            &token,
+            &(&self.session, options, barriers, zone_set),
+        )?;
+        if let Some(failed) = results.get("failed_barriers") {
+            let failed: Vec<u32> = failed.clone().try_into()?;
+            for id in failed {
+                warn!(position = ?self.barriers.remove(&id), "barrier rejected");
+            }
+        }
+        let options: HashMap<&str, Value> = HashMap::new();
+        self.proxy
+            .call_method("Enable", &(&self.session, options))?;
+        info!(barriers = self.barriers.len(), "input capture enabled");
+        Ok(())
+    }
+
+    fn begin(&mut self, activation_id: Option<u32>, barrier_id: Option<u32>) {
+        self.activation = activation_id;
+        match barrier_id.and_then(|id| self.barriers.get(&id)) {
+            Some(pos) => {
+                info!(position = %pos, "barrier hit, capturing input");
+                self.active = true;
+                self.pending.push_back(CaptureEvent::Begin(*pos));
+            }
+            // e.g. activated by the compositor for other reasons
+            None => {
+                debug!(?barrier_id, "activated without known barrier");
+                if let Err(e) = self.release_session() {
+                    warn!("could not release: {}", e);
+                }
+            }
+        }
+    }
+
+    fn end(&mut self) {
+        self.frame.clear();
+        if self.active {
+            self.active = false;
+            self.pending.push_back(CaptureEvent::End);
+        }
+    }
+
+    fn release_session(&mut self) -> zbus::Result<()> {
+        let mut options = HashMap::new();
+        if let Some(id) = self.activation.take() {
+            options.insert("activation_id", Value::from(id));
+        }
+        self.proxy
+            .call_method("Release", &(&self.session, options))?;
+        Ok(())
+    }
+
+    fn handle_signal(&mut self, signal: Signal) -> Result<(), Box<dyn Error>> {
+        match signal {
+            Signal::Activated {
+                activation_id,
+                barrier_id,
+            } => self.begin(activation_id, barrier_id),
+            Signal::Deactivated => self.end(),
+            Signal::Disabled | Signal::ZonesChanged => {
+                info!("zones changed, updating barriers");
+                self.end();
+                self.enable()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn input(&mut self, e: Event) {
+        if self.active {
+            self.frame.push(e);
+        }
+    }
+
+    fn handle(&mut self, msg: Message) -> io::Result<()> {
+        let mut msg = match self.devices.handle(&mut self.ctx, msg)? {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+        if self.devices.is_device(msg.object) {
+            if msg.opcode == ei::DEVICE_EVENT_FRAME {
+                let _serial = msg.u32()?;
+                let time = (msg.u64()? / 1000) as u32;
+                let events = std::mem::take(&mut self.frame);
+                let pointer = events.iter().any(|e| matches!(e, Event::Pointer(_)));
+                for e in events {
+                    self.pending
+                        .push_back(CaptureEvent::Input(with_time(e, time)));
+                }
+                if pointer {
+                    self.pending
+                        .push_back(CaptureEvent::Input(Event::Pointer(PointerEvent::Frame)));
+                }
+            }
+            return Ok(());
+        }
+        let interface = match self.devices.interface(msg.object) {
+            Some(interface) => interface,
+            None => return Ok(()),
+        };
+        match (interface, msg.opcode) {
+            (Interface::Pointer, POINTER_MOTION_RELATIVE) => {
+                let dx = msg.f32()? as f64;
+                let dy = msg.f32()? as f64;
+                self.input(Event::Pointer(PointerEvent::Motion { time: 0, dx, dy }));
+            }
+            (Interface::Button, BUTTON_BUTTON) => {
+                let button = msg.u32()?;
+                let state = msg.u32()?;
+                self.input(Event::Pointer(PointerEvent::Button {
+                    time: 0,
+                    button,
+                    state,
+                }));
+            }
+            (Interface::Scroll, SCROLL_SCROLL) => {
+                let x = msg.f32()? as f64;
+                let y = msg.f32()? as f64;
+                self.scroll(x, y);
+            }
+            (Interface::Scroll, SCROLL_DISCRETE) => {
+                let x = msg.i32()? as f64 / DISCRETE_STEP * SCROLL_STEP;
+                let y = msg.i32()? as f64 / DISCRETE_STEP * SCROLL_STEP;
+                self.scroll(x, y);
+            }
+            (Interface::Keyboard, KEYBOARD_KEYMAP) => {
+                let _keymap_type = msg.u32()?;
+                let size = msg.u32()?;
+                let fd = self
+                    .ctx
+                    .take_fd()
+                    .ok_or_else(|| io::Error::other("keymap without fd"))?;
+                info!(size, "keymap changed");
+                let mmap = unsafe { Mmap::map(&File::from(fd))? };
+                self.pending.push_back(CaptureEvent::KeyMap(mmap));
+            }
+            (Interface::Keyboard, KEYBOARD_KEY) => {
+                let key = msg.u32()?;
+                let state = msg.u32()?;
+                self.input(Event::Keyboard(KeyboardEvent::Key {
+                    time: 0,
+                    key,
+                    state,
+                }));
+            }
+            (Interface::Keyboard, KEYBOARD_MODIFIERS) => {
+                let _serial = msg.u32()?;
+                let mods_depressed = msg.u32()?;
+                let mods_locked = msg.u32()?;
+                let mods_latched = msg.u32()?;
+                let group = msg.u32()?;
+                self.input(Event::Keyboard(KeyboardEvent::Modifiers {
+                    mods_depressed,
+                    mods_latched,
+                    mods_locked,
+                    group,
+                }));
+                if self.active && mods_depressed == RELEASE_MODIFIERS {
+                    info!("release shortcut pressed");
+                    if let Err(e) = self.release() {
+                        warn!("could not release: {}", e);
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn scroll(&mut self, x: f64, y: f64) {
+        if y != 0. {
+            self.input(Event::Pointer(PointerEvent::Axis {
+                time: 0,
+                axis: event::AXIS_VERTICAL,
+                value: y,
+            }));
+        }
+        if x != 0. {
+            self.input(Event::Pointer(PointerEvent::Axis {
+                time: 0,
+                axis: event::AXIS_HORIZONTAL,
+                value: x,
+            }));
+        }
+    }
+
+    /// handle everything that is available without blocking
+    fn process(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        while self.wake.read(&mut buf).is_ok() {}
+        while let Ok(signal) = self.signals.try_recv() {
+            self.handle_signal(signal)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        while let Some(msg) = self.ctx.next_message()? {
+            self.handle(msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl InputCapture for PortalCapture {
+    fn name(&self) -> &'static str {
+        "input-capture-portal"
+    }
+
+    fn keymap_format(&self) -> u32 {
+        event::KEYMAP_XKB_V1
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.ctx.as_raw_fd(), self.wake.as_raw_fd()]
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        self.process()
+    }
+
+    fn next_event(&mut self) -> Option<CaptureEvent> {
+        self.pending.pop_front()
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        self.end();
+        self.release_session().map_err(io::Error::other)
+    }
+}
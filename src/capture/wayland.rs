@@ -0,0 +1,509 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::unix::prelude::{AsRawFd, FromRawFd, RawFd},
+};
+
+use memmap::Mmap;
+use tracing::{debug, info, warn};
+
+use wayland_protocols::wp::{
+    keyboard_shortcuts_inhibit::zv1::client::{
+        zwp_keyboard_shortcuts_inhibit_manager_v1::ZwpKeyboardShortcutsInhibitManagerV1,
+        zwp_keyboard_shortcuts_inhibitor_v1::ZwpKeyboardShortcutsInhibitorV1,
+    },
+    pointer_constraints::zv1::client::{
+        zwp_locked_pointer_v1::ZwpLockedPointerV1,
+        zwp_pointer_constraints_v1::{Lifetime, ZwpPointerConstraintsV1},
+    },
+    relative_pointer::zv1::client::{
+        zwp_relative_pointer_manager_v1::ZwpRelativePointerManagerV1,
+        zwp_relative_pointer_v1::{self, ZwpRelativePointerV1},
+    },
+};
+
+use wayland_protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::{Layer, ZwlrLayerShellV1},
+    zwlr_layer_surface_v1::{self, Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1},
+};
+
+use wayland_client::{
+    delegate_noop,
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{
+        wl_buffer, wl_compositor, wl_keyboard, wl_pointer, wl_region, wl_registry, wl_seat, wl_shm,
+        wl_shm_pool, wl_surface,
+    },
+    Connection, Dispatch, EventQueue, QueueHandle, WEnum,
+};
+
+use crate::{
+    event::{self, Event, PointerEvent},
+    protocol::Position,
+    wayland::{self, Probe},
+};
+
+use super::{CaptureEvent, InputCapture, RELEASE_MODIFIERS};
+
+struct Globals {
+    compositor: wl_compositor::WlCompositor,
+    pointer_constraints: ZwpPointerConstraintsV1,
+    relative_pointer_manager: ZwpRelativePointerManagerV1,
+    /// optional, compositor shortcuts are not inhibited without it
+    shortcut_inhibit_manager: Option<ZwpKeyboardShortcutsInhibitManagerV1>,
+    seat: wl_seat::WlSeat,
+    shm: wl_shm::WlShm,
+    layer_shell: ZwlrLayerShellV1,
+}
+
+struct State {
+    windows: Vec<Window>,
+    /// window the pointer is grabbed by
+    focused: Option<Position>,
+    pointer_lock: Option<ZwpLockedPointerV1>,
+    rel_pointer: Option<ZwpRelativePointerV1>,
+    shortcut_inhibitor: Option<ZwpKeyboardShortcutsInhibitorV1>,
+    pending: VecDeque<CaptureEvent>,
+    g: Globals,
+}
+
+struct Window {
+    pos: Position,
+    buffer: Option<wl_buffer::WlBuffer>,
+    surface: wl_surface::WlSurface,
+    layer_surface: ZwlrLayerSurfaceV1,
+}
+
+/// Capture backend for wlroots based compositors.
+///
+/// A 1px wide layer-shell surface is placed at every screen edge with a peer.
+/// Entering it locks the pointer and grabs the keyboard,
+/// input is then read through the relative pointer protocol.
+pub struct LayerShellCapture {
+    /// of the compositor connection
+    fd: RawFd,
+    queue: EventQueue<State>,
+    state: State,
+}
+
+impl Window {
+    fn new(g: &Globals, qh: &QueueHandle<State>, pos: Position) -> Window {
+        let surface = g.compositor.create_surface(qh, ());
+
+        let layer_surface = g.layer_shell.get_layer_surface(
+            &surface,
+            None,
+            Layer::Top,
+            "LAN Mouse Sharing".into(),
+            qh,
+            pos,
+        );
+
+        // stretch along the edge, size is determined by the compositor
+        let (anchor, width, height) = match pos {
+            Position::Left => (Anchor::Left | Anchor::Top | Anchor::Bottom, 1, 0),
+            Position::Right => (Anchor::Right | Anchor::Top | Anchor::Bottom, 1, 0),
+            Position::Top => (Anchor::Top | Anchor::Left | Anchor::Right, 0, 1),
+            Position::Bottom => (Anchor::Bottom | Anchor::Left | Anchor::Right, 0, 1),
+        };
+        layer_surface.set_anchor(anchor);
+        layer_surface.set_size(width, height);
+        layer_surface.set_exclusive_zone(0);
+        layer_surface.set_margin(0, 0, 0, 0);
+        surface.set_input_region(None);
+        surface.commit();
+        Window {
+            pos,
+            buffer: None,
+            surface,
+            layer_surface,
+        }
+    }
+}
+
+fn create_buffer(
+    g: &Globals,
+    qh: &QueueHandle<State>,
+    (width, height): (u32, u32),
+) -> wl_buffer::WlBuffer {
+    let mut file = tempfile::tempfile().unwrap();
+    draw(&mut file, (width, height));
+    let pool = g
+        .shm
+        .create_pool(file.as_raw_fd(), (width * height * 4) as i32, qh, ());
+    let buffer = pool.create_buffer(
+        0,
+        width as i32,
+        height as i32,
+        (width * 4) as i32,
+        wl_shm::Format::Argb8888,
+        qh,
+        (),
+    );
+    pool.destroy();
+    buffer
+}
+
+fn draw(f: &mut File, (width, height): (u32, u32)) {
+    let mut buf = BufWriter::new(f);
+    for _ in 0..height {
+        for _ in 0..width {
+            buf.write_all(&0x44FBF1C7u32.to_ne_bytes()).unwrap();
+        }
+    }
+}
+
+impl LayerShellCapture {
+    pub fn new(positions: &[Position]) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::connect_to_env()?;
+        let (g, queue) = registry_queue_init::<State>(&conn)?;
+        let qh = queue.handle();
+
+        let mut probe = Probe::default();
+        let compositor = probe.bind(&g, &qh, 4..=5);
+        let shm = probe.bind(&g, &qh, 1..=1);
+        let layer_shell = probe.bind(&g, &qh, 3..=4);
+        let seat = probe.bind(&g, &qh, 7..=8);
+        let pointer_constraints = probe.bind(&g, &qh, 1..=1);
+        let relative_pointer_manager = probe.bind(&g, &qh, 1..=1);
+        let shortcut_inhibit_manager = probe.bind(&g, &qh, 1..=1);
+        if !probe.missing().is_empty() {
+            warn!(missing = ?probe.missing(), "compositor protocols missing");
+        }
+
+        let g = match (
+            compositor,
+            shm,
+            layer_shell,
+            seat,
+            pointer_constraints,
+            relative_pointer_manager,
+        ) {
+            (
+                Some(compositor),
+                Some(shm),
+                Some(layer_shell),
+                Some(seat),
+                Some(pointer_constraints),
+                Some(relative_pointer_manager),
+            ) => Globals {
+                compositor,
+                shm,
+                layer_shell,
+                seat,
+                pointer_constraints,
+                relative_pointer_manager,
+                shortcut_inhibit_manager,
+            },
+            _ => {
+                return Err(
+                    format!("required protocols missing: {}", probe.missing().join(", ")).into(),
+                )
+            }
+        };
+        if g.shortcut_inhibit_manager.is_none() {
+            warn!("compositor shortcuts stay active while grabbed");
+        }
+
+        let windows = positions
+            .iter()
+            .map(|pos| Window::new(&g, &qh, *pos))
+            .collect();
+
+        let state = State {
+            g,
+            windows,
+            focused: None,
+            pointer_lock: None,
+            rel_pointer: None,
+            shortcut_inhibitor: None,
+            pending: VecDeque::new(),
+        };
+        let fd = conn.backend().poll_fd().as_raw_fd();
+        Ok(LayerShellCapture { fd, queue, state })
+    }
+}
+
+impl InputCapture for LayerShellCapture {
+    fn name(&self) -> &'static str {
+        "layer-shell"
+    }
+
+    fn keymap_format(&self) -> u32 {
+        event::KEYMAP_XKB_V1
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.fd]
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        wayland::dispatch(&mut self.queue, &mut self.state)
+    }
+
+    fn next_event(&mut self) -> Option<CaptureEvent> {
+        self.state.pending.pop_front()
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        self.state.ungrab();
+        self.queue.flush().map_err(io::Error::other)
+    }
+}
+
+impl State {
+    fn window(&self, pos: Position) -> Option<&Window> {
+        self.windows.iter().find(|w| w.pos == pos)
+    }
+
+    fn grab(
+        &mut self,
+        surface: &wl_surface::WlSurface,
+        pointer: &wl_pointer::WlPointer,
+        serial: u32,
+        qh: &QueueHandle<State>,
+    ) {
+        let window = match self.windows.iter().find(|w| &w.surface == surface) {
+            Some(window) => window,
+            None => return,
+        };
+        info!(position = %window.pos, "grabbing pointer and keyboard");
+        pointer.set_cursor(serial, None, 0, 0);
+        window
+            .layer_surface
+            .set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+        surface.commit();
+        if self.pointer_lock.is_none() {
+            self.pointer_lock = Some(self.g.pointer_constraints.lock_pointer(
+                surface,
+                pointer,
+                None,
+                Lifetime::Oneshot,
+                qh,
+                (),
+            ));
+        }
+        if self.rel_pointer.is_none() {
+            self.rel_pointer = Some(self.g.relative_pointer_manager.get_relative_pointer(
+                pointer,
+                qh,
+                (),
+            ));
+        }
+        if let (None, Some(manager)) = (&self.shortcut_inhibitor, &self.g.shortcut_inhibit_manager)
+        {
+            self.shortcut_inhibitor =
+                Some(manager.inhibit_shortcuts(surface, &self.g.seat, qh, ()));
+        }
+        self.focused = Some(window.pos);
+        self.pending.push_back(CaptureEvent::Begin(window.pos));
+    }
+
+    fn ungrab(&mut self) {
+        let pos = match self.focused.take() {
+            Some(pos) => pos,
+            None => return,
+        };
+        info!("releasing pointer and keyboard");
+        if let Some(window) = self.window(pos) {
+            window
+                .layer_surface
+                .set_keyboard_interactivity(KeyboardInteractivity::None);
+            window.surface.commit();
+        }
+        if let Some(pointer_lock) = &self.pointer_lock {
+            pointer_lock.destroy();
+            self.pointer_lock = None;
+        }
+        if let Some(rel_pointer) = &self.rel_pointer {
+            rel_pointer.destroy();
+            self.rel_pointer = None;
+        }
+        if let Some(shortcut_inhibitor) = &self.shortcut_inhibitor {
+            shortcut_inhibitor.destroy();
+            self.shortcut_inhibitor = None;
+        }
+        self.pending.push_back(CaptureEvent::End);
+    }
+
+    fn input(&mut self, e: Event) {
+        if self.focused.is_some() {
+            self.pending.push_back(CaptureEvent::Input(e));
+        }
+    }
+}
+
+impl Dispatch<wl_seat::WlSeat, ()> for State {
+    fn event(
+        _: &mut Self,
+        seat: &wl_seat::WlSeat,
+        event: <wl_seat::WlSeat as wayland_client::Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+        {
+            if capabilities.contains(wl_seat::Capability::Pointer) {
+                seat.get_pointer(qh, ());
+            }
+            if capabilities.contains(wl_seat::Capability::Keyboard) {
+                seat.get_keyboard(qh, ());
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_pointer::WlPointer, ()> for State {
+    fn event(
+        state: &mut Self,
+        pointer: &wl_pointer::WlPointer,
+        event: <wl_pointer::WlPointer as wayland_client::Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_pointer::Event::Enter {
+                serial,
+                surface,
+                surface_x: _,
+                surface_y: _,
+            } => {
+                state.grab(&surface, pointer, serial, qh);
+            }
+            wl_pointer::Event::Leave { .. } => {
+                state.ungrab();
+            }
+            event => {
+                if let Some(e) = wayland::pointer_event(event) {
+                    state.input(Event::Pointer(e));
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_keyboard::WlKeyboard, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &wl_keyboard::WlKeyboard,
+        event: wl_keyboard::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_keyboard::Event::Modifiers { mods_depressed, .. } => {
+                if let Some(e) = wayland::keyboard_event(event) {
+                    state.input(Event::Keyboard(e));
+                }
+                if mods_depressed == RELEASE_MODIFIERS {
+                    info!("release shortcut pressed");
+                    state.ungrab();
+                }
+            }
+            wl_keyboard::Event::Keymap { format, fd, size } => {
+                info!(?format, size, "keymap changed");
+                let mmap = unsafe { Mmap::map(&File::from_raw_fd(fd.as_raw_fd())).unwrap() };
+                state.pending.push_back(CaptureEvent::KeyMap(mmap));
+            }
+            event => {
+                if let Some(e) = wayland::keyboard_event(event) {
+                    state.input(Event::Keyboard(e));
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwpRelativePointerV1, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &ZwpRelativePointerV1,
+        event: <ZwpRelativePointerV1 as wayland_client::Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwp_relative_pointer_v1::Event::RelativeMotion {
+            utime_hi,
+            utime_lo,
+            dx: _,
+            dy: _,
+            dx_unaccel,
+            dy_unaccel,
+        } = event
+        {
+            let time = (((utime_hi as u64) << 32 | utime_lo as u64) / 1000) as u32;
+            state.input(Event::Pointer(PointerEvent::Motion {
+                time,
+                dx: dx_unaccel,
+                dy: dy_unaccel,
+            }));
+        }
+    }
+}
+
+impl Dispatch<ZwlrLayerSurfaceV1, Position> for State {
+    fn event(
+        state: &mut Self,
+        layer_surface: &ZwlrLayerSurfaceV1,
+        event: <ZwlrLayerSurfaceV1 as wayland_client::Proxy>::Event,
+        pos: &Position,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let zwlr_layer_surface_v1::Event::Configure {
+            serial,
+            width,
+            height,
+        } = event
+        {
+            debug!(position = %pos, width, height, "layer surface configured");
+            layer_surface.ack_configure(serial);
+            let buffer = create_buffer(&state.g, qh, (width, height));
+            let window = match state.windows.iter_mut().find(|w| w.pos == *pos) {
+                Some(window) => window,
+                None => return,
+            };
+            window.surface.attach(Some(&buffer), 0, 0);
+            window.surface.commit();
+            if let Some(old) = window.buffer.replace(buffer) {
+                old.destroy();
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+        _: &mut State,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<State>,
+    ) {
+    }
+}
+
+// don't emit any events
+delegate_noop!(State: wl_region::WlRegion);
+delegate_noop!(State: wl_shm_pool::WlShmPool);
+delegate_noop!(State: wl_compositor::WlCompositor);
+delegate_noop!(State: ZwlrLayerShellV1);
+delegate_noop!(State: ZwpRelativePointerManagerV1);
+delegate_noop!(State: ZwpKeyboardShortcutsInhibitManagerV1);
+delegate_noop!(State: ZwpPointerConstraintsV1);
+
+// ignore events
+delegate_noop!(State: ignore wl_shm::WlShm);
+delegate_noop!(State: ignore wl_buffer::WlBuffer);
+delegate_noop!(State: ignore wl_surface::WlSurface);
+delegate_noop!(State: ignore ZwpKeyboardShortcutsInhibitorV1);
+delegate_noop!(State: ignore ZwpLockedPointerV1);
@@ -0,0 +1,414 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+};
+
+use tracing::{debug, info, warn};
+use x11rb::{
+    connection::{Connection, RequestConnection},
+    protocol::{
+        xinput::{self, ConnectionExt as _, Fp3232, XIEventMask},
+        xproto::{
+            ButtonPressEvent, ConfigureWindowAux, ConnectionExt as _, CreateWindowAux, EventMask,
+            GrabMode, GrabStatus, KeyButMask, KeyPressEvent, StackMode, Window, WindowClass,
+        },
+        Event as XEvent,
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{
+    event::{self, Event, KeyboardEvent, PointerEvent},
+    protocol::Position,
+};
+
+use super::{CaptureEvent, InputCapture, RELEASE_MODIFIERS};
+
+/// XIAllMasterDevices
+const ALL_MASTER_DEVICES: u16 = 1;
+
+/// X keycodes are evdev keycodes shifted by 8
+const KEYCODE_OFFSET: u32 = 8;
+
+/// scroll distance reported for one wheel click, as libinput does
+const SCROLL_STEP: f64 = 15.0;
+
+/// Capture backend for X11 sessions.
+///
+/// A one pixel wide input-only window is placed at every configured
+/// screen edge. Entering one grabs pointer and keyboard; while grabbed
+/// the pointer is parked in the screen center and relative motion is
+/// read from XInput2 raw events.
+pub struct X11Capture {
+    conn: RustConnection,
+    root: Window,
+    center: (i16, i16),
+    windows: Vec<(Window, Position)>,
+    cursor: u32,
+    /// modifier mask for every keycode
+    modifiers: [u16; 256],
+    focused: Option<Position>,
+    pending: VecDeque<CaptureEvent>,
+}
+
+fn fp3232_to_f64(v: &Fp3232) -> f64 {
+    v.integral as f64 + v.frac as f64 / (1u64 << 32) as f64
+}
+
+fn evdev_button(button: u8) -> Option<u32> {
+    match button {
+        1 => Some(event::BTN_LEFT),
+        2 => Some(event::BTN_MIDDLE),
+        3 => Some(event::BTN_RIGHT),
+        8 => Some(event::BTN_SIDE),
+        9 => Some(event::BTN_EXTRA),
+        _ => None,
+    }
+}
+
+/// scroll buttons 4-7 as (axis, direction)
+fn scroll_button(button: u8) -> Option<(u32, f64)> {
+    match button {
+        4 => Some((event::AXIS_VERTICAL, -1.)),
+        5 => Some((event::AXIS_VERTICAL, 1.)),
+        6 => Some((event::AXIS_HORIZONTAL, -1.)),
+        7 => Some((event::AXIS_HORIZONTAL, 1.)),
+        _ => None,
+    }
+}
+
+impl X11Capture {
+    pub fn new(positions: &[Position]) -> Result<Self, Box<dyn Error>> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        if conn
+            .extension_information(xinput::X11_EXTENSION_NAME)?
+            .is_none()
+        {
+            return Err("XInput extension not available".into());
+        }
+        // raw events are delivered during grabs since XI 2.1
+        let version = conn.xinput_xi_query_version(2, 2)?.reply()?;
+        if (version.major_version, version.minor_version) < (2, 1) {
+            return Err(format!(
+                "XInput {}.{} too old, 2.1 required",
+                version.major_version, version.minor_version
+            )
+            .into());
+        }
+
+        let screen = &conn.setup().roots[screen_num];
+        let root = screen.root;
+        let (width, height) = (screen.width_in_pixels, screen.height_in_pixels);
+
+        let windows = positions
+            .iter()
+            .map(|pos| {
+                let (x, y, w, h) = match pos {
+                    Position::Left => (0, 0, 1, height),
+                    Position::Right => (width as i16 - 1, 0, 1, height),
+                    Position::Top => (0, 0, width, 1),
+                    Position::Bottom => (0, height as i16 - 1, width, 1),
+                };
+                let window = conn.generate_id()?;
+                conn.create_window(
+                    0,
+                    window,
+                    root,
+                    x,
+                    y,
+                    w,
+                    h,
+                    0,
+                    WindowClass::INPUT_ONLY,
+                    0,
+                    &CreateWindowAux::new()
+                        .override_redirect(1)
+                        .event_mask(EventMask::ENTER_WINDOW),
+                )?;
+                conn.map_window(window)?;
+                conn.configure_window(
+                    window,
+                    &ConfigureWindowAux::new().stack_mode(StackMode::ABOVE),
+                )?;
+                Ok((window, *pos))
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+        // invisible cursor shown while grabbed
+        let pixmap = conn.generate_id()?;
+        conn.create_pixmap(1, pixmap, root, 1, 1)?;
+        let cursor = conn.generate_id()?;
+        conn.create_cursor(cursor, pixmap, pixmap, 0, 0, 0, 0, 0, 0, 0, 0)?;
+        conn.free_pixmap(pixmap)?;
+
+        conn.xinput_xi_select_events(
+            root,
+            &[xinput::EventMask {
+                deviceid: ALL_MASTER_DEVICES,
+                mask: vec![XIEventMask::RAW_MOTION],
+            }],
+        )?;
+
+        let mut modifiers = [0u16; 256];
+        let mapping = conn.get_modifier_mapping()?.reply()?;
+        let per_modifier = mapping.keycodes.len() / 8;
+        for (i, keycode) in mapping.keycodes.iter().enumerate() {
+            if *keycode != 0 {
+                modifiers[*keycode as usize] |= 1 << (i / per_modifier);
+            }
+        }
+        conn.flush()?;
+
+        Ok(X11Capture {
+            conn,
+            root,
+            center: ((width / 2) as i16, (height / 2) as i16),
+            windows,
+            cursor,
+            modifiers,
+            focused: None,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn grab_window(&mut self, window: Window) -> Result<(), Box<dyn Error>> {
+        let pos = match self.windows.iter().find(|(w, _)| *w == window) {
+            Some((_, pos)) => *pos,
+            None => return Ok(()),
+        };
+        if self.focused.is_some() {
+            return Ok(());
+        }
+        info!(position = %pos, "grabbing pointer and keyboard");
+        let status = self
+            .conn
+            .grab_pointer(
+                false,
+                window,
+                EventMask::BUTTON_PRESS | EventMask::BUTTON_RELEASE,
+                GrabMode::ASYNC,
+                GrabMode::ASYNC,
+                self.root,
+                self.cursor,
+                x11rb::CURRENT_TIME,
+            )?
+            .reply()?
+            .status;
+        if status != GrabStatus::SUCCESS {
+            warn!(?status, "could not grab pointer");
+            return Ok(());
+        }
+        let status = self
+            .conn
+            .grab_keyboard(
+                false,
+                window,
+                x11rb::CURRENT_TIME,
+                GrabMode::ASYNC,
+                GrabMode::ASYNC,
+            )?
+            .reply()?
+            .status;
+        if status != GrabStatus::SUCCESS {
+            warn!(?status, "could not grab keyboard");
+            self.conn.ungrab_pointer(x11rb::CURRENT_TIME)?;
+            return Ok(());
+        }
+        // park the pointer away from the edges, so releasing
+        // the grab does not immediately trigger the next one
+        let (x, y) = self.center;
+        self.conn
+            .warp_pointer(x11rb::NONE, self.root, 0, 0, 0, 0, x, y)?;
+        self.conn.flush()?;
+        self.focused = Some(pos);
+        self.pending.push_back(CaptureEvent::Begin(pos));
+        Ok(())
+    }
+
+    fn ungrab(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.focused.take().is_none() {
+            return Ok(());
+        }
+        info!("releasing pointer and keyboard");
+        self.conn.ungrab_pointer(x11rb::CURRENT_TIME)?;
+        self.conn.ungrab_keyboard(x11rb::CURRENT_TIME)?;
+        self.conn.flush()?;
+        self.pending.push_back(CaptureEvent::End);
+        Ok(())
+    }
+
+    fn input(&mut self, e: Event) {
+        if self.focused.is_some() {
+            self.pending.push_back(CaptureEvent::Input(e));
+        }
+    }
+
+    fn key(&mut self, e: KeyPressEvent, state: u32) -> Result<(), Box<dyn Error>> {
+        self.input(Event::Keyboard(KeyboardEvent::Key {
+            time: e.time,
+            key: e.detail as u32 - KEYCODE_OFFSET,
+            state,
+        }));
+        // the event state does not include the key itself
+        let modifier = self.modifiers[e.detail as usize];
+        if modifier == 0 {
+            return Ok(());
+        }
+        let mods = match state {
+            event::KEY_PRESSED => u16::from(e.state) | modifier,
+            _ => u16::from(e.state) & !modifier,
+        };
+        self.input(Event::Keyboard(KeyboardEvent::Modifiers {
+            mods_depressed: depressed(mods),
+            mods_latched: 0,
+            mods_locked: u32::from(mods & locked()),
+            group: (mods >> 13) as u32 & 0x3,
+        }));
+        // regardless of CapsLock and NumLock
+        if depressed(mods) == RELEASE_MODIFIERS {
+            info!("release shortcut pressed");
+            self.ungrab()?;
+        }
+        Ok(())
+    }
+
+    fn button(&mut self, e: ButtonPressEvent, state: u32) {
+        if let Some((axis, direction)) = scroll_button(e.detail) {
+            // X reports wheel clicks as press / release pairs
+            if state == event::BUTTON_PRESSED {
+                self.input(Event::Pointer(PointerEvent::Axis {
+                    time: e.time,
+                    axis,
+                    value: direction * SCROLL_STEP,
+                }));
+                self.input(Event::Pointer(PointerEvent::Frame));
+            }
+            return;
+        }
+        match evdev_button(e.detail) {
+            Some(button) => {
+                self.input(Event::Pointer(PointerEvent::Button {
+                    time: e.time,
+                    button,
+                    state,
+                }));
+                self.input(Event::Pointer(PointerEvent::Frame));
+            }
+            None => debug!(button = e.detail, "ignoring unknown button"),
+        }
+    }
+
+    fn raw_motion(&mut self, e: xinput::RawMotionEvent) {
+        // values are only present for valuators set in the mask
+        let mut values = e.axisvalues.iter();
+        let (mut dx, mut dy) = (0., 0.);
+        for valuator in 0..2 {
+            let set = e
+                .valuator_mask
+                .first()
+                .is_some_and(|mask| mask & (1 << valuator) != 0);
+            if set {
+                let v = values.next().map_or(0., fp3232_to_f64);
+                match valuator {
+                    0 => dx = v,
+                    _ => dy = v,
+                }
+            }
+        }
+        if dx != 0. || dy != 0. {
+            self.input(Event::Pointer(PointerEvent::Motion {
+                time: e.time,
+                dx,
+                dy,
+            }));
+            self.input(Event::Pointer(PointerEvent::Frame));
+        }
+    }
+
+    fn handle(&mut self, e: XEvent) -> Result<(), Box<dyn Error>> {
+        match e {
+            XEvent::EnterNotify(e) => self.grab_window(e.event)?,
+            XEvent::KeyPress(e) => self.key(e, event::KEY_PRESSED)?,
+            XEvent::KeyRelease(e) => self.key(e, event::KEY_RELEASED)?,
+            XEvent::ButtonPress(e) => self.button(e, event::BUTTON_PRESSED),
+            XEvent::ButtonRelease(e) => self.button(e, event::BUTTON_RELEASED),
+            XEvent::XinputRawMotion(e) => self.raw_motion(e),
+            XEvent::Error(e) => warn!("X11 error: {:?}", e),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl InputCapture for X11Capture {
+    fn name(&self) -> &'static str {
+        "x11"
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.conn.stream().as_raw_fd()]
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        // events may have been buffered while waiting for a reply,
+        // so the queue is drained on every call
+        while let Some(e) = self.conn.poll_for_event().map_err(io::Error::other)? {
+            self.handle(e)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<CaptureEvent> {
+        self.pending.pop_front()
+    }
+
+    fn grab(&mut self, pos: Position) -> io::Result<()> {
+        let window = match self.windows.iter().find(|(_, p)| *p == pos) {
+            Some((window, _)) => *window,
+            None => return Err(io::Error::other(format!("no edge window at {}", pos))),
+        };
+        self.grab_window(window)
+            .map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        self.ungrab().map_err(|e| io::Error::other(e.to_string()))
+    }
+}
+
+/// CapsLock and NumLock, reported as locked modifiers
+fn locked() -> u16 {
+    u16::from(KeyButMask::LOCK | KeyButMask::MOD2)
+}
+
+/// modifiers held down in the X modifier state `mods`
+fn depressed(mods: u16) -> u32 {
+    u32::from(mods & 0xff & !locked())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_shortcut_with_locks() {
+        let shortcut = u16::from(
+            KeyButMask::SHIFT | KeyButMask::CONTROL | KeyButMask::MOD1 | KeyButMask::MOD4,
+        );
+        assert_eq!(depressed(shortcut), RELEASE_MODIFIERS);
+        let numlock = shortcut | u16::from(KeyButMask::MOD2);
+        assert_eq!(depressed(numlock), RELEASE_MODIFIERS);
+        let capslock = numlock | u16::from(KeyButMask::LOCK);
+        assert_eq!(depressed(capslock), RELEASE_MODIFIERS);
+        // buttons held meanwhile do not matter either
+        let button = shortcut | u16::from(KeyButMask::BUTTON1);
+        assert_eq!(depressed(button), RELEASE_MODIFIERS);
+        assert_ne!(
+            depressed(shortcut & !u16::from(KeyButMask::MOD4)),
+            RELEASE_MODIFIERS
+        );
+    }
+}
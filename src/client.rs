@@ -0,0 +1,279 @@
+//! Emulation side: input received from the server,
+//! configured as the left peer, is emulated locally.
+
+use std::{
+    io,
+    os::unix::prelude::RawFd,
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "xkb")]
+use crate::keysym;
+use crate::{
+    config::KeyboardMode,
+    emulation::{Emulator, InputEmulation},
+    event::{self, Event},
+    poll::{self, Interest},
+    protocol::{self, Connection, DataRequest, KeymapState, Notification, Position, Reply},
+    recording::Recorder,
+};
+
+/// time between attempts of the handshake with the server
+const HANDSHAKE_RETRY: Duration = Duration::from_secs(1);
+
+pub struct Client {
+    connection: Connection,
+    emulation: Box<dyn InputEmulation>,
+    emulator: Emulator,
+    notifications: Receiver<Notification>,
+    /// whether the keymap of the server is installed
+    wants_keymap: bool,
+    remote_keymap: RemoteKeymap,
+    /// next attempt of the handshake, `None` once completed
+    handshake: Option<Instant>,
+    /// received events are recorded here
+    recorder: Option<Recorder>,
+}
+
+impl Client {
+    /// Introduce ourselves to the server in the background,
+    /// its keymap is fetched once the handshake completed.
+    pub fn new(
+        connection: Connection,
+        mut emulation: Box<dyn InputEmulation>,
+        recorder: Option<Recorder>,
+    ) -> Client {
+        let capabilities = emulation.capabilities();
+        info!(?capabilities, "advertising capabilities");
+        connection.offer_data(
+            DataRequest::Capabilities,
+            u32::from(capabilities).to_ne_bytes(),
+        );
+        // subscribe before the handshake, so no keymap change is missed
+        let notifications = connection.peers().subscribe();
+        let keyboard = connection
+            .peers()
+            .get(Position::Left)
+            .map(|p| p.keyboard)
+            .unwrap_or_default();
+        let mut info =
+            protocol::local_info(emulation.name(), capabilities, emulation.keymap_format());
+        info.keyboard = keyboard;
+        connection.set_local_info(&info);
+        // retried on the tick until the server answers
+        connection.start_handshake(Position::Left);
+        // no keymap needed without a keyboard, in keysym mode
+        // or if the backend takes none, the server is asked once known
+        let wants_keymap = capabilities.keyboard
+            && keyboard == KeyboardMode::Keycode
+            && emulation.keymap_format() != event::KEYMAP_NONE;
+        // pointer events are emulated right away, the keymap follows
+        if wants_keymap {
+            let state = fallback_keymap(emulation.as_mut());
+            connection.peers().set_keymap_state(Position::Left, state);
+        }
+        Client {
+            connection,
+            emulation,
+            emulator: Emulator::default(),
+            notifications,
+            wants_keymap,
+            remote_keymap: RemoteKeymap::default(),
+            handshake: Some(Instant::now() + HANDSHAKE_RETRY),
+            recorder,
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// file descriptors to wait for before [`Client::dispatch`],
+    /// changes on every iteration
+    pub fn fds(&self) -> Vec<(RawFd, Interest)> {
+        let mut fds = poll::readable(self.emulation.fds());
+        fds.extend(self.connection.fds());
+        fds
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        let retry = self
+            .handshake
+            .map(|at| at.saturating_duration_since(Instant::now()));
+        self.connection.timeout().into_iter().chain(retry).min()
+    }
+
+    /// Advance the emulation backend and the connection without blocking
+    /// and emulate the received events.
+    pub fn dispatch(&mut self) -> io::Result<()> {
+        self.emulation.dispatch()?;
+        self.connection.dispatch();
+        self.poll_handshake();
+        let connection = &self.connection;
+        for n in self.notifications.try_iter() {
+            match n {
+                Notification::KeyMapChanged(Position::Left, hash) if self.wants_keymap => {
+                    self.remote_keymap.announce(connection, hash)
+                }
+                _ => {}
+            }
+        }
+        // install a new keymap before the events following it
+        self.remote_keymap
+            .poll(connection, self.emulation.as_mut(), self.recorder.as_mut());
+        let pending = connection
+            .peers()
+            .get(Position::Left)
+            .is_some_and(|p| p.keymap == KeymapState::Pending);
+        while let Some(event) = connection.receive_event() {
+            if pending && matches!(event, Event::Keyboard(_)) {
+                debug!(kind = event.kind(), "no keymap yet, dropping event");
+                continue;
+            }
+            if let Some(recorder) = self.recorder.as_mut() {
+                if let Err(e) = recorder.record(&event) {
+                    warn!("could not record event: {}", e);
+                }
+            }
+            self.emulator.consume(self.emulation.as_mut(), event);
+        }
+        self.flush();
+        Ok(())
+    }
+
+    /// Start the handshake again if it failed, unless one is in progress.
+    /// Completed once the capabilities of the server are known,
+    /// through the handshake or from peers older than it.
+    fn poll_handshake(&mut self) {
+        let at = match self.handshake {
+            Some(at) => at,
+            None => return,
+        };
+        let peers = self.connection.peers();
+        if peers
+            .get(Position::Left)
+            .is_some_and(|p| p.capabilities.is_some())
+        {
+            self.handshake = None;
+            self.handshake_completed();
+        } else if at <= Instant::now() {
+            self.connection.start_handshake(Position::Left);
+            self.handshake = Some(Instant::now() + HANDSHAKE_RETRY);
+        }
+    }
+
+    /// Fetch the keymap of the server, unless it has none to offer.
+    /// Peers older than the handshake are assumed to offer one.
+    fn handshake_completed(&mut self) {
+        if !self.wants_keymap {
+            return;
+        }
+        let server = self.connection.peers().info(Position::Left);
+        let server_keymap = server.map_or(event::KEYMAP_XKB_V1, |s| s.keymap_format);
+        if server_keymap == event::KEYMAP_NONE {
+            self.wants_keymap = false;
+            self.connection
+                .peers()
+                .set_keymap_state(Position::Left, KeymapState::Unused);
+            return;
+        }
+        self.remote_keymap.fetch(&self.connection);
+    }
+
+    /// submit everything emulated so far
+    pub fn flush(&mut self) {
+        if let Err(e) = self.emulation.flush() {
+            warn!("could not flush emulation: {}", e);
+        }
+    }
+}
+
+/// Keymap of the server, fetched in the background.
+#[derive(Default)]
+struct RemoteKeymap {
+    /// hash of the installed keymap
+    installed: Option<u64>,
+    /// hash of the last announced keymap
+    announced: Option<u64>,
+    fetch: Option<Reply>,
+}
+
+impl RemoteKeymap {
+    fn fetch(&mut self, connection: &Connection) {
+        if self.fetch.is_none() {
+            self.fetch = connection.request(Position::Left, DataRequest::KeyMap, true);
+        }
+    }
+
+    /// the server announced a keymap with the given hash
+    fn announce(&mut self, connection: &Connection, hash: u64) {
+        self.announced = Some(hash);
+        if self.installed != Some(hash) {
+            self.fetch(connection);
+        }
+    }
+
+    /// install the keymap once fetched
+    fn poll(
+        &mut self,
+        connection: &Connection,
+        emulation: &mut dyn InputEmulation,
+        recorder: Option<&mut Recorder>,
+    ) {
+        let response = match self.fetch.as_ref().and_then(|reply| reply.try_take()) {
+            Some(response) => response,
+            None => return,
+        };
+        self.fetch = None;
+        // retried until the server offers a keymap
+        let data = match response {
+            Ok(Some(data)) => data,
+            _ => return,
+        };
+        let hash = protocol::keymap_hash(&data);
+        if self.installed != Some(hash) {
+            info!(
+                hash = format!("{:016x}", hash),
+                "installing keymap of the server"
+            );
+            emulation.set_keymap(&data);
+            if let Some(recorder) = recorder {
+                if let Err(e) = recorder.record_keymap(&data) {
+                    warn!("could not record keymap: {}", e);
+                }
+            }
+            self.installed = Some(hash);
+            connection
+                .peers()
+                .set_keymap_state(Position::Left, KeymapState::Received);
+        }
+        // changed again while fetching
+        if self.announced.is_some_and(|a| a != hash) {
+            self.fetch(connection);
+        }
+    }
+}
+
+/// Install the local default keymap until the one of the server arrives.
+#[cfg(feature = "xkb")]
+fn fallback_keymap(emulation: &mut dyn InputEmulation) -> KeymapState {
+    match keysym::default_keymap() {
+        Ok(keymap) => {
+            info!("using the local default keymap until the server's arrives");
+            emulation.set_keymap(&keymap);
+            KeymapState::Fallback
+        }
+        Err(e) => {
+            warn!("no fallback keymap: {}", e);
+            KeymapState::Pending
+        }
+    }
+}
+
+#[cfg(not(feature = "xkb"))]
+fn fallback_keymap(_: &mut dyn InputEmulation) -> KeymapState {
+    KeymapState::Pending
+}
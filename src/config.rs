@@ -3,10 +3,19 @@ use std::net::IpAddr;
 use std::{error::Error, fs};
 use toml;
 
+pub use lan_mouse_proto::handshake::KeyboardMode;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub client: Clients,
     pub port: Option<u16>,
+    /// serve prometheus metrics on 127.0.0.1:<metrics_port>
+    pub metrics_port: Option<u16>,
+    /// capture physical devices through evdev instead of the compositor
+    pub evdev: Option<Evdev>,
+    /// longest time in milliseconds pointer events are held back
+    /// to send a whole frame at once, 0 sends every event on its own
+    pub batch_delay: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -22,6 +31,17 @@ pub struct Client {
     pub host_name: Option<String>,
     pub ip: Option<IpAddr>,
     pub port: Option<u16>,
+    /// how key events are exchanged with this peer
+    pub keyboard: Option<KeyboardMode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Evdev {
+    /// device names, `vendor:product` ids (hex) or `/dev/input/event*` paths
+    pub devices: Vec<String>,
+    /// size of the local screen in pixels, used to detect edge crossings
+    pub width: u32,
+    pub height: u32,
 }
 
 impl Config {
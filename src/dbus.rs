@@ -0,0 +1,234 @@
+use std::{collections::HashMap, error::Error, sync::mpsc, thread, time::Duration};
+
+use tracing::{info, warn};
+
+use zbus::{
+    blocking::{self, ConnectionBuilder, InterfaceRef},
+    dbus_interface, fdo, SignalContext,
+};
+
+use crate::{
+    poll,
+    protocol::{Notification, Peers, Position},
+    stats::Stats,
+};
+
+pub const PATH: &str = "/org/lanmouse/Daemon";
+
+/// how long a method waits for the event loop to carry out a [`Command`]
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Requests to the event loop owning the capture backend.
+#[derive(Debug)]
+pub enum Command {
+    /// grab input for the peer at `Position` and send it there,
+    /// the outcome is sent back
+    SwitchTo(Position, mpsc::Sender<Result<(), String>>),
+    /// end the grab
+    Release,
+}
+
+/// Side exporting the interface.
+pub enum Role {
+    /// captures input, commands are carried out by its event loop
+    Server(poll::Sender<Command>),
+    Client,
+}
+
+impl Role {
+    /// well-known name on the bus, so client and server can run side by side
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Server(_) => "org.lanmouse.Server",
+            Role::Client => "org.lanmouse.Client",
+        }
+    }
+}
+
+/// `org.lanmouse.Daemon` object exported on the session bus.
+///
+/// Peers are addressed by their position ("left", "right", "top", "bottom").
+struct Daemon {
+    peers: Peers,
+    stats: Stats,
+    commands: Option<poll::Sender<Command>>,
+}
+
+impl Daemon {
+    fn command(&self, command: Command) -> fdo::Result<()> {
+        match &self.commands {
+            Some(commands) => commands
+                .send(command)
+                .map_err(|_| fdo::Error::Failed("event loop has exited".into())),
+            None => Err(fdo::Error::NotSupported(
+                "input is not captured on this side".into(),
+            )),
+        }
+    }
+}
+
+fn position(peer: &str) -> fdo::Result<Position> {
+    peer.parse()
+        .map_err(|e: <Position as std::str::FromStr>::Err| fdo::Error::InvalidArgs(e.to_string()))
+}
+
+#[dbus_interface(name = "org.lanmouse.Daemon")]
+impl Daemon {
+    /// grab input and direct all following events to the given peer
+    fn switch_to_peer(&self, peer: &str) -> fdo::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        self.command(Command::SwitchTo(position(peer)?, tx))?;
+        match rx.recv_timeout(REPLY_TIMEOUT) {
+            Ok(result) => result.map_err(fdo::Error::Failed),
+            Err(_) => Err(fdo::Error::Failed("event loop did not answer".into())),
+        }
+    }
+
+    /// end the grab, no peer receives events afterwards
+    fn release(&self) -> fdo::Result<()> {
+        self.command(Command::Release)
+    }
+
+    async fn enable_peer(
+        &self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        peer: &str,
+    ) -> fdo::Result<()> {
+        self.peers
+            .set_enabled(position(peer)?, true)
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        self.peers_changed(&ctxt).await?;
+        Ok(())
+    }
+
+    async fn disable_peer(
+        &self,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+        peer: &str,
+    ) -> fdo::Result<()> {
+        self.peers
+            .set_enabled(position(peer)?, false)
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        self.peers_changed(&ctxt).await?;
+        Ok(())
+    }
+
+    /// (position, address, enabled, connected) of every configured peer
+    #[dbus_interface(property)]
+    fn peers(&self) -> Vec<(String, String, bool, bool)> {
+        self.peers
+            .list()
+            .into_iter()
+            .map(|(pos, p)| (pos.to_string(), p.addr.to_string(), p.enabled, p.connected))
+            .collect()
+    }
+
+    /// position of the peer receiving events, empty if released
+    #[dbus_interface(property)]
+    fn active_peer(&self) -> String {
+        self.peers
+            .active()
+            .map(|p| p.to_string())
+            .unwrap_or_default()
+    }
+
+    /// where the keymap for the key events of a peer comes from:
+    /// "unused", "pending", "fallback" or "received"
+    fn keymap_state(&self, peer: &str) -> fdo::Result<String> {
+        let pos = position(peer)?;
+        self.peers
+            .get(pos)
+            .map(|p| p.keymap.to_string())
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("no peer configured at {}", pos)))
+    }
+
+    /// traffic counters per peer, e.g. `{"right": {"events_sent.motion": 42, ...}}`
+    fn statistics(&self) -> HashMap<String, HashMap<String, u64>> {
+        self.stats
+            .peers()
+            .into_iter()
+            .map(|(pos, s)| (pos.to_string(), s.to_map()))
+            .collect()
+    }
+
+    #[dbus_interface(signal)]
+    async fn peer_connected(ctxt: &SignalContext<'_>, peer: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn peer_disconnected(ctxt: &SignalContext<'_>, peer: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn focus_moved(ctxt: &SignalContext<'_>, peer: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn keymap_changed(ctxt: &SignalContext<'_>, peer: &str) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    async fn clipboard_synced(ctxt: &SignalContext<'_>, peer: &str) -> zbus::Result<()>;
+}
+
+/// emit the signal of `n` and the changes of the properties it affects
+fn emit(iface: &InterfaceRef<Daemon>, n: Notification) -> zbus::Result<()> {
+    let ctxt = iface.signal_context();
+    let daemon = iface.get();
+    zbus::block_on(async {
+        match n {
+            Notification::PeerConnected(pos) => {
+                Daemon::peer_connected(ctxt, &pos.to_string()).await?;
+                daemon.peers_changed(ctxt).await
+            }
+            Notification::PeerDisconnected(pos) => {
+                Daemon::peer_disconnected(ctxt, &pos.to_string()).await?;
+                daemon.peers_changed(ctxt).await
+            }
+            Notification::FocusMoved(pos) => {
+                let peer = pos.map(|p| p.to_string()).unwrap_or_default();
+                Daemon::focus_moved(ctxt, &peer).await?;
+                daemon.active_peer_changed(ctxt).await
+            }
+            Notification::KeyMapChanged(pos, _) => {
+                Daemon::keymap_changed(ctxt, &pos.to_string()).await
+            }
+            Notification::ClipboardSynced(pos) => {
+                Daemon::clipboard_synced(ctxt, &pos.to_string()).await
+            }
+        }
+    })
+}
+
+/// Export the daemon interface on the session bus under the name of `role`.
+///
+/// The bus is taken from `DBUS_SESSION_BUS_ADDRESS`, so the service
+/// can be run against a private `dbus-daemon` as well.
+/// The service stays registered as long as the returned connection is alive.
+pub fn serve(
+    role: Role,
+    peers: Peers,
+    stats: Stats,
+) -> Result<blocking::Connection, Box<dyn Error>> {
+    let name = role.name();
+    let commands = match role {
+        Role::Server(commands) => Some(commands),
+        Role::Client => None,
+    };
+    let notifications = peers.subscribe();
+    let daemon = Daemon {
+        peers,
+        stats,
+        commands,
+    };
+    let conn = ConnectionBuilder::session()?
+        .name(name)?
+        .serve_at(PATH, daemon)?
+        .build()?;
+    info!(name, path = PATH, "dbus service registered");
+    let iface = conn.object_server().interface::<_, Daemon>(PATH)?;
+    thread::spawn(move || {
+        for n in notifications {
+            if let Err(e) = emit(&iface, n) {
+                warn!("failed to emit dbus signal: {}", e);
+            }
+        }
+    });
+    Ok(conn)
+}
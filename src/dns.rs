@@ -1,5 +1,6 @@
 use std::{error::Error, fmt::Display, net::IpAddr};
 
+use tracing::{debug, instrument, warn};
 use trust_dns_resolver::Resolver;
 
 #[derive(Debug, Clone)]
@@ -26,14 +27,20 @@ impl Display for DnsError {
     }
 }
 
+#[instrument]
 pub fn resolve(host: &Option<String>) -> Result<IpAddr, Box<dyn Error>> {
     let host = match host {
         Some(host) => host,
         None => return Err(InvalidConfigError.into()),
     };
     let response = Resolver::from_system_conf()?.lookup_ip(host)?;
-    match response.iter().next() {
-        Some(ip) => Ok(ip),
-        None => Err(DnsError { host: host.clone() }.into()),
+    let ips: Vec<IpAddr> = response.iter().collect();
+    debug!(?ips, "lookup finished");
+    match ips.first() {
+        Some(ip) => Ok(*ip),
+        None => {
+            warn!("no addresses found");
+            Err(DnsError { host: host.clone() }.into())
+        }
     }
 }
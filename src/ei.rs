@@ -0,0 +1,583 @@
+//! Minimal client side implementation of the ei protocol spoken
+//! between libei clients and an EIS implementation (the compositor).
+//!
+//! Only the wire format, the handshake, the connection level
+//! events (ping, disconnect) and the seats and devices are handled here,
+//! the input interfaces are driven by the emulation and capture backends.
+//!
+//! Every message starts with a header of
+//! `[object id: u64][length incl. header: u32][opcode: u32]`
+//! in native byte order, followed by the arguments padded to 4 bytes.
+//! File descriptors are passed out of band.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    error::Error,
+    fmt, io, mem,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::{net::UnixStream, prelude::AsRawFd},
+    },
+    path::PathBuf,
+    ptr,
+};
+
+use tracing::{debug, info, warn};
+
+pub const CONTEXT_RECEIVER: u32 = 1;
+pub const CONTEXT_SENDER: u32 = 2;
+
+/// object id of the `ei_handshake`
+const HANDSHAKE: u64 = 0;
+
+// ei_handshake requests
+const HANDSHAKE_VERSION: u32 = 0;
+const HANDSHAKE_FINISH: u32 = 1;
+const HANDSHAKE_CONTEXT_TYPE: u32 = 2;
+const HANDSHAKE_NAME: u32 = 3;
+const HANDSHAKE_INTERFACE_VERSION: u32 = 4;
+
+// ei_handshake events
+const HANDSHAKE_EVENT_VERSION: u32 = 0;
+const HANDSHAKE_EVENT_INTERFACE_VERSION: u32 = 1;
+const HANDSHAKE_EVENT_CONNECTION: u32 = 2;
+
+// ei_connection events
+const CONNECTION_DISCONNECTED: u32 = 0;
+const CONNECTION_SEAT: u32 = 1;
+const CONNECTION_INVALID_OBJECT: u32 = 2;
+const CONNECTION_PING: u32 = 3;
+
+// ei_pingpong requests
+const PINGPONG_DONE: u32 = 0;
+
+// ei_seat requests
+const SEAT_BIND: u32 = 1;
+
+// ei_seat events
+const SEAT_DESTROYED: u32 = 0;
+const SEAT_CAPABILITY: u32 = 2;
+const SEAT_DONE: u32 = 3;
+const SEAT_DEVICE: u32 = 4;
+
+// ei_device requests
+pub const DEVICE_START_EMULATING: u32 = 1;
+pub const DEVICE_FRAME: u32 = 3;
+
+// ei_device events
+const DEVICE_DESTROYED: u32 = 0;
+const DEVICE_INTERFACE: u32 = 5;
+const DEVICE_RESUMED: u32 = 7;
+const DEVICE_PAUSED: u32 = 8;
+pub const DEVICE_EVENT_FRAME: u32 = 11;
+
+/// `destroyed` event of every input interface
+const INTERFACE_DESTROYED: u32 = 0;
+
+const HEADER_LEN: usize = 16;
+
+/// Interfaces and versions announced during the handshake
+/// that are needed by every context.
+const CORE_INTERFACES: [(&str, u32); 5] = [
+    ("ei_connection", 1),
+    ("ei_callback", 1),
+    ("ei_pingpong", 1),
+    ("ei_seat", 1),
+    ("ei_device", 1),
+];
+
+/// Input interfaces announced by emulation and capture
+pub const INPUT_INTERFACES: [(&str, u32); 4] = [
+    ("ei_pointer", 1),
+    ("ei_button", 1),
+    ("ei_scroll", 1),
+    ("ei_keyboard", 1),
+];
+
+/// input interface of a device
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Interface {
+    Pointer,
+    Button,
+    Scroll,
+    Keyboard,
+}
+
+impl Interface {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ei_pointer" => Some(Interface::Pointer),
+            "ei_button" => Some(Interface::Button),
+            "ei_scroll" => Some(Interface::Scroll),
+            "ei_keyboard" => Some(Interface::Keyboard),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_data(msg: impl Into<Box<dyn Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Path of the EIS socket given by `LIBEI_SOCKET`, which is
+/// relative to `XDG_RUNTIME_DIR` unless absolute.
+pub fn socket_path() -> Option<PathBuf> {
+    let socket = PathBuf::from(env::var_os("LIBEI_SOCKET")?);
+    if socket.is_absolute() {
+        return Some(socket);
+    }
+    Some(PathBuf::from(env::var_os("XDG_RUNTIME_DIR")?).join(socket))
+}
+
+/// Arguments of a request
+#[derive(Default)]
+pub struct Args(Vec<u8>);
+
+impl Args {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    pub fn i32(mut self, v: i32) -> Self {
+        self.0.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    pub fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    pub fn f32(mut self, v: f32) -> Self {
+        self.0.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    /// length including the terminating nul, padded to 4 bytes
+    pub fn string(mut self, s: &str) -> Self {
+        let len = s.len() + 1;
+        self.0.extend_from_slice(&(len as u32).to_ne_bytes());
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.resize(self.0.len() + 1 + (4 - len % 4) % 4, 0);
+        self
+    }
+}
+
+/// An event received from the EIS implementation
+pub struct Message {
+    pub object: u64,
+    pub opcode: u32,
+    args: Vec<u8>,
+    pos: usize,
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("object", &self.object)
+            .field("opcode", &self.opcode)
+            .field("len", &self.args.len())
+            .finish()
+    }
+}
+
+impl Message {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let bytes = self
+            .args
+            .get(self.pos..self.pos + N)
+            .ok_or_else(|| invalid_data("message truncated"))?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_ne_bytes(self.take()?))
+    }
+
+    pub fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_ne_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_ne_bytes(self.take()?))
+    }
+
+    pub fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_ne_bytes(self.take()?))
+    }
+
+    pub fn string(&mut self) -> io::Result<Option<String>> {
+        let len = self.u32()? as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        let padded = len + (4 - len % 4) % 4;
+        let bytes = self
+            .args
+            .get(self.pos..self.pos + padded)
+            .ok_or_else(|| invalid_data("string truncated"))?;
+        self.pos += padded;
+        let s = String::from_utf8_lossy(&bytes[..len - 1]).into_owned();
+        Ok(Some(s))
+    }
+}
+
+/// Connection to an EIS implementation after a successful handshake
+pub struct Context {
+    stream: UnixStream,
+    buf: Vec<u8>,
+    fds: VecDeque<OwnedFd>,
+    next_id: u64,
+    connection: u64,
+}
+
+impl Context {
+    /// Perform the handshake as a client of the given context type,
+    /// announcing the given interfaces in addition to the core ones.
+    pub fn connect(
+        stream: UnixStream,
+        context_type: u32,
+        name: &str,
+        interfaces: &[(&str, u32)],
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut ctx = Context {
+            stream,
+            buf: vec![],
+            fds: VecDeque::new(),
+            next_id: 1,
+            connection: 0,
+        };
+        let version = loop {
+            let mut msg = ctx.read_message()?;
+            if msg.object == HANDSHAKE && msg.opcode == HANDSHAKE_EVENT_VERSION {
+                break msg.u32()?;
+            }
+        };
+        debug!(version, "EIS handshake");
+        ctx.send(HANDSHAKE, HANDSHAKE_VERSION, Args::new().u32(1))?;
+        ctx.send(
+            HANDSHAKE,
+            HANDSHAKE_CONTEXT_TYPE,
+            Args::new().u32(context_type),
+        )?;
+        ctx.send(HANDSHAKE, HANDSHAKE_NAME, Args::new().string(name))?;
+        for (interface, version) in CORE_INTERFACES.iter().chain(interfaces) {
+            ctx.send(
+                HANDSHAKE,
+                HANDSHAKE_INTERFACE_VERSION,
+                Args::new().string(interface).u32(*version),
+            )?;
+        }
+        ctx.send(HANDSHAKE, HANDSHAKE_FINISH, Args::new())?;
+        loop {
+            let mut msg = ctx.read_message()?;
+            match (msg.object, msg.opcode) {
+                (HANDSHAKE, HANDSHAKE_EVENT_INTERFACE_VERSION) => {
+                    let interface = msg.string()?.unwrap_or_default();
+                    debug!(interface, version = msg.u32()?, "EIS supports interface");
+                }
+                (HANDSHAKE, HANDSHAKE_EVENT_CONNECTION) => {
+                    let _serial = msg.u32()?;
+                    ctx.connection = msg.u64()?;
+                    return Ok(ctx);
+                }
+                _ => warn!(?msg, "unexpected message during handshake"),
+            }
+        }
+    }
+
+    /// the `ei_connection` object
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    /// allocate a client side object id
+    pub fn new_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.stream.set_nonblocking(nonblocking)
+    }
+
+    pub fn send(&mut self, object: u64, opcode: u32, args: Args) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + args.0.len());
+        buf.extend_from_slice(&object.to_ne_bytes());
+        buf.extend_from_slice(&((HEADER_LEN + args.0.len()) as u32).to_ne_bytes());
+        buf.extend_from_slice(&opcode.to_ne_bytes());
+        buf.extend_from_slice(&args.0);
+        io::Write::write_all(&mut self.stream, &buf)
+    }
+
+    /// file descriptor passed along with the current message
+    pub fn take_fd(&mut self) -> Option<OwnedFd> {
+        self.fds.pop_front()
+    }
+
+    /// receive available data together with passed file descriptors
+    fn recv(&mut self) -> io::Result<()> {
+        let mut data = [0u8; 4096];
+        // u64 for cmsghdr alignment
+        let mut control = [0u64; 32];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let n = unsafe { libc::recvmsg(self.stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "EIS closed the connection",
+            ));
+        }
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    for i in 0..len / mem::size_of::<RawFd>() {
+                        let fd = ptr::read_unaligned(data.add(i));
+                        self.fds.push_back(OwnedFd::from_raw_fd(fd));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        self.buf.extend_from_slice(&data[..n as usize]);
+        Ok(())
+    }
+
+    /// split off the next complete message, if any
+    fn parse(&mut self) -> io::Result<Option<Message>> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let object = u64::from_ne_bytes(self.buf[0..8].try_into().unwrap());
+        let len = u32::from_ne_bytes(self.buf[8..12].try_into().unwrap()) as usize;
+        let opcode = u32::from_ne_bytes(self.buf[12..16].try_into().unwrap());
+        if len < HEADER_LEN {
+            return Err(invalid_data(format!("invalid message length {}", len)));
+        }
+        if self.buf.len() < len {
+            return Ok(None);
+        }
+        let args = self.buf[HEADER_LEN..len].to_vec();
+        self.buf.drain(..len);
+        Ok(Some(Message {
+            object,
+            opcode,
+            args,
+            pos: 0,
+        }))
+    }
+
+    fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            if let Some(msg) = self.parse()? {
+                return Ok(msg);
+            }
+            self.recv()?;
+        }
+    }
+
+    /// Next event for the backend, connection level events are handled here.
+    /// Returns `None` if no complete message is available on a
+    /// nonblocking context.
+    pub fn next_message(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            let mut msg = match self.read_message() {
+                Ok(msg) => msg,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if msg.object != self.connection {
+                return Ok(Some(msg));
+            }
+            match msg.opcode {
+                CONNECTION_DISCONNECTED => {
+                    let _serial = msg.u32()?;
+                    let reason = msg.u32()?;
+                    let explanation = msg.string()?.unwrap_or_default();
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        format!("disconnected by EIS ({}): {}", reason, explanation),
+                    ));
+                }
+                CONNECTION_INVALID_OBJECT => {
+                    let _serial = msg.u32()?;
+                    warn!(object = msg.u64()?, "EIS reports invalid object");
+                }
+                CONNECTION_PING => {
+                    let pingpong = msg.u64()?;
+                    self.send(pingpong, PINGPONG_DONE, Args::new().u64(0))?;
+                }
+                _ => return Ok(Some(msg)),
+            }
+        }
+    }
+}
+
+impl AsRawFd for Context {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+/// A device of a seat together with its input interfaces
+#[derive(Default)]
+pub struct Device {
+    pub resumed: bool,
+    /// `start_emulating` was sent since the device was resumed,
+    /// only used by sender contexts
+    pub emulating: bool,
+    pub interfaces: HashMap<Interface, u64>,
+}
+
+enum Object {
+    /// seat with the mask of the capabilities to bind
+    Seat(u64),
+    Device,
+    /// input interface of a device
+    Interface(u64, Interface),
+}
+
+/// Seats and devices announced by the EIS implementation.
+///
+/// Every seat is bound to the input interfaces it offers,
+/// the devices added in response are tracked with their interfaces.
+#[derive(Default)]
+pub struct Devices {
+    objects: HashMap<u64, Object>,
+    devices: HashMap<u64, Device>,
+    serial: u32,
+}
+
+impl Devices {
+    /// last serial sent by the EIS implementation
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    pub fn any_resumed(&self) -> bool {
+        self.devices.values().any(|d| d.resumed)
+    }
+
+    /// a resumed device with `interface` as `(device, interface object, device)`
+    pub fn find(&mut self, interface: Interface) -> Option<(u64, u64, &mut Device)> {
+        self.devices.iter_mut().find_map(|(id, d)| {
+            let object = *d.interfaces.get(&interface)?;
+            d.resumed.then_some((*id, object, d))
+        })
+    }
+
+    pub fn is_device(&self, object: u64) -> bool {
+        matches!(self.objects.get(&object), Some(Object::Device))
+    }
+
+    /// input interface of the given interface object
+    pub fn interface(&self, object: u64) -> Option<Interface> {
+        match self.objects.get(&object) {
+            Some(Object::Interface(_, interface)) => Some(*interface),
+            _ => None,
+        }
+    }
+
+    /// Handle seat and device events. Other events of devices
+    /// and interfaces are returned to the backend.
+    pub fn handle(&mut self, ctx: &mut Context, mut msg: Message) -> io::Result<Option<Message>> {
+        if msg.object == ctx.connection() {
+            if msg.opcode == CONNECTION_SEAT {
+                let seat = msg.u64()?;
+                self.objects.insert(seat, Object::Seat(0));
+            }
+            return Ok(None);
+        }
+        match self.objects.get_mut(&msg.object) {
+            Some(Object::Seat(capabilities)) => match msg.opcode {
+                SEAT_CAPABILITY => {
+                    let mask = msg.u64()?;
+                    let name = msg.string()?.unwrap_or_default();
+                    if Interface::from_name(&name).is_some() {
+                        *capabilities |= mask;
+                    }
+                }
+                SEAT_DONE => {
+                    let capabilities = *capabilities;
+                    debug!(capabilities, "binding seat");
+                    ctx.send(msg.object, SEAT_BIND, Args::new().u64(capabilities))?;
+                }
+                SEAT_DEVICE => {
+                    let device = msg.u64()?;
+                    self.objects.insert(device, Object::Device);
+                    self.devices.insert(device, Device::default());
+                }
+                SEAT_DESTROYED => {
+                    self.serial = msg.u32()?;
+                    self.objects.remove(&msg.object);
+                }
+                _ => {}
+            },
+            Some(Object::Device) => {
+                let device = self.devices.entry(msg.object).or_default();
+                match msg.opcode {
+                    DEVICE_INTERFACE => {
+                        let object = msg.u64()?;
+                        let name = msg.string()?.unwrap_or_default();
+                        if let Some(interface) = Interface::from_name(&name) {
+                            device.interfaces.insert(interface, object);
+                            self.objects
+                                .insert(object, Object::Interface(msg.object, interface));
+                        }
+                    }
+                    DEVICE_RESUMED => {
+                        self.serial = msg.u32()?;
+                        device.resumed = true;
+                        info!(device = msg.object, interfaces = ?device.interfaces.keys(), "EIS device resumed");
+                    }
+                    DEVICE_PAUSED => {
+                        self.serial = msg.u32()?;
+                        device.resumed = false;
+                        device.emulating = false;
+                        info!(device = msg.object, "EIS device paused");
+                    }
+                    DEVICE_DESTROYED => {
+                        self.serial = msg.u32()?;
+                        self.devices.remove(&msg.object);
+                        self.objects.remove(&msg.object);
+                    }
+                    _ => return Ok(Some(msg)),
+                }
+            }
+            Some(Object::Interface(device, interface)) => match msg.opcode {
+                INTERFACE_DESTROYED => {
+                    self.serial = msg.u32()?;
+                    if let Some(device) = self.devices.get_mut(device) {
+                        device.interfaces.remove(interface);
+                    }
+                    self.objects.remove(&msg.object);
+                }
+                _ => return Ok(Some(msg)),
+            },
+            None => debug!(?msg, "message for unknown object"),
+        }
+        Ok(None)
+    }
+}
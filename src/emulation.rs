@@ -0,0 +1,121 @@
+use std::{env, error::Error, io, os::unix::prelude::RawFd};
+
+use tracing::{info, warn};
+
+use crate::event::{self, Capabilities, Event};
+
+mod emulator;
+#[cfg(feature = "libei")]
+pub mod libei;
+pub mod memory;
+#[cfg(feature = "uinput")]
+pub mod uinput;
+pub mod wayland;
+#[cfg(feature = "x11")]
+pub mod x11;
+
+pub use emulator::Emulator;
+
+/// A sink for input events received from a peer.
+///
+/// Backends translate the backend neutral events
+/// into native requests, e.g. virtual input devices.
+pub trait InputEmulation {
+    /// short name of the backend, advertised to peers
+    fn name(&self) -> &'static str;
+
+    fn consume(&mut self, event: Event);
+
+    /// Use the given xkb keymap for all following key events.
+    /// Backends relying on the local keymap ignore this.
+    fn set_keymap(&mut self, _keymap: &[u8]) {}
+
+    /// The xkb keymap keys are currently interpreted with,
+    /// if the backend can tell, e.g. the keymap of the compositor.
+    fn active_keymap(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// keymap format accepted by [`InputEmulation::set_keymap`]
+    fn keymap_format(&self) -> u32 {
+        event::KEYMAP_NONE
+    }
+
+    /// event classes this backend can emulate
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::ALL
+    }
+
+    /// file descriptors of the connection to the compositor or device,
+    /// readable when [`InputEmulation::dispatch`] has work to do
+    fn fds(&self) -> Vec<RawFd> {
+        vec![]
+    }
+
+    /// handle events of the backend (pings, errors) without blocking
+    fn dispatch(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// submit all pending requests
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Create the emulation backend for the current session:
+/// wlroots virtual devices or libei on wayland, XTest on X11.
+/// Falls back to uinput, which works on any compositor and the console.
+pub fn create() -> Result<Box<dyn InputEmulation>, Box<dyn Error>> {
+    if env::var_os("WAYLAND_DISPLAY").is_some() {
+        // with only one of the virtual devices, libei is preferred
+        let mut partial = None;
+        match wayland::VirtualDevices::new() {
+            Ok(backend) if backend.capabilities() == Capabilities::ALL => {
+                info!("using wlroots virtual input emulation");
+                return Ok(Box::new(backend));
+            }
+            Ok(backend) => {
+                let missing = if backend.capabilities().pointer {
+                    "keyboard"
+                } else {
+                    "pointer"
+                };
+                warn!(missing, "wlroots virtual input is incomplete");
+                partial = Some(backend);
+            }
+            Err(e) => warn!("wlroots virtual input unavailable: {}", e),
+        }
+        #[cfg(feature = "libei")]
+        match libei::LibeiEmulation::new() {
+            Ok(backend) => {
+                info!("using libei emulation");
+                return Ok(Box::new(backend));
+            }
+            Err(e) => warn!("libei unavailable: {}", e),
+        }
+        if let Some(backend) = partial {
+            info!("using incomplete wlroots virtual input emulation");
+            return Ok(Box::new(backend));
+        }
+    } else {
+        // XTest on Xwayland would only reach X11 clients
+        #[cfg(feature = "x11")]
+        if env::var_os("DISPLAY").is_some() {
+            match x11::XTest::new() {
+                Ok(backend) => {
+                    info!("using XTest emulation");
+                    return Ok(Box::new(backend));
+                }
+                Err(e) => warn!("XTest unavailable: {}", e),
+            }
+        }
+    }
+    #[cfg(feature = "uinput")]
+    match uinput::UinputDevices::new() {
+        Ok(backend) => {
+            info!("using uinput emulation");
+            return Ok(Box::new(backend));
+        }
+        Err(e) => warn!("uinput unavailable: {}", e),
+    }
+    Err("no emulation backend available for this session".into())
+}
@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+
+use tracing::debug;
+#[cfg(feature = "xkb")]
+use tracing::warn;
+
+use crate::event::{self, ControlEvent, Event, KeyboardEvent, PointerEvent};
+#[cfg(feature = "xkb")]
+use crate::keysym::Mapper;
+
+use super::InputEmulation;
+
+/// Emulates events, mapping keysyms onto the local keymap first.
+#[derive(Default)]
+pub struct Emulator {
+    /// created on the first keysym, `Some(None)` if that failed
+    #[cfg(feature = "xkb")]
+    mapper: Option<Option<Mapper>>,
+    /// keys and buttons pressed, released when the peer leaves
+    keys: HashSet<u32>,
+    buttons: HashSet<u32>,
+}
+
+impl Emulator {
+    /// emulate `event`, releasing everything still pressed when the peer leaves
+    pub fn consume(&mut self, emulation: &mut dyn InputEmulation, event: Event) {
+        match event {
+            #[cfg(feature = "xkb")]
+            Event::Keyboard(KeyboardEvent::Keysym {
+                time,
+                keysym,
+                state,
+            }) => {
+                let mapper = self.mapper.get_or_insert_with(|| {
+                    // extend the keymap only if the backend takes keymaps
+                    let extend = emulation.keymap_format() == event::KEYMAP_XKB_V1;
+                    let keymap = emulation.active_keymap();
+                    let mapper = Mapper::new(keymap.as_deref(), extend)
+                        .map_err(|e| warn!("keysym mode unavailable: {}", e))
+                        .ok()?;
+                    if extend {
+                        emulation.set_keymap(&mapper.keymap());
+                    }
+                    Some(mapper)
+                });
+                let mapper = match mapper {
+                    Some(mapper) => mapper,
+                    None => return,
+                };
+                let mapped = mapper.map(time, keysym, state);
+                if mapped.keymap_changed {
+                    emulation.set_keymap(&mapper.keymap());
+                }
+                for e in mapped.events {
+                    self.emulate(emulation, e);
+                }
+            }
+            #[cfg(not(feature = "xkb"))]
+            Event::Keyboard(KeyboardEvent::Keysym { .. }) => {
+                debug!("dropping keysym, keysym mode not compiled in")
+            }
+            Event::Control(ControlEvent::Enter) => debug!("peer entered"),
+            Event::Control(ControlEvent::Leave) => {
+                debug!(
+                    keys = self.keys.len(),
+                    buttons = self.buttons.len(),
+                    "peer left"
+                );
+                self.release(emulation);
+            }
+            event => self.emulate(emulation, event),
+        }
+    }
+
+    fn emulate(&mut self, emulation: &mut dyn InputEmulation, event: Event) {
+        match event {
+            Event::Keyboard(KeyboardEvent::Key { key, state, .. }) => match state {
+                event::KEY_PRESSED => self.keys.insert(key),
+                _ => self.keys.remove(&key),
+            },
+            Event::Pointer(PointerEvent::Button { button, state, .. }) => match state {
+                event::BUTTON_PRESSED => self.buttons.insert(button),
+                _ => self.buttons.remove(&button),
+            },
+            _ => false,
+        };
+        emulation.consume(event);
+    }
+
+    /// release everything still pressed
+    pub fn release(&mut self, emulation: &mut dyn InputEmulation) {
+        for key in self.keys.drain() {
+            emulation.consume(Event::Keyboard(KeyboardEvent::Key {
+                time: 0,
+                key,
+                state: event::KEY_RELEASED,
+            }));
+        }
+        for button in self.buttons.drain() {
+            emulation.consume(Event::Pointer(PointerEvent::Button {
+                time: 0,
+                button,
+                state: event::BUTTON_RELEASED,
+            }));
+        }
+    }
+}
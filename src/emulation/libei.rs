@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io, mem,
+    os::{
+        fd::IntoRawFd,
+        unix::{
+            net::UnixStream,
+            prelude::{AsRawFd, FromRawFd, RawFd},
+        },
+    },
+    time::Duration,
+};
+
+use memmap::Mmap;
+use tracing::{debug, info, warn};
+use zbus::zvariant::{OwnedFd, Value};
+
+use crate::{
+    ei::{self, Args, Context, Devices, Interface, Message},
+    event::{Event, KeyboardEvent, PointerEvent},
+    portal::{self, Portal},
+};
+
+use super::InputEmulation;
+
+const REMOTE_DESKTOP: &str = "org.freedesktop.portal.RemoteDesktop";
+
+/// device types for `RemoteDesktop.SelectDevices`
+const DEVICE_KEYBOARD: u32 = 1;
+const DEVICE_POINTER: u32 = 2;
+
+/// how long to wait for the EIS implementation to resume a device
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// requests of ei_pointer, ei_button, ei_scroll, ei_keyboard
+const POINTER_MOTION_RELATIVE: u32 = 1;
+const BUTTON_BUTTON: u32 = 1;
+const SCROLL_SCROLL: u32 = 1;
+const KEYBOARD_KEY: u32 = 1;
+
+// ei_keyboard events
+const KEYBOARD_KEYMAP: u32 = 1;
+
+/// Emulation backend speaking the ei protocol to an EIS implementation,
+/// either the socket given by `LIBEI_SOCKET` or one obtained
+/// through the RemoteDesktop portal.
+pub struct LibeiEmulation {
+    ctx: Context,
+    devices: Devices,
+    sequence: u32,
+    /// xkb keymap of the EIS keyboard
+    keymap: Option<Vec<u8>>,
+    /// keeps the portal session alive
+    _portal: Option<Portal>,
+}
+
+/// CLOCK_MONOTONIC in microseconds, as expected for frames
+fn now_us() -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+/// Start a RemoteDesktop session and connect to its EIS implementation.
+fn connect_portal() -> Result<(UnixStream, Portal), Box<dyn Error>> {
+    let mut portal = Portal::new()?;
+    let proxy = portal.proxy(REMOTE_DESKTOP)?;
+
+    let token = portal.token();
+    let session_token = portal.token();
+    let options = HashMap::from([
+        ("handle_token", Value::from(token.as_str())),
+        ("session_handle_token", Value::from(session_token.as_str())),
+    ]);
+    let results = portal.request(&proxy, "CreateSession", &token, &(options,))?;
+    let session = portal::session_handle(&results)?;
+
+    let token = portal.token();
+    let options = HashMap::from([
+        ("handle_token", Value::from(token.as_str())),
+        ("types", Value::from(DEVICE_KEYBOARD | DEVICE_POINTER)),
+    ]);
+    portal.request(&proxy, "SelectDevices", &token, &(&session, options))?;
+
+    let token = portal.token();
+    let options = HashMap::from([("handle_token", Value::from(token.as_str()))]);
+    portal.request(&proxy, "Start", &token, &(&session, "", options))?;
+
+    let options: HashMap<&str, Value> = HashMap::new();
+    let fd: OwnedFd = proxy.call("ConnectToEIS", &(&session, options))?;
+    let stream = unsafe { UnixStream::from_raw_fd(fd.into_raw_fd()) };
+    Ok((stream, portal))
+}
+
+impl LibeiEmulation {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let (stream, portal) = match ei::socket_path() {
+            Some(path) => {
+                info!(path = %path.display(), "connecting to EIS");
+                (UnixStream::connect(path)?, None)
+            }
+            None => {
+                info!("requesting EIS connection from the RemoteDesktop portal");
+                let (stream, portal) = connect_portal()?;
+                (stream, Some(portal))
+            }
+        };
+        stream.set_read_timeout(Some(SETUP_TIMEOUT))?;
+        let ctx = Context::connect(
+            stream,
+            ei::CONTEXT_SENDER,
+            "lan-mouse",
+            &ei::INPUT_INTERFACES,
+        )?;
+        let mut emulation = LibeiEmulation {
+            ctx,
+            devices: Devices::default(),
+            sequence: 0,
+            keymap: None,
+            _portal: portal,
+        };
+        // wait for the first device to become usable
+        while !emulation.devices.any_resumed() {
+            match emulation.ctx.next_message()? {
+                Some(msg) => emulation.handle(msg)?,
+                None => return Err("EIS did not provide any device".into()),
+            }
+        }
+        emulation.ctx.set_nonblocking(true)?;
+        Ok(emulation)
+    }
+
+    fn handle(&mut self, msg: Message) -> io::Result<()> {
+        let mut msg = match self.devices.handle(&mut self.ctx, msg)? {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+        let interface = self.devices.interface(msg.object);
+        if interface == Some(Interface::Keyboard) && msg.opcode == KEYBOARD_KEYMAP {
+            // keys are interpreted with the keymap of the EIS side
+            let _keymap_type = msg.u32()?;
+            let size = msg.u32()?;
+            let fd = self
+                .ctx
+                .take_fd()
+                .ok_or_else(|| io::Error::other("keymap without fd"))?;
+            debug!(size, "keymap of the EIS keyboard");
+            let mmap = unsafe { Mmap::map(&File::from(fd))? };
+            self.keymap = Some(mmap.to_vec());
+        }
+        Ok(())
+    }
+
+    fn process(&mut self) -> io::Result<()> {
+        while let Some(msg) = self.ctx.next_message()? {
+            self.handle(msg)?;
+        }
+        Ok(())
+    }
+
+    /// Send a request on the given interface of a resumed device,
+    /// followed by a frame.
+    fn send(&mut self, interface: Interface, opcode: u32, args: Args) -> io::Result<()> {
+        let serial = self.devices.serial();
+        let (device_id, object, device) = match self.devices.find(interface) {
+            Some(found) => found,
+            None => {
+                debug!(?interface, "no device available");
+                return Ok(());
+            }
+        };
+        if !device.emulating {
+            device.emulating = true;
+            self.sequence += 1;
+            self.ctx.send(
+                device_id,
+                ei::DEVICE_START_EMULATING,
+                Args::new().u32(serial).u32(self.sequence),
+            )?;
+        }
+        self.ctx.send(object, opcode, args)?;
+        self.ctx.send(
+            device_id,
+            ei::DEVICE_FRAME,
+            Args::new().u32(serial).u64(now_us()),
+        )
+    }
+
+    fn emulate(&mut self, event: Event) -> io::Result<()> {
+        self.process()?;
+        match event {
+            Event::Pointer(PointerEvent::Motion { dx, dy, .. }) => self.send(
+                Interface::Pointer,
+                POINTER_MOTION_RELATIVE,
+                Args::new().f32(dx as f32).f32(dy as f32),
+            ),
+            // would need a device with regions
+            Event::Pointer(PointerEvent::Absolute { .. }) => {
+                debug!("absolute motion not supported, dropping");
+                Ok(())
+            }
+            Event::Pointer(PointerEvent::Button { button, state, .. }) => self.send(
+                Interface::Button,
+                BUTTON_BUTTON,
+                Args::new().u32(button).u32(state),
+            ),
+            Event::Pointer(PointerEvent::Axis { axis, value, .. }) => {
+                let (x, y) = match axis {
+                    crate::event::AXIS_HORIZONTAL => (value as f32, 0.),
+                    _ => (0., value as f32),
+                };
+                self.send(Interface::Scroll, SCROLL_SCROLL, Args::new().f32(x).f32(y))
+            }
+            // every request is followed by a frame already
+            Event::Pointer(PointerEvent::Frame) => Ok(()),
+            Event::Keyboard(KeyboardEvent::Key { key, state, .. }) => self.send(
+                Interface::Keyboard,
+                KEYBOARD_KEY,
+                Args::new().u32(key).u32(state),
+            ),
+            // modifier state follows from the key events
+            Event::Keyboard(KeyboardEvent::Modifiers { .. }) => Ok(()),
+            // keysyms are mapped onto keys before emulation
+            Event::Keyboard(KeyboardEvent::Keysym { .. }) => Ok(()),
+            // handled by the client
+            Event::Control(_) => Ok(()),
+        }
+    }
+}
+
+impl InputEmulation for LibeiEmulation {
+    fn name(&self) -> &'static str {
+        "libei"
+    }
+
+    fn consume(&mut self, event: Event) {
+        if let Err(e) = self.emulate(event) {
+            warn!("libei: {}", e);
+        }
+    }
+
+    fn active_keymap(&self) -> Option<Vec<u8>> {
+        self.keymap.clone()
+    }
+
+    fn set_keymap(&mut self, keymap: &[u8]) {
+        debug!(
+            len = keymap.len(),
+            "ignoring keymap, EIS uses its own keymap"
+        );
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.ctx.as_raw_fd()]
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        // answers pings while idle
+        self.process()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+use std::{
+    io,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use crate::event::{self, Event};
+
+use super::InputEmulation;
+
+/// everything an emulation backend can be asked to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Emulated {
+    Event(Event),
+    KeyMap(Vec<u8>),
+}
+
+/// In-memory emulation backend, forwarding everything into a channel
+pub struct MemoryEmulation {
+    tx: Sender<Emulated>,
+}
+
+/// create an emulation backend together with the receiver of emulated input
+pub fn new() -> (MemoryEmulation, Receiver<Emulated>) {
+    let (tx, rx) = mpsc::channel();
+    (MemoryEmulation { tx }, rx)
+}
+
+impl InputEmulation for MemoryEmulation {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn keymap_format(&self) -> u32 {
+        event::KEYMAP_XKB_V1
+    }
+
+    fn consume(&mut self, event: Event) {
+        let _ = self.tx.send(Emulated::Event(event));
+    }
+
+    fn set_keymap(&mut self, keymap: &[u8]) {
+        let _ = self.tx.send(Emulated::KeyMap(keymap.to_vec()));
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
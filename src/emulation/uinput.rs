@@ -0,0 +1,174 @@
+use std::{error::Error, io};
+
+use evdev::{
+    uinput::{VirtualDevice, VirtualDeviceBuilder},
+    AttributeSet, EventType, InputEvent, Key, RelativeAxisType,
+};
+use tracing::{debug, warn};
+
+use crate::event::{self, Event, KeyboardEvent, PointerEvent};
+
+use super::InputEmulation;
+
+/// scroll distance of one wheel click, as reported by libinput
+const SCROLL_STEP: f64 = 15.0;
+
+/// high resolution wheel units per click
+const HI_RES_STEP: f64 = 120.0;
+
+// linux/input-event-codes.h
+const KEY_ESC: u16 = 1;
+const KEY_MICMUTE: u16 = 248;
+const BTN_TASK: u16 = 0x117;
+
+/// Emulation backend creating a virtual mouse and keyboard through
+/// `/dev/uinput`.
+///
+/// The devices are picked up by whatever reads input on this machine,
+/// i.e. any compositor, X11 and the console. Keys are translated by the
+/// local keymap, the keymap of the peer is not used.
+pub struct UinputDevices {
+    pointer: VirtualDevice,
+    keyboard: VirtualDevice,
+    /// sub-pixel motion not yet sent
+    motion: (f64, f64),
+    /// high resolution scroll not yet sent as wheel clicks (vertical, horizontal)
+    wheel: [f64; 2],
+}
+
+impl UinputDevices {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let mut buttons = AttributeSet::<Key>::new();
+        for code in event::BTN_LEFT as u16..=BTN_TASK {
+            buttons.insert(Key::new(code));
+        }
+        let mut axes = AttributeSet::<RelativeAxisType>::new();
+        axes.insert(RelativeAxisType::REL_X);
+        axes.insert(RelativeAxisType::REL_Y);
+        axes.insert(RelativeAxisType::REL_WHEEL);
+        axes.insert(RelativeAxisType::REL_HWHEEL);
+        axes.insert(RelativeAxisType::REL_WHEEL_HI_RES);
+        axes.insert(RelativeAxisType::REL_HWHEEL_HI_RES);
+        let pointer = VirtualDeviceBuilder::new()?
+            .name("lan-mouse virtual pointer")
+            .with_keys(&buttons)?
+            .with_relative_axes(&axes)?
+            .build()?;
+
+        let mut keys = AttributeSet::<Key>::new();
+        for code in KEY_ESC..=KEY_MICMUTE {
+            keys.insert(Key::new(code));
+        }
+        let keyboard = VirtualDeviceBuilder::new()?
+            .name("lan-mouse virtual keyboard")
+            .with_keys(&keys)?
+            .build()?;
+
+        Ok(UinputDevices {
+            pointer,
+            keyboard,
+            motion: (0., 0.),
+            wheel: [0., 0.],
+        })
+    }
+
+    fn scroll(&mut self, axis: u32, value: f64) -> io::Result<()> {
+        // wayland scrolls down / right for positive values,
+        // the vertical wheel turns up for positive values
+        let (idx, axis, hi_res_axis, hi_res) = match axis {
+            event::AXIS_HORIZONTAL => (
+                1,
+                RelativeAxisType::REL_HWHEEL,
+                RelativeAxisType::REL_HWHEEL_HI_RES,
+                value,
+            ),
+            _ => (
+                0,
+                RelativeAxisType::REL_WHEEL,
+                RelativeAxisType::REL_WHEEL_HI_RES,
+                -value,
+            ),
+        };
+        let hi_res = hi_res * HI_RES_STEP / SCROLL_STEP;
+        self.wheel[idx] += hi_res;
+        let clicks = (self.wheel[idx] / HI_RES_STEP).trunc();
+        self.wheel[idx] -= clicks * HI_RES_STEP;
+        let mut events = vec![InputEvent::new(
+            EventType::RELATIVE,
+            hi_res_axis.0,
+            hi_res.round() as i32,
+        )];
+        if clicks != 0. {
+            events.push(InputEvent::new(EventType::RELATIVE, axis.0, clicks as i32));
+        }
+        self.pointer.emit(&events)
+    }
+
+    fn emulate(&mut self, event: Event) -> io::Result<()> {
+        match event {
+            Event::Pointer(PointerEvent::Motion { dx, dy, .. }) => {
+                self.motion.0 += dx;
+                self.motion.1 += dy;
+                let (x, y) = (self.motion.0.trunc(), self.motion.1.trunc());
+                self.motion.0 -= x;
+                self.motion.1 -= y;
+                if x != 0. || y != 0. {
+                    self.pointer.emit(&[
+                        InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, x as i32),
+                        InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, y as i32),
+                    ])?;
+                }
+            }
+            // the virtual mouse has no absolute axes
+            Event::Pointer(PointerEvent::Absolute { .. }) => {
+                debug!("absolute motion not supported, dropping")
+            }
+            Event::Pointer(PointerEvent::Button { button, state, .. }) => {
+                self.pointer.emit(&[InputEvent::new(
+                    EventType::KEY,
+                    button as u16,
+                    state as i32,
+                )])?;
+            }
+            Event::Pointer(PointerEvent::Axis { axis, value, .. }) => {
+                self.scroll(axis, value)?;
+            }
+            // every emitted batch is terminated by a SYN_REPORT already
+            Event::Pointer(PointerEvent::Frame) => {}
+            Event::Keyboard(KeyboardEvent::Key { key, state, .. }) => {
+                self.keyboard
+                    .emit(&[InputEvent::new(EventType::KEY, key as u16, state as i32)])?;
+            }
+            // modifier state follows from the key events
+            Event::Keyboard(KeyboardEvent::Modifiers { .. }) => {}
+            // keysyms are mapped onto keys before emulation
+            Event::Keyboard(KeyboardEvent::Keysym { .. }) => {}
+            // handled by the client
+            Event::Control(_) => {}
+        }
+        Ok(())
+    }
+}
+
+impl InputEmulation for UinputDevices {
+    fn name(&self) -> &'static str {
+        "uinput"
+    }
+
+    fn consume(&mut self, event: Event) {
+        if let Err(e) = self.emulate(event) {
+            warn!("uinput: {}", e);
+        }
+    }
+
+    fn set_keymap(&mut self, keymap: &[u8]) {
+        debug!(
+            len = keymap.len(),
+            "ignoring keymap, uinput uses the local keymap"
+        );
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::unix::prelude::{AsRawFd, RawFd},
+};
+
+use memmap::Mmap;
+use tracing::{debug, info, warn};
+
+use wayland_protocols_wlr::virtual_pointer::v1::client::{
+    zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1 as VpManager,
+    zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1 as Vp,
+};
+
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::{
+    zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1 as VkManager,
+    zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1 as Vk,
+};
+
+use wayland_client::{
+    delegate_noop,
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{wl_keyboard, wl_registry, wl_seat},
+    Connection, Dispatch, EventQueue, QueueHandle, WEnum,
+};
+
+use crate::{
+    event::{self, Capabilities, Event, KeyboardEvent, PointerEvent},
+    wayland::{self, Probe},
+};
+
+use super::InputEmulation;
+
+/// range absolute positions are scaled to, the compositor maps it onto its outputs
+const EXTENT: u32 = u16::MAX as u32;
+
+// no events of the virtual devices are handled
+#[derive(Default)]
+struct State {
+    /// xkb keymap of the seat keyboard, as set by the compositor
+    keymap: Option<Vec<u8>>,
+}
+
+/// Emulation backend using the wlroots virtual pointer
+/// and the virtual keyboard protocol.
+/// Either one may be missing, the respective events are dropped then.
+pub struct VirtualDevices {
+    /// of the compositor connection
+    fd: RawFd,
+    queue: EventQueue<State>,
+    state: State,
+    pointer: Option<Vp>,
+    keyboard: Option<Vk>,
+}
+
+impl VirtualDevices {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::connect_to_env()?;
+        let (globals, mut queue) = registry_queue_init::<State>(&conn)?;
+        let qh = queue.handle();
+
+        let mut probe = Probe::default();
+        let vpm: Option<VpManager> = probe.bind(&globals, &qh, 1..=1);
+        let vkm: Option<VkManager> = probe.bind(&globals, &qh, 1..=1);
+        let seat: Option<wl_seat::WlSeat> = probe.bind(&globals, &qh, 7..=8);
+        if !probe.missing().is_empty() {
+            warn!(missing = ?probe.missing(), "compositor protocols missing");
+        }
+
+        let pointer = vpm.map(|vpm| vpm.create_virtual_pointer(None, &qh, ()));
+        let keyboard = match (vkm, &seat) {
+            (Some(vkm), Some(seat)) => Some(vkm.create_virtual_keyboard(seat, &qh, ())),
+            _ => None,
+        };
+        match (&pointer, &keyboard) {
+            (None, None) => {
+                return Err(
+                    format!("required protocols missing: {}", probe.missing().join(", ")).into(),
+                )
+            }
+            (None, _) => warn!("no virtual pointer, pointer events are dropped"),
+            (_, None) => warn!("no virtual keyboard, keyboard events are dropped"),
+            _ => {}
+        }
+        // the capabilities of the seat, then the keymap of its keyboard
+        let mut state = State::default();
+        if keyboard.is_some() {
+            for _ in 0..2 {
+                queue.roundtrip(&mut state)?;
+            }
+        }
+        Ok(VirtualDevices {
+            fd: conn.backend().poll_fd().as_raw_fd(),
+            queue,
+            state,
+            pointer,
+            keyboard,
+        })
+    }
+}
+
+impl InputEmulation for VirtualDevices {
+    fn name(&self) -> &'static str {
+        "wlroots"
+    }
+
+    fn keymap_format(&self) -> u32 {
+        event::KEYMAP_XKB_V1
+    }
+
+    fn consume(&mut self, event: Event) {
+        match (event, &self.pointer, &self.keyboard) {
+            (Event::Pointer(e), Some(pointer), _) => match e {
+                PointerEvent::Motion { time, dx, dy } => {
+                    pointer.motion(time, dx, dy);
+                    pointer.frame();
+                }
+                PointerEvent::Absolute { time, x, y } => {
+                    let (x, y) = (x * EXTENT as f64, y * EXTENT as f64);
+                    pointer.motion_absolute(time, x as u32, y as u32, EXTENT, EXTENT);
+                    pointer.frame();
+                }
+                PointerEvent::Button {
+                    time,
+                    button,
+                    state,
+                } => {
+                    pointer.button(time, button, wayland::button_state(state));
+                    pointer.frame();
+                }
+                PointerEvent::Axis { time, axis, value } => {
+                    pointer.axis(time, wayland::axis(axis), value);
+                    pointer.frame();
+                }
+                PointerEvent::Frame => {
+                    pointer.frame();
+                }
+            },
+            (Event::Keyboard(e), _, Some(keyboard)) => match e {
+                KeyboardEvent::Key { time, key, state } => {
+                    keyboard.key(time, key, state);
+                }
+                KeyboardEvent::Modifiers {
+                    mods_depressed,
+                    mods_latched,
+                    mods_locked,
+                    group,
+                } => {
+                    keyboard.modifiers(mods_depressed, mods_latched, mods_locked, group);
+                }
+                // keysyms are mapped onto keys before emulation
+                KeyboardEvent::Keysym { .. } => {}
+            },
+            // not supported, see `capabilities`
+            _ => {}
+        }
+    }
+
+    fn active_keymap(&self) -> Option<Vec<u8>> {
+        self.state.keymap.clone()
+    }
+
+    fn set_keymap(&mut self, keymap: &[u8]) {
+        let keyboard = match &self.keyboard {
+            Some(keyboard) => keyboard,
+            None => return,
+        };
+        // TODO use shm_open
+        let upload = || -> io::Result<()> {
+            let f = tempfile::tempfile()?;
+            let mut buf = BufWriter::new(&f);
+            buf.write_all(keymap)?;
+            buf.flush()?;
+            drop(buf);
+            keyboard.keymap(1, f.as_raw_fd(), keymap.len() as u32);
+            Ok(())
+        };
+        match upload() {
+            Ok(()) => info!(len = keymap.len(), "keymap uploaded to virtual keyboard"),
+            Err(e) => warn!("could not upload keymap: {}", e),
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            pointer: self.pointer.is_some(),
+            keyboard: self.keyboard.is_some(),
+        }
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.fd]
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        wayland::dispatch(&mut self.queue, &mut self.state)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.queue.flush().map_err(io::Error::other)
+    }
+}
+
+delegate_noop!(State: Vp);
+delegate_noop!(State: Vk);
+delegate_noop!(State: VpManager);
+delegate_noop!(State: VkManager);
+
+impl Dispatch<wl_seat::WlSeat, ()> for State {
+    fn event(
+        _: &mut Self,
+        seat: &wl_seat::WlSeat,
+        event: wl_seat::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+        {
+            // only for its keymap, nothing is focused
+            if capabilities.contains(wl_seat::Capability::Keyboard) {
+                seat.get_keyboard(qh, ());
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_keyboard::WlKeyboard, ()> for State {
+    fn event(
+        state: &mut Self,
+        _: &wl_keyboard::WlKeyboard,
+        event: wl_keyboard::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_keyboard::Event::Keymap { format, fd, size } = event {
+            if format != WEnum::Value(wl_keyboard::KeymapFormat::XkbV1) {
+                debug!(?format, "ignoring keymap of the seat");
+                return;
+            }
+            match unsafe { Mmap::map(&File::from(fd)) } {
+                Ok(mmap) => {
+                    debug!(size, "keymap of the seat");
+                    state.keymap = Some(mmap.to_vec());
+                }
+                Err(e) => warn!("could not map the keymap of the seat: {}", e),
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+        _: &mut State,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<State>,
+    ) {
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    error::Error,
+    io,
+    os::unix::prelude::{AsRawFd, RawFd},
+};
+
+use tracing::{debug, warn};
+use x11rb::{
+    connection::{Connection, RequestConnection},
+    protocol::{
+        xproto::{
+            AtomEnum, ConnectionExt as _, Window, BUTTON_PRESS_EVENT, BUTTON_RELEASE_EVENT,
+            KEY_PRESS_EVENT, KEY_RELEASE_EVENT, MOTION_NOTIFY_EVENT,
+        },
+        xtest::{self, ConnectionExt as _},
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{
+    event::{self, Event, KeyboardEvent, PointerEvent},
+    logging,
+};
+
+use super::InputEmulation;
+
+/// X keycodes are evdev keycodes shifted by 8
+const KEYCODE_OFFSET: u32 = 8;
+
+/// scroll distance of one wheel click, as reported by libinput
+const SCROLL_STEP: f64 = 15.0;
+
+/// Emulation backend injecting events through the XTest extension
+pub struct XTest {
+    conn: RustConnection,
+    /// sub-pixel motion not yet sent
+    motion: (f64, f64),
+    /// scroll distance not yet sent as wheel clicks (vertical, horizontal)
+    scroll: [f64; 2],
+    root: Window,
+    /// size of the root window, for absolute motion
+    size: (u16, u16),
+}
+
+fn x11_button(button: u32) -> Option<u8> {
+    match button {
+        event::BTN_LEFT => Some(1),
+        event::BTN_MIDDLE => Some(2),
+        event::BTN_RIGHT => Some(3),
+        event::BTN_SIDE => Some(8),
+        event::BTN_EXTRA => Some(9),
+        _ => None,
+    }
+}
+
+/// X keycodes are a single byte
+fn x11_keycode(key: u32) -> Option<u8> {
+    key.checked_add(KEYCODE_OFFSET)
+        .and_then(|keycode| u8::try_from(keycode).ok())
+}
+
+/// whole pixels towards zero, clamped to the range of X coordinates
+fn coordinate(v: f64) -> i16 {
+    v.trunc().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+impl XTest {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let (conn, screen) = x11rb::connect(None)?;
+        if conn
+            .extension_information(xtest::X11_EXTENSION_NAME)?
+            .is_none()
+        {
+            return Err("XTest extension not available".into());
+        }
+        let root = &conn.setup().roots[screen];
+        let size = (root.width_in_pixels, root.height_in_pixels);
+        let root = root.root;
+        Ok(XTest {
+            conn,
+            motion: (0., 0.),
+            scroll: [0., 0.],
+            root,
+            size,
+        })
+    }
+
+    #[cfg(feature = "xkb")]
+    /// Rules, model, layout, variant and options the keymap
+    /// of the server was compiled from, as set by `setxkbmap`.
+    fn rules_names(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let atom = self
+            .conn
+            .intern_atom(true, b"_XKB_RULES_NAMES")?
+            .reply()?
+            .atom;
+        if atom == x11rb::NONE {
+            return Err("no _XKB_RULES_NAMES".into());
+        }
+        let reply = self
+            .conn
+            .get_property(false, self.root, atom, AtomEnum::STRING, 0, 1024)?
+            .reply()?;
+        let names = reply
+            .value
+            .split(|b| *b == 0)
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        Ok(names)
+    }
+
+    fn fake_input(&self, type_: u8, detail: u8) -> Result<(), Box<dyn Error>> {
+        self.fake_motion(type_, detail, 0, 0)
+    }
+
+    fn fake_motion(&self, type_: u8, detail: u8, x: i16, y: i16) -> Result<(), Box<dyn Error>> {
+        self.conn
+            .xtest_fake_input(type_, detail, x11rb::CURRENT_TIME, x11rb::NONE, x, y, 0)?;
+        Ok(())
+    }
+
+    /// press and release the wheel button once per scroll step
+    fn scroll(&mut self, axis: u32, value: f64) -> Result<(), Box<dyn Error>> {
+        let (idx, negative, positive) = match axis {
+            event::AXIS_HORIZONTAL => (1, 6, 7),
+            _ => (0, 4, 5),
+        };
+        self.scroll[idx] += value;
+        while self.scroll[idx].abs() >= SCROLL_STEP {
+            let (button, step) = if self.scroll[idx] > 0. {
+                (positive, SCROLL_STEP)
+            } else {
+                (negative, -SCROLL_STEP)
+            };
+            self.fake_input(BUTTON_PRESS_EVENT, button)?;
+            self.fake_input(BUTTON_RELEASE_EVENT, button)?;
+            self.scroll[idx] -= step;
+        }
+        Ok(())
+    }
+
+    fn emulate(&mut self, event: Event) -> Result<(), Box<dyn Error>> {
+        match event {
+            Event::Pointer(PointerEvent::Motion { dx, dy, .. }) => {
+                self.motion.0 += dx;
+                self.motion.1 += dy;
+                let (x, y) = (coordinate(self.motion.0), coordinate(self.motion.1));
+                // motion beyond the range of X coordinates is dropped
+                self.motion.0 = self.motion.0.fract();
+                self.motion.1 = self.motion.1.fract();
+                if x != 0 || y != 0 {
+                    // detail 1: relative motion
+                    self.fake_motion(MOTION_NOTIFY_EVENT, 1, x, y)?;
+                }
+            }
+            Event::Pointer(PointerEvent::Absolute { x, y, .. }) => {
+                let x = x.clamp(0., 1.) * (self.size.0 - 1) as f64;
+                let y = y.clamp(0., 1.) * (self.size.1 - 1) as f64;
+                // detail 0: absolute motion
+                self.fake_motion(MOTION_NOTIFY_EVENT, 0, coordinate(x), coordinate(y))?;
+            }
+            Event::Pointer(PointerEvent::Button { button, state, .. }) => {
+                let button = match x11_button(button) {
+                    Some(button) => button,
+                    None => {
+                        debug!(button, "ignoring unknown button");
+                        return Ok(());
+                    }
+                };
+                let type_ = match state {
+                    event::BUTTON_PRESSED => BUTTON_PRESS_EVENT,
+                    _ => BUTTON_RELEASE_EVENT,
+                };
+                self.fake_input(type_, button)?;
+            }
+            Event::Pointer(PointerEvent::Axis { axis, value, .. }) => {
+                self.scroll(axis, value)?;
+            }
+            Event::Pointer(PointerEvent::Frame) => {}
+            Event::Keyboard(KeyboardEvent::Key { key, state, .. }) => {
+                let type_ = match state {
+                    event::KEY_PRESSED => KEY_PRESS_EVENT,
+                    _ => KEY_RELEASE_EVENT,
+                };
+                let keycode = match x11_keycode(key) {
+                    Some(keycode) => keycode,
+                    None => {
+                        if logging::log_keys() {
+                            debug!(key, "ignoring key without X keycode");
+                        } else {
+                            debug!("ignoring key without X keycode");
+                        }
+                        return Ok(());
+                    }
+                };
+                self.fake_input(type_, keycode)?;
+            }
+            // the X server tracks modifiers from the key events itself
+            Event::Keyboard(KeyboardEvent::Modifiers { .. }) => {}
+            // keysyms are mapped onto keys before emulation
+            Event::Keyboard(KeyboardEvent::Keysym { .. }) => {}
+            // handled by the client
+            Event::Control(_) => {}
+        }
+        Ok(())
+    }
+}
+
+impl InputEmulation for XTest {
+    fn name(&self) -> &'static str {
+        "xtest"
+    }
+
+    #[cfg(feature = "xkb")]
+    fn active_keymap(&self) -> Option<Vec<u8>> {
+        let names = self
+            .rules_names()
+            .map_err(|e| debug!("no keymap names: {}", e))
+            .ok()?;
+        let names: [&str; 5] = std::array::from_fn(|i| names.get(i).map_or("", String::as_str));
+        debug!(?names, "keymap of the X server");
+        crate::keysym::keymap_from_names(names)
+            .map_err(|e| warn!("{}", e))
+            .ok()
+    }
+
+    fn consume(&mut self, event: Event) {
+        if let Err(e) = self.emulate(event) {
+            warn!("xtest: {}", e);
+        }
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.conn.stream().as_raw_fd()]
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        // XTest requests have no replies, only errors arrive here
+        while let Some(e) = self.conn.poll_for_event().map_err(io::Error::other)? {
+            debug!(event = ?e, "X11 event");
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush().map_err(io::Error::other)
+    }
+}
@@ -0,0 +1,6 @@
+//! Backend neutral input events.
+//!
+//! Defined in [`lan_mouse_proto::event`] along with their wire format,
+//! so tools speaking the protocol share them.
+
+pub use lan_mouse_proto::event::*;
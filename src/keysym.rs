@@ -0,0 +1,484 @@
+//! Keysym keyboard mode for peers with different layouts.
+//!
+//! Instead of keycodes interpreted with the keymap of the server,
+//! the server resolves keys to keysyms with its own keymap ([`Resolver`])
+//! and the client maps them back onto keys of its local keymap ([`Mapper`]).
+//! Keysyms missing from the local keymap are added to it,
+//! if the emulation backend accepts keymaps.
+
+use std::{collections::HashMap, error::Error};
+
+use tracing::{debug, info, warn};
+use xkbcommon::xkb::{self, KeyDirection, Keycode, Keysym};
+
+use crate::{
+    event::{self, Event, KeyboardEvent},
+    logging,
+};
+
+/// offset between evdev and xkb keycodes
+const EVDEV_OFFSET: u32 = 8;
+
+const NO_SYMBOL: u32 = 0;
+
+fn compile(context: &xkb::Context, keymap: &str) -> Result<xkb::Keymap, Box<dyn Error>> {
+    let keymap = xkb::Keymap::new_from_string(
+        context,
+        keymap.trim_end_matches('\0').to_string(),
+        xkb::KEYMAP_FORMAT_TEXT_V1,
+        xkb::KEYMAP_COMPILE_NO_FLAGS,
+    );
+    Ok(keymap.ok_or("could not compile keymap")?)
+}
+
+/// compile rules, model, layout, variant and options,
+/// empty ones are taken from the environment (`XKB_DEFAULT_*`)
+fn compile_names(context: &xkb::Context, names: [&str; 5]) -> Result<xkb::Keymap, Box<dyn Error>> {
+    let [rules, model, layout, variant, options] = names;
+    let options = (!options.is_empty()).then(|| options.to_string());
+    let keymap = xkb::Keymap::new_from_names(
+        context,
+        rules,
+        model,
+        layout,
+        variant,
+        options,
+        xkb::KEYMAP_COMPILE_NO_FLAGS,
+    );
+    Ok(keymap.ok_or_else(|| format!("could not compile the keymap of {:?}", names))?)
+}
+
+fn compile_default(context: &xkb::Context) -> Result<xkb::Keymap, Box<dyn Error>> {
+    compile_names(context, [""; 5])
+}
+
+/// The local default keymap (see `XKB_DEFAULT_LAYOUT`), NUL terminated.
+pub fn default_keymap() -> Result<Vec<u8>, Box<dyn Error>> {
+    keymap_from_names([""; 5])
+}
+
+/// The keymap of rules, model, layout, variant and options
+/// like `setxkbmap -query` reports them, NUL terminated.
+pub fn keymap_from_names(names: [&str; 5]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let keymap = compile_names(&context, names)?;
+    let mut keymap = keymap
+        .get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1)
+        .into_bytes();
+    keymap.push(0);
+    Ok(keymap)
+}
+
+fn key_event(time: u32, key: u32, state: u32) -> Event {
+    Event::Keyboard(KeyboardEvent::Key {
+        time,
+        key: key - EVDEV_OFFSET,
+        state,
+    })
+}
+
+/// Sender side: resolves captured keys to keysyms.
+pub struct Resolver {
+    state: xkb::State,
+    /// keysyms of pressed keys, releases report the keysym of the press
+    pressed: HashMap<u32, u32>,
+}
+
+impl Resolver {
+    /// `keymap` is the xkb keymap of the capture backend
+    pub fn new(keymap: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let keymap = compile(&context, &String::from_utf8_lossy(keymap))?;
+        debug!(layout = keymap.layout_get_name(0), "resolving keysyms");
+        Ok(Resolver {
+            state: xkb::State::new(&keymap),
+            pressed: HashMap::new(),
+        })
+    }
+
+    /// Translate `e` for a peer in keysym mode, `None` if it is not sent.
+    /// Must see every captured event to follow the modifier state.
+    pub fn translate(&mut self, e: Event) -> Option<Event> {
+        match e {
+            Event::Keyboard(KeyboardEvent::Key { time, key, state }) => {
+                let keysym = match state {
+                    event::KEY_PRESSED => {
+                        let keycode = Keycode::new(key + EVDEV_OFFSET);
+                        let keysym = self.state.key_get_one_sym(keycode).raw();
+                        self.pressed.insert(key, keysym);
+                        keysym
+                    }
+                    _ => self.pressed.remove(&key)?,
+                };
+                if keysym == NO_SYMBOL {
+                    return None;
+                }
+                Some(Event::Keyboard(KeyboardEvent::Keysym {
+                    time,
+                    keysym,
+                    state,
+                }))
+            }
+            Event::Keyboard(KeyboardEvent::Modifiers {
+                mods_depressed,
+                mods_latched,
+                mods_locked,
+                group,
+            }) => {
+                self.state
+                    .update_mask(mods_depressed, mods_latched, mods_locked, 0, 0, group);
+                // the receiver follows the keysyms of the modifier keys instead
+                None
+            }
+            e => Some(e),
+        }
+    }
+}
+
+/// Result of [`Mapper::map`].
+#[derive(Default)]
+pub struct Mapped {
+    /// keycode events to emulate
+    pub events: Vec<Event>,
+    /// the keymap was extended and must be uploaded again
+    pub keymap_changed: bool,
+}
+
+/// Receiver side: maps keysyms onto keys of the local keymap.
+pub struct Mapper {
+    context: xkb::Context,
+    /// keymap source, including added keysyms
+    text: String,
+    keymap: xkb::Keymap,
+    /// state of the keys pressed through the mapper
+    state: xkb::State,
+    /// key and modifier mask producing each keysym
+    table: HashMap<u32, (u32, u32)>,
+    /// modifier mask and key of Shift and Level3, pressed for
+    /// backends following the modifier state of the keys
+    modifier_keys: Vec<(u32, u32)>,
+    /// number of keysyms added to the keymap
+    added: u32,
+    /// whether missing keysyms may be added to the keymap
+    extend: bool,
+    /// keys pressed for each keysym
+    pressed: HashMap<u32, u32>,
+}
+
+impl Mapper {
+    /// Map onto `keymap`, the active keymap of the emulation backend,
+    /// or the local default keymap (see `XKB_DEFAULT_LAYOUT`) without one.
+    /// If `extend` is set, the backend accepts keymaps from [`Mapper::keymap`]
+    /// and missing keysyms are added to it.
+    pub fn new(keymap: Option<&[u8]>, extend: bool) -> Result<Self, Box<dyn Error>> {
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let active = keymap.and_then(|keymap| {
+            compile(&context, &String::from_utf8_lossy(keymap))
+                .map_err(|e| warn!("active keymap unusable: {}", e))
+                .ok()
+        });
+        let keymap = match active {
+            Some(keymap) => keymap,
+            None => {
+                info!("no active keymap, using the local default keymap");
+                compile_default(&context)?
+            }
+        };
+        info!(layout = keymap.layout_get_name(0), "mapping keysyms");
+        let text = keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1);
+        let mut mapper = Mapper {
+            state: xkb::State::new(&keymap),
+            context,
+            text,
+            keymap,
+            table: HashMap::new(),
+            modifier_keys: vec![],
+            added: 0,
+            extend,
+            pressed: HashMap::new(),
+        };
+        mapper.build_table();
+        Ok(mapper)
+    }
+
+    /// the local keymap, NUL terminated
+    pub fn keymap(&self) -> Vec<u8> {
+        let mut keymap = self.text.clone().into_bytes();
+        keymap.push(0);
+        keymap
+    }
+
+    fn mod_mask(&self, name: &str) -> Option<u32> {
+        match self.keymap.mod_get_index(name) {
+            xkb::MOD_INVALID => None,
+            idx => Some(1 << idx),
+        }
+    }
+
+    /// find the key and modifiers of every keysym on the first layout
+    fn build_table(&mut self) {
+        let shift = self.mod_mask(xkb::MOD_NAME_SHIFT);
+        let level3 = self.mod_mask("Mod5");
+        let both = shift.zip(level3).map(|(a, b)| a | b);
+        let mut state = xkb::State::new(&self.keymap);
+        self.table.clear();
+        // unmodified levels take precedence
+        for mask in [Some(0), shift, level3, both].into_iter().flatten() {
+            state.update_mask(mask, 0, 0, 0, 0, 0);
+            let (min, max) = (self.keymap.min_keycode(), self.keymap.max_keycode());
+            for key in min.raw()..=max.raw() {
+                if let [keysym] = state.key_get_syms(Keycode::new(key)) {
+                    self.table.entry(keysym.raw()).or_insert((key, mask));
+                }
+            }
+        }
+        self.modifier_keys = [(shift, Keysym::Shift_L), (level3, Keysym::ISO_Level3_Shift)]
+            .into_iter()
+            .filter_map(|(mask, keysym)| Some((mask?, self.table.get(&keysym.raw())?.0)))
+            .collect();
+    }
+
+    /// key and modifiers producing `keysym`, if any
+    fn lookup(&self, keysym: u32) -> Option<(u32, u32)> {
+        self.table.get(&keysym).copied()
+    }
+
+    /// Add `keysym` on a new key, returns its key and modifiers.
+    fn add(&mut self, keysym: u32) -> Result<(u32, u32), Box<dyn Error>> {
+        let name = xkb::keysym_get_name(Keysym::new(keysym));
+        let keycode = self.keymap.max_keycode().raw() + 1;
+        let key = format!("LM{}", self.added);
+        let mut text = self.text.clone();
+
+        let start = text.find("maximum = ").ok_or("keymap without maximum")?;
+        let end = start + text[start..].find(';').ok_or("invalid keymap")?;
+        text.replace_range(start..end, &format!("maximum = {}", keycode));
+        let line = end + text[end..].find('\n').ok_or("invalid keymap")? + 1;
+        text.insert_str(line, &format!("\t<{}> = {};\n", key, keycode));
+
+        let symbols = text.find("xkb_symbols").ok_or("keymap without symbols")?;
+        let line = symbols + text[symbols..].find('\n').ok_or("invalid keymap")? + 1;
+        text.insert_str(line, &format!("\tkey <{}> {{ [ {} ] }};\n", key, name));
+
+        self.keymap = compile(&self.context, &text)?;
+        self.text = text;
+        self.added += 1;
+        self.state = xkb::State::new(&self.keymap);
+        for key in self.pressed.values() {
+            self.state
+                .update_key(Keycode::new(*key), KeyDirection::Down);
+        }
+        self.build_table();
+        if logging::log_keys() {
+            info!(keysym = name, keycode, "added keysym to keymap");
+        } else {
+            info!(keycode, "added keysym to keymap");
+        }
+        Ok(self.lookup(keysym).ok_or("keysym not mapped")?)
+    }
+
+    fn modifiers(&self) -> Event {
+        Event::Keyboard(KeyboardEvent::Modifiers {
+            mods_depressed: self.state.serialize_mods(xkb::STATE_MODS_DEPRESSED),
+            mods_latched: self.state.serialize_mods(xkb::STATE_MODS_LATCHED),
+            mods_locked: self.state.serialize_mods(xkb::STATE_MODS_LOCKED),
+            group: self.state.serialize_layout(xkb::STATE_LAYOUT_EFFECTIVE),
+        })
+    }
+
+    /// press or release the modifier keys in `mask`
+    fn modifier_keys(&self, time: u32, mask: u32, state: u32) -> impl Iterator<Item = Event> + '_ {
+        self.modifier_keys
+            .iter()
+            .filter(move |(m, _)| mask & m != 0)
+            .map(move |(_, key)| key_event(time, *key, state))
+    }
+
+    /// Map a keysym event onto key and modifier events of the local keymap.
+    pub fn map(&mut self, time: u32, keysym: u32, state: u32) -> Mapped {
+        let mut mapped = Mapped::default();
+        if state != event::KEY_PRESSED {
+            if let Some(key) = self.pressed.remove(&keysym) {
+                self.state.update_key(Keycode::new(key), KeyDirection::Up);
+                mapped.events.push(key_event(time, key, state));
+                mapped.events.push(self.modifiers());
+            }
+            return mapped;
+        }
+        let (key, mask) = match self.lookup(keysym) {
+            Some(entry) => entry,
+            None if self.extend => match self.add(keysym) {
+                Ok(entry) => {
+                    mapped.keymap_changed = true;
+                    entry
+                }
+                Err(e) if logging::log_keys() => {
+                    warn!(keysym, "could not extend keymap: {}", e);
+                    return mapped;
+                }
+                Err(e) => {
+                    warn!("could not extend keymap: {}", e);
+                    return mapped;
+                }
+            },
+            None => {
+                if logging::log_keys() {
+                    debug!(keysym, "keysym not in local keymap");
+                } else {
+                    debug!("keysym not in local keymap");
+                }
+                return mapped;
+            }
+        };
+        // e.g. shift is held already for an uppercase letter
+        let produced = self.state.key_get_one_sym(Keycode::new(key)).raw() == keysym;
+        if !produced {
+            let held = self.state.serialize_mods(xkb::STATE_MODS_DEPRESSED);
+            let temporary: Vec<_> = self.modifier_keys(time, mask & !held, state).collect();
+            mapped.events.extend(temporary);
+            mapped
+                .events
+                .push(Event::Keyboard(KeyboardEvent::Modifiers {
+                    mods_depressed: mask,
+                    mods_latched: 0,
+                    mods_locked: 0,
+                    group: 0,
+                }));
+            mapped.events.push(key_event(time, key, state));
+            let released: Vec<_> = self
+                .modifier_keys(time, mask & !held, event::KEY_RELEASED)
+                .collect();
+            mapped.events.extend(released);
+        } else {
+            mapped.events.push(key_event(time, key, state));
+        }
+        self.state.update_key(Keycode::new(key), KeyDirection::Down);
+        self.pressed.insert(keysym, key);
+        mapped.events.push(self.modifiers());
+        mapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: u32 = 30;
+    const KEY_LEFTSHIFT: u32 = 42;
+
+    fn us() -> Vec<u8> {
+        keymap_from_names(["evdev", "pc105", "us", "", ""]).unwrap()
+    }
+
+    fn key(key: u32, state: u32) -> Event {
+        Event::Keyboard(KeyboardEvent::Key {
+            time: 0,
+            key,
+            state,
+        })
+    }
+
+    fn keysym(keysym: Keysym, state: u32) -> Option<Event> {
+        Some(Event::Keyboard(KeyboardEvent::Keysym {
+            time: 0,
+            keysym: keysym.raw(),
+            state,
+        }))
+    }
+
+    fn shift(mods_depressed: u32) -> Event {
+        Event::Keyboard(KeyboardEvent::Modifiers {
+            mods_depressed,
+            mods_latched: 0,
+            mods_locked: 0,
+            group: 0,
+        })
+    }
+
+    /// keys in `events`, without the modifier state
+    fn keys(events: &[Event]) -> Vec<(u32, u32)> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Keyboard(KeyboardEvent::Key { key, state, .. }) => Some((*key, *state)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resolve() {
+        let mut resolver = Resolver::new(&us()).unwrap();
+        let (pressed, released) = (event::KEY_PRESSED, event::KEY_RELEASED);
+        assert_eq!(
+            resolver.translate(key(KEY_A, pressed)),
+            keysym(Keysym::a, pressed)
+        );
+        assert_eq!(resolver.translate(shift(1)), None);
+        // released with the keysym of the press
+        assert_eq!(
+            resolver.translate(key(KEY_A, released)),
+            keysym(Keysym::a, released)
+        );
+        assert_eq!(
+            resolver.translate(key(KEY_A, pressed)),
+            keysym(Keysym::A, pressed)
+        );
+        // not pressed before
+        assert_eq!(resolver.translate(key(KEY_LEFTSHIFT, released)), None);
+    }
+
+    #[test]
+    fn lookup() {
+        let mut mapper = Mapper::new(Some(&us()), false).unwrap();
+        let shift = mapper.mod_mask(xkb::MOD_NAME_SHIFT).unwrap();
+        let a = KEY_A + EVDEV_OFFSET;
+        assert_eq!(mapper.lookup(Keysym::a.raw()), Some((a, 0)));
+        assert_eq!(mapper.lookup(Keysym::A.raw()), Some((a, shift)));
+        assert_eq!(mapper.lookup(Keysym::Cyrillic_zhe.raw()), None);
+
+        // shift held around the key
+        let (pressed, released) = (event::KEY_PRESSED, event::KEY_RELEASED);
+        let mapped = mapper.map(0, Keysym::A.raw(), pressed);
+        assert_eq!(
+            keys(&mapped.events),
+            [
+                (KEY_LEFTSHIFT, pressed),
+                (KEY_A, pressed),
+                (KEY_LEFTSHIFT, released)
+            ]
+        );
+        let mapped = mapper.map(0, Keysym::A.raw(), released);
+        assert_eq!(keys(&mapped.events), [(KEY_A, released)]);
+
+        // nothing to add it to
+        let mapped = mapper.map(0, Keysym::Cyrillic_zhe.raw(), pressed);
+        assert!(mapped.events.is_empty());
+        assert!(!mapped.keymap_changed);
+    }
+
+    #[test]
+    fn add_keysym() {
+        let mut mapper = Mapper::new(Some(&us()), true).unwrap();
+        let keycode = mapper.keymap.max_keycode().raw() + 1;
+        let zhe = Keysym::Cyrillic_zhe;
+
+        let mapped = mapper.map(0, zhe.raw(), event::KEY_PRESSED);
+        assert!(mapped.keymap_changed);
+        let added = keycode - EVDEV_OFFSET;
+        assert_eq!(keys(&mapped.events), [(added, event::KEY_PRESSED)]);
+        assert_eq!(mapper.lookup(zhe.raw()), Some((keycode, 0)));
+        // the rest is unchanged
+        assert_eq!(
+            mapper.lookup(Keysym::a.raw()),
+            Some((KEY_A + EVDEV_OFFSET, 0))
+        );
+
+        // the patched keymap resolves the new key
+        let mut resolver = Resolver::new(&mapper.keymap()).unwrap();
+        assert_eq!(
+            resolver.translate(key(added, event::KEY_PRESSED)),
+            keysym(zhe, event::KEY_PRESSED)
+        );
+    }
+}
@@ -1,3 +1,21 @@
+pub mod capture;
+pub mod client;
 pub mod config;
+pub mod dbus;
 pub mod dns;
+#[cfg(feature = "libei")]
+pub mod ei;
+pub mod emulation;
+pub mod event;
+#[cfg(feature = "xkb")]
+pub mod keysym;
+pub mod logging;
+pub mod poll;
+#[cfg(feature = "libei")]
+pub mod portal;
 pub mod protocol;
+pub mod recording;
+pub mod screen;
+pub mod server;
+pub mod stats;
+pub mod wayland;
@@ -0,0 +1,95 @@
+use std::{
+    error::Error,
+    fs::File,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+
+/// environment variable holding a default filter, e.g. `lan_mouse=debug`
+pub const ENV_VAR: &str = "LAN_MOUSE_LOG";
+
+static LOG_KEYS: AtomicBool = AtomicBool::new(false);
+
+/// Logging options, usually parsed from the command line:
+///
+/// - `-v` / `-vv`: log debug / trace messages
+/// - `--log-level <filter>`: explicit filter, overrides `LAN_MOUSE_LOG`
+/// - `--log-file <path>`: additionally write json logs to `path`
+/// - `--log-keys`: include key codes in event logs (off by default)
+#[derive(Default)]
+pub struct LogOptions {
+    pub filter: Option<String>,
+    pub verbosity: u8,
+    pub json_file: Option<String>,
+    pub log_keys: bool,
+}
+
+impl LogOptions {
+    /// parse the command line, rejecting anything but logging options
+    pub fn from_args() -> Result<Self, Box<dyn Error>> {
+        let mut opts = LogOptions::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if !opts.parse_arg(&arg, &mut args)? {
+                return Err(format!("unknown argument \"{}\"", arg).into());
+            }
+        }
+        Ok(opts)
+    }
+
+    /// Consume `arg` (and its value from `args`) if it is a logging option.
+    /// Returns whether `arg` was a logging option.
+    pub fn parse_arg(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> Result<bool, Box<dyn Error>> {
+        match arg {
+            "-v" => self.verbosity += 1,
+            "-vv" => self.verbosity += 2,
+            "--log-level" => self.filter = Some(args.next().ok_or("--log-level: missing filter")?),
+            "--log-file" => self.json_file = Some(args.next().ok_or("--log-file: missing path")?),
+            "--log-keys" => self.log_keys = true,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// whether key codes may be written to the logs
+pub fn log_keys() -> bool {
+    LOG_KEYS.load(Ordering::Relaxed)
+}
+
+/// Install the global tracing subscriber.
+pub fn init(opts: LogOptions) -> Result<(), Box<dyn Error>> {
+    let default = match opts.verbosity {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    let filter = match opts.filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None if opts.verbosity > 0 => EnvFilter::try_new(default)?,
+        None => EnvFilter::try_from_env(ENV_VAR).or_else(|_| EnvFilter::try_new(default))?,
+    };
+    let json = match opts.json_file {
+        Some(path) => Some(
+            fmt::layer()
+                .json()
+                .with_writer(Mutex::new(File::create(path)?)),
+        ),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(json)
+        .try_init()?;
+    LOG_KEYS.store(opts.log_keys, Ordering::Relaxed);
+    Ok(())
+}
@@ -0,0 +1,235 @@
+//! Readiness based event loop shared by client and server.
+//!
+//! Backends, sockets, timers, channels and termination signals all
+//! expose file descriptors, [`wait`] blocks until any of them is ready.
+//! Afterwards every component is dispatched without blocking.
+
+use std::{
+    io, mem,
+    os::unix::prelude::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::{
+        mpsc::{self, SendError, TryRecvError},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Readiness a file descriptor is waited for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interest {
+    Read,
+    Write,
+}
+
+/// `fds` waited for to become readable
+pub fn readable(fds: impl IntoIterator<Item = RawFd>) -> Vec<(RawFd, Interest)> {
+    fds.into_iter().map(|fd| (fd, Interest::Read)).collect()
+}
+
+/// Block until one of `fds` is ready or `timeout` elapsed.
+/// Returns early if interrupted by a signal.
+pub fn wait(fds: &[(RawFd, Interest)], timeout: Option<Duration>) -> io::Result<()> {
+    let mut fds: Vec<_> = fds
+        .iter()
+        .map(|(fd, interest)| libc::pollfd {
+            fd: *fd,
+            events: match interest {
+                Interest::Read => libc::POLLIN,
+                Interest::Write => libc::POLLOUT,
+            },
+            revents: 0,
+        })
+        .collect();
+    // round up, waking up before a deadline would only lead to waiting again
+    let timeout = timeout.map_or(-1, |t| {
+        t.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+    });
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Periodic timer, readable whenever it expired.
+pub struct Timer {
+    fd: OwnedFd,
+}
+
+impl Timer {
+    pub fn new(interval: Duration) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let interval = libc::timespec {
+            tv_sec: interval.as_secs() as libc::time_t,
+            tv_nsec: interval.subsec_nanos() as libc::c_long,
+        };
+        let spec = libc::itimerspec {
+            it_interval: interval,
+            it_value: interval,
+        };
+        if unsafe { libc::timerfd_settime(fd.as_raw_fd(), 0, &spec, ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Timer { fd })
+    }
+
+    /// number of expirations since the last call
+    pub fn expirations(&self) -> u64 {
+        let mut n = 0u64;
+        let len = mem::size_of::<u64>();
+        let read = unsafe { libc::read(self.fd.as_raw_fd(), ptr::addr_of_mut!(n).cast(), len) };
+        if read == len as isize {
+            n
+        } else {
+            0
+        }
+    }
+}
+
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// SIGINT and SIGTERM, readable once one of them arrived.
+///
+/// The signals are blocked and only delivered through the file descriptor,
+/// so it must be created before any thread is spawned:
+/// threads inherit the signal mask of their parent.
+pub struct Shutdown {
+    fd: OwnedFd,
+}
+
+impl Shutdown {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            if libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Shutdown {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// whether a termination signal arrived since the last call
+    pub fn requested(&self) -> bool {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let len = mem::size_of::<libc::signalfd_siginfo>();
+        let read = unsafe { libc::read(self.fd.as_raw_fd(), ptr::addr_of_mut!(info).cast(), len) };
+        read == len as isize
+    }
+}
+
+impl AsRawFd for Shutdown {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Channel whose receiving end can be waited for, see [`channel`].
+pub struct Sender<T> {
+    tx: mpsc::Sender<T>,
+    /// dropped after `tx`, so the receiver sees the disconnect when woken
+    wake: Wake,
+}
+
+/// Receiving end of a [`channel`],
+/// readable while messages are pending or all senders are gone.
+pub struct Receiver<T> {
+    rx: mpsc::Receiver<T>,
+    fd: Arc<OwnedFd>,
+}
+
+/// Create a channel for handing messages from other threads
+/// to the event loop, an eventfd signals pending messages.
+pub fn channel<T>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+    let (tx, rx) = mpsc::channel();
+    let tx = Sender {
+        tx,
+        wake: Wake(fd.clone()),
+    };
+    Ok((tx, Receiver { rx, fd }))
+}
+
+fn signal(fd: &OwnedFd) {
+    let n = 1u64;
+    let len = mem::size_of::<u64>();
+    // only fails if the counter would overflow, it is readable then anyway
+    unsafe { libc::write(fd.as_raw_fd(), ptr::addr_of!(n).cast(), len) };
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+        self.tx.send(t)?;
+        signal(&self.wake.0);
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            tx: self.tx.clone(),
+            wake: Wake(self.wake.0.clone()),
+        }
+    }
+}
+
+/// Wakes up the receiver when a sender is dropped, it may be the last one.
+struct Wake(Arc<OwnedFd>);
+
+impl Drop for Wake {
+    fn drop(&mut self) {
+        signal(&self.0);
+    }
+}
+
+impl<T> Receiver<T> {
+    /// next message without blocking
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.rx.try_recv() {
+            Err(TryRecvError::Empty) => {
+                // reset the readiness, then look again
+                // for messages sent in between
+                let mut n = 0u64;
+                let len = mem::size_of::<u64>();
+                unsafe { libc::read(self.fd.as_raw_fd(), ptr::addr_of_mut!(n).cast(), len) };
+                self.rx.try_recv()
+            }
+            r => r,
+        }
+    }
+}
+
+impl<T> AsRawFd for Receiver<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
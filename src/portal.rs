@@ -0,0 +1,87 @@
+//! Blocking helpers for xdg-desktop-portal.
+//!
+//! Most portal methods return a request handle immediately and report
+//! their result later through the `Response` signal of that request.
+
+use std::{collections::HashMap, error::Error};
+
+use serde::Serialize;
+use zbus::{
+    blocking,
+    zvariant::{DynamicType, OwnedObjectPath, OwnedValue, Value},
+};
+
+const DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PATH: &str = "/org/freedesktop/portal/desktop";
+
+/// Connection to the portal. Sessions are closed together with it.
+pub struct Portal {
+    conn: blocking::Connection,
+    token: u32,
+}
+
+impl Portal {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let conn = blocking::Connection::session()?;
+        Ok(Portal { conn, token: 0 })
+    }
+
+    pub fn proxy(&self, interface: &'static str) -> zbus::Result<blocking::Proxy<'static>> {
+        blocking::Proxy::new(&self.conn, DESTINATION, PATH, interface)
+    }
+
+    /// unique token for the `handle_token` / `session_handle_token` options
+    pub fn token(&mut self) -> String {
+        self.token += 1;
+        format!("lan_mouse_{}_{}", std::process::id(), self.token)
+    }
+
+    /// Call `method` with `handle_token` set to `token` in its options
+    /// and wait for the response.
+    pub fn request<B>(
+        &self,
+        proxy: &blocking::Proxy<'_>,
+        method: &str,
+        token: &str,
+        body: &B,
+    ) -> Result<HashMap<String, OwnedValue>, Box<dyn Error>>
+    where
+        B: Serialize + DynamicType,
+    {
+        let sender = self
+            .conn
+            .unique_name()
+            .ok_or("not connected to the session bus")?
+            .trim_start_matches(':')
+            .replace('.', "_");
+        let path = format!("{}/request/{}/{}", PATH, sender, token);
+        let request = blocking::Proxy::new(
+            &self.conn,
+            DESTINATION,
+            path,
+            "org.freedesktop.portal.Request",
+        )?;
+        // subscribe before calling, the response may arrive immediately
+        let mut responses = request.receive_signal("Response")?;
+        proxy.call_method(method, body)?;
+        let response = responses.next().ok_or("portal request aborted")?;
+        let (code, results): (u32, HashMap<String, OwnedValue>) = response.body()?;
+        match code {
+            0 => Ok(results),
+            1 => Err(format!("{} cancelled by the user", method).into()),
+            _ => Err(format!("{} failed", method).into()),
+        }
+    }
+}
+
+/// `session_handle` of a `CreateSession` response,
+/// some portals report it as string rather than object path
+pub fn session_handle(
+    results: &HashMap<String, OwnedValue>,
+) -> Result<OwnedObjectPath, Box<dyn Error>> {
+    match results.get("session_handle").map(|v| &**v) {
+        Some(Value::ObjectPath(path)) => Ok(path.clone().into()),
+        Some(Value::Str(path)) => Ok(OwnedObjectPath::try_from(path.as_str())?),
+        _ => Err("no session handle".into()),
+    }
+}
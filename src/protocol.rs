@@ -1,21 +1,48 @@
-use crate::config::{self, Config};
+use crate::config::{self, Config, KeyboardMode};
 use crate::dns;
-use memmap::Mmap;
+use crate::event::{self, Capabilities, PointerEvent};
+use crate::logging;
+use crate::poll::{self, Interest};
+use crate::screen;
+use crate::stats::Stats;
 use std::{
-    collections::HashMap,
-    io::prelude::*,
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::Display,
     net::TcpListener,
+    os::unix::prelude::{AsRawFd, RawFd},
     process::exit,
-    sync::{Arc, RwLock},
-    thread,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use wayland_client::{
-    protocol::{wl_keyboard, wl_pointer},
-    WEnum,
+use lan_mouse_proto::datagram::{self, Ack, Reliable, Sequenced};
+use tracing::{debug, debug_span, error, info, instrument, trace, warn};
+
+use std::net::{SocketAddr, UdpSocket};
+
+mod batch;
+mod handshake;
+mod reactor;
+mod reliable;
+mod sequence;
+
+pub use handshake::local_info;
+pub use lan_mouse_proto::{
+    handshake::PeerInfo,
+    message::{keymap_hash, DataRequest, ErrorCode, Push, RemoteError},
+    Decode, DecodeError, Encode, PROTOCOL_VERSION,
 };
+pub use reactor::Response;
 
-use std::net::{SocketAddr, TcpStream, UdpSocket};
+use batch::{Batch, Pending};
+use reactor::{Outcome, Reactor, REQUEST_TIMEOUT};
+use reliable::{Inbox, Outbox};
+use sequence::{Order, Sequencer, Window};
 
 trait Resolve {
     fn resolve(&self) -> Option<SocketAddr>;
@@ -29,274 +56,1101 @@ impl Resolve for Option<config::Client> {
         };
         let ip = match client.ip {
             Some(ip) => ip,
-            None => dns::resolve(&client.host_name).unwrap(),
+            None => match dns::resolve(&client.host_name) {
+                Ok(ip) => ip,
+                Err(e) => {
+                    error!("{}", e);
+                    return None;
+                }
+            },
         };
         Some(SocketAddr::new(ip, client.port.unwrap_or(42069)))
     }
 }
 
-struct ClientAddrs {
-    left: Option<SocketAddr>,
-    right: Option<SocketAddr>,
-    _top: Option<SocketAddr>,
-    _bottom: Option<SocketAddr>,
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Position {
+    Left,
+    Right,
+    Top,
+    Bottom,
 }
 
-pub struct Connection {
-    udp_socket: UdpSocket,
-    client: ClientAddrs,
-    offer_data: Arc<RwLock<HashMap<DataRequest, Mmap>>>,
-}
+#[derive(Debug, Clone)]
+pub struct InvalidPositionError(String);
 
-pub trait Encode {
-    fn encode(&self) -> Vec<u8>;
-}
+impl Error for InvalidPositionError {}
 
-pub trait Decode {
-    fn decode(buf: Vec<u8>) -> Self;
+impl Display for InvalidPositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid position \"{}\"", self.0)
+    }
 }
 
-impl Encode for wl_pointer::Event {
-    fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        match *self {
-            Self::Motion {
-                time: t,
-                surface_x: x,
-                surface_y: y,
-            } => {
-                buf.push(0u8);
-                buf.extend_from_slice(t.to_ne_bytes().as_ref());
-                buf.extend_from_slice(x.to_ne_bytes().as_ref());
-                buf.extend_from_slice(y.to_ne_bytes().as_ref());
-            }
-            Self::Button {
-                serial: _,
-                time: t,
-                button: b,
-                state: s,
-            } => {
-                buf.push(1u8);
-                buf.extend_from_slice(t.to_ne_bytes().as_ref());
-                buf.extend_from_slice(b.to_ne_bytes().as_ref());
-                buf.push(u32::from(s) as u8);
-            }
-            Self::Axis {
-                time: t,
-                axis: a,
-                value: v,
-            } => {
-                buf.push(2u8);
-                buf.extend_from_slice(t.to_ne_bytes().as_ref());
-                buf.push(u32::from(a) as u8);
-                buf.extend_from_slice(v.to_ne_bytes().as_ref());
-            }
-            Self::Frame {} => {
-                buf.push(3u8);
-            }
-            _ => todo!(),
+impl FromStr for Position {
+    type Err = InvalidPositionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "left" => Ok(Self::Left),
+            "right" => Ok(Self::Right),
+            "top" => Ok(Self::Top),
+            "bottom" => Ok(Self::Bottom),
+            _ => Err(InvalidPositionError(s.into())),
         }
-        buf
     }
 }
 
-impl Encode for wl_keyboard::Event {
-    fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
-        match self {
-            Self::Key {
-                serial: _,
-                time: t,
-                key: k,
-                state: s,
-            } => {
-                buf.push(4u8);
-                buf.extend_from_slice(t.to_ne_bytes().as_ref());
-                buf.extend_from_slice(k.to_ne_bytes().as_ref());
-                buf.push(u32::from(*s) as u8);
-            }
-            Self::Modifiers {
-                serial: _,
-                mods_depressed,
-                mods_latched,
-                mods_locked,
-                group,
-            } => {
-                buf.push(5u8);
-                buf.extend_from_slice(mods_depressed.to_ne_bytes().as_ref());
-                buf.extend_from_slice(mods_latched.to_ne_bytes().as_ref());
-                buf.extend_from_slice(mods_locked.to_ne_bytes().as_ref());
-                buf.extend_from_slice(group.to_ne_bytes().as_ref());
-            }
-            _ => todo!(),
-        }
-        buf
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Left => "left",
+            Self::Right => "right",
+            Self::Top => "top",
+            Self::Bottom => "bottom",
+        };
+        write!(f, "{}", s)
     }
 }
 
-pub enum Event {
-    Pointer(wl_pointer::Event),
-    Keyboard(wl_keyboard::Event),
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub enabled: bool,
+    /// set once the first packet or request of this peer arrived
+    pub connected: bool,
+    /// what the peer can emulate, `None` until queried
+    pub capabilities: Option<Capabilities>,
+    /// largest scale factor of the peer's outputs, 1 until known
+    pub scale: f64,
+    /// configured locally or requested by the peer
+    pub keyboard: KeyboardMode,
+    /// keymap used for the key events of this peer
+    pub keymap: KeymapState,
 }
 
-impl Decode for Event {
-    fn decode(buf: Vec<u8>) -> Self {
-        match buf[0] {
-            0 => Self::Pointer(wl_pointer::Event::Motion {
-                time: u32::from_ne_bytes(buf[1..5].try_into().unwrap()),
-                surface_x: f64::from_ne_bytes(buf[5..13].try_into().unwrap()),
-                surface_y: f64::from_ne_bytes(buf[13..21].try_into().unwrap()),
-            }),
-            1 => Self::Pointer(wl_pointer::Event::Button {
-                serial: 0,
-                time: (u32::from_ne_bytes(buf[1..5].try_into().unwrap())),
-                button: (u32::from_ne_bytes(buf[5..9].try_into().unwrap())),
-                state: (WEnum::Value(wl_pointer::ButtonState::try_from(buf[9] as u32).unwrap())),
-            }),
-            2 => Self::Pointer(wl_pointer::Event::Axis {
-                time: (u32::from_ne_bytes(buf[1..5].try_into().unwrap())),
-                axis: (WEnum::Value(wl_pointer::Axis::try_from(buf[5] as u32).unwrap())),
-                value: (f64::from_ne_bytes(buf[6..14].try_into().unwrap())),
-            }),
-            3 => Self::Pointer(wl_pointer::Event::Frame {}),
-            4 => Self::Keyboard(wl_keyboard::Event::Key {
-                serial: 0,
-                time: u32::from_ne_bytes(buf[1..5].try_into().unwrap()),
-                key: u32::from_ne_bytes(buf[5..9].try_into().unwrap()),
-                state: WEnum::Value(wl_keyboard::KeyState::try_from(buf[9] as u32).unwrap()),
-            }),
-            5 => Self::Keyboard(wl_keyboard::Event::Modifiers {
-                serial: 0,
-                mods_depressed: u32::from_ne_bytes(buf[1..5].try_into().unwrap()),
-                mods_latched: u32::from_ne_bytes(buf[5..9].try_into().unwrap()),
-                mods_locked: u32::from_ne_bytes(buf[9..13].try_into().unwrap()),
-                group: u32::from_ne_bytes(buf[13..17].try_into().unwrap()),
-            }),
-            _ => panic!("protocol violation"),
-        }
+/// Where the keymap for the key events of a peer comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeymapState {
+    /// the keymap of the peer is not used
+    #[default]
+    Unused,
+    /// waiting for the keymap of the peer, key events are dropped
+    Pending,
+    /// waiting for the keymap of the peer, the local default keymap is used meanwhile
+    Fallback,
+    /// the keymap of the peer is installed
+    Received,
+}
+
+impl Display for KeymapState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Unused => "unused",
+            Self::Pending => "pending",
+            Self::Fallback => "fallback",
+            Self::Received => "received",
+        };
+        write!(f, "{}", s)
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
-pub enum DataRequest {
-    KeyMap,
+/// Changes to the peer state, delivered to every subscriber
+#[derive(Clone, Debug)]
+pub enum Notification {
+    PeerConnected(Position),
+    PeerDisconnected(Position),
+    FocusMoved(Option<Position>),
+    /// the peer offers a new keymap with the given [`keymap_hash`]
+    KeyMapChanged(Position, u64),
+    /// the peer fetched our clipboard contents
+    ClipboardSynced(Position),
+}
+
+#[derive(Debug, Clone)]
+pub enum PeerError {
+    NoSuchPeer(Position),
+    Disabled(Position),
 }
 
-impl From<u32> for DataRequest {
-    fn from(idx: u32) -> Self {
-        match idx {
-            0 => Self::KeyMap,
-            _ => panic!("invalid enum value"),
+impl Error for PeerError {}
+
+impl Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoSuchPeer(pos) => write!(f, "no peer configured at {}", pos),
+            Self::Disabled(pos) => write!(f, "peer at {} is disabled", pos),
         }
     }
 }
 
-impl From<[u8; 4]> for DataRequest {
-    fn from(buf: [u8; 4]) -> Self {
-        DataRequest::from(u32::from_ne_bytes(buf))
+struct PeerTable {
+    peers: HashMap<Position, Peer>,
+    /// received through the handshake
+    infos: HashMap<Position, PeerInfo>,
+    active: Option<Position>,
+    subscribers: Vec<Sender<Notification>>,
+}
+
+impl PeerTable {
+    fn notify(&mut self, n: Notification) {
+        self.subscribers.retain(|s| s.send(n.clone()).is_ok());
+    }
+
+    fn position_of(&self, addr: SocketAddr) -> Option<Position> {
+        // the port may differ behind NAT,
+        // so fall back to matching the ip only
+        let exact = self.peers.iter().find(|(_, p)| p.addr == addr);
+        exact
+            .or_else(|| self.peers.iter().find(|(_, p)| p.addr.ip() == addr.ip()))
+            .map(|(pos, _)| *pos)
     }
 }
 
-impl From<DataRequest> for u32 {
-    fn from(d: DataRequest) -> Self {
-        match d {
-            DataRequest::KeyMap => 0,
+/// Shared handle to the peers known to a [`Connection`].
+///
+/// Clones refer to the same state, so it can be handed to
+/// other threads like the D-Bus service.
+#[derive(Clone)]
+pub struct Peers(Arc<RwLock<PeerTable>>);
+
+impl Peers {
+    fn new(peers: HashMap<Position, Peer>) -> Self {
+        Peers(Arc::new(RwLock::new(PeerTable {
+            peers,
+            infos: HashMap::new(),
+            active: None,
+            subscribers: vec![],
+        })))
+    }
+
+    pub fn list(&self) -> Vec<(Position, Peer)> {
+        let table = self.0.read().unwrap();
+        table.peers.iter().map(|(pos, p)| (*pos, *p)).collect()
+    }
+
+    pub fn get(&self, pos: Position) -> Option<Peer> {
+        self.0.read().unwrap().peers.get(&pos).copied()
+    }
+
+    /// what the peer told about itself in the handshake
+    pub fn info(&self, pos: Position) -> Option<PeerInfo> {
+        self.0.read().unwrap().infos.get(&pos).cloned()
+    }
+
+    /// Protocol version of the peer at `pos` from the handshake,
+    /// ours while not known yet.
+    ///
+    /// Peers older than the handshake only answer
+    /// [`DataRequest::Capabilities`], which carries no version,
+    /// so they get version 1, the oldest encoding of the events.
+    pub fn version(&self, pos: Position) -> u32 {
+        let table = self.0.read().unwrap();
+        match (table.infos.get(&pos), table.peers.get(&pos)) {
+            (Some(info), _) => info.version,
+            (None, Some(peer)) if peer.capabilities.is_some() => 1,
+            _ => PROTOCOL_VERSION,
         }
     }
-}
 
-fn handle_request(data: &Arc<RwLock<HashMap<DataRequest, Mmap>>>, mut stream: TcpStream) {
-    let mut buf = [0u8; 4];
-    stream.read_exact(&mut buf).unwrap();
-    match DataRequest::from(buf) {
-        DataRequest::KeyMap => {
-            let data = data.read().unwrap();
-            let buf = data.get(&DataRequest::KeyMap);
-            match buf {
-                None => {
-                    stream.write(&0u32.to_ne_bytes()).unwrap();
-                }
-                Some(buf) => {
-                    stream.write(&buf[..].len().to_ne_bytes()).unwrap();
-                    stream.write(&buf[..]).unwrap();
+    /// the peer currently receiving our events
+    pub fn active(&self) -> Option<Position> {
+        self.0.read().unwrap().active
+    }
+
+    /// direct all following events to the peer at `pos`
+    pub fn switch_to(&self, pos: Position) -> Result<(), PeerError> {
+        let mut table = self.0.write().unwrap();
+        match table.peers.get(&pos) {
+            None => return Err(PeerError::NoSuchPeer(pos)),
+            Some(p) if !p.enabled => return Err(PeerError::Disabled(pos)),
+            Some(_) => {}
+        }
+        if table.active != Some(pos) {
+            info!(position = %pos, "focus moved");
+            table.active = Some(pos);
+            table.notify(Notification::FocusMoved(Some(pos)));
+        }
+        Ok(())
+    }
+
+    /// stop sending events to any peer
+    pub fn release(&self) {
+        let mut table = self.0.write().unwrap();
+        if let Some(pos) = table.active.take() {
+            info!(position = %pos, "released");
+            table.notify(Notification::FocusMoved(None));
+        }
+    }
+
+    pub fn set_enabled(&self, pos: Position, enabled: bool) -> Result<(), PeerError> {
+        let mut table = self.0.write().unwrap();
+        let peer = match table.peers.get_mut(&pos) {
+            Some(peer) => peer,
+            None => return Err(PeerError::NoSuchPeer(pos)),
+        };
+        info!(position = %pos, enabled, "peer state changed");
+        peer.enabled = enabled;
+        if !enabled && peer.connected {
+            peer.connected = false;
+            table.notify(Notification::PeerDisconnected(pos));
+        }
+        if !enabled && table.active == Some(pos) {
+            table.active = None;
+            table.notify(Notification::FocusMoved(None));
+        }
+        Ok(())
+    }
+
+    /// receive a [`Notification`] for every future state change
+    pub fn subscribe(&self) -> Receiver<Notification> {
+        let (tx, rx) = mpsc::channel();
+        self.0.write().unwrap().subscribers.push(tx);
+        rx
+    }
+
+    fn set_info(&self, pos: Position, info: PeerInfo) {
+        let mut table = self.0.write().unwrap();
+        if let Some(peer) = table.peers.get_mut(&pos) {
+            peer.capabilities = Some(info.capabilities);
+            peer.scale = screen::scale(&info.outputs);
+            if info.keyboard == KeyboardMode::Keysym && peer.keyboard != KeyboardMode::Keysym {
+                if cfg!(feature = "xkb") {
+                    info!(position = %pos, "peer requested keysym mode");
+                    peer.keyboard = KeyboardMode::Keysym;
+                } else {
+                    warn!(position = %pos, "peer requested keysym mode, which is not compiled in");
                 }
             }
-            stream.flush().unwrap();
+            table.infos.insert(pos, info);
+        }
+    }
+
+    pub fn set_keymap_state(&self, pos: Position, state: KeymapState) {
+        if let Some(peer) = self.0.write().unwrap().peers.get_mut(&pos) {
+            if peer.keymap != state {
+                info!(position = %pos, keymap = %state, "keymap state changed");
+                peer.keymap = state;
+            }
         }
     }
+
+    fn set_capabilities(&self, pos: Position, capabilities: Capabilities) {
+        if let Some(peer) = self.0.write().unwrap().peers.get_mut(&pos) {
+            peer.capabilities = Some(capabilities);
+        }
+    }
+
+    /// position of the peer with the given address, enabled or not
+    fn position(&self, addr: SocketAddr) -> Option<Position> {
+        self.0.read().unwrap().position_of(addr)
+    }
+
+    /// look up the sender of a packet, marking it as connected.
+    /// Returns `None` for unknown or disabled peers.
+    fn seen(&self, addr: SocketAddr) -> Option<Position> {
+        let mut table = self.0.write().unwrap();
+        let pos = table.position_of(addr)?;
+        let peer = table.peers.get_mut(&pos).unwrap();
+        if !peer.enabled {
+            return None;
+        }
+        if !peer.connected {
+            info!(position = %pos, %addr, "peer connected");
+            peer.connected = true;
+            table.notify(Notification::PeerConnected(pos));
+        }
+        Some(pos)
+    }
+
+    fn notify(&self, n: Notification) {
+        self.0.write().unwrap().notify(n);
+    }
+}
+
+/// in milliseconds, see [`config::Config::batch_delay`]
+const DEFAULT_BATCH_DELAY: u64 = 4;
+
+/// largest UDP payload, nothing is ever truncated
+const MAX_DATAGRAM: usize = 65535;
+
+/// received events kept until taken by [`Connection::receive_event`]
+const MAX_INCOMING: usize = 1024;
+
+/// data served on the control channels
+type Offers = Arc<RwLock<HashMap<DataRequest, Box<dyn AsRef<[u8]> + Send + Sync>>>>;
+
+/// Answer to a request running in the background, see [`Connection::request`].
+/// Dropping it cancels the request.
+pub struct Reply(Arc<Mutex<Option<Response>>>);
+
+impl Reply {
+    /// the answer once the request finished, `Ok(None)` if nothing was offered
+    pub fn try_take(&self) -> Option<Response> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// what to do once one of our requests finished
+enum Then {
+    Reply(Arc<Mutex<Option<Response>>>),
+    Handshake(Position),
+    Capabilities(Position),
+}
+
+impl Then {
+    fn cancelled(&self) -> bool {
+        matches!(self, Then::Reply(slot) if Arc::strong_count(slot) == 1)
+    }
+}
+
+pub struct Connection {
+    udp_socket: UdpSocket,
+    /// control channels to the peers
+    reactor: Mutex<Reactor<Then>>,
+    peers: Peers,
+    stats: Stats,
+    offer_data: Offers,
+    /// largest scale factor of the local outputs
+    scale: RwLock<f64>,
+    /// hash of the offered keymap
+    keymap_hash: RwLock<Option<u64>>,
+    /// identifies our datagrams, see [`sequence`] and [`reliable`]
+    session: u32,
+    /// numbers the datagrams to each peer
+    sequencers: Mutex<HashMap<Position, Sequencer>>,
+    /// datagrams received from each peer
+    windows: Mutex<HashMap<Position, Window>>,
+    /// reliable events sent to each peer
+    outboxes: Mutex<HashMap<Position, Outbox>>,
+    /// reliable events received from each peer,
+    /// `None` for the ones that could not be decoded
+    inboxes: Mutex<HashMap<Position, Inbox<Option<event::Event>>>>,
+    /// received events not yet taken by [`Connection::receive_event`]
+    incoming: Mutex<VecDeque<event::Event>>,
+    /// pointer events of the current frame, see [`batch`]
+    batch: Mutex<Batch>,
+    /// longest time pointer events are held back, zero disables batching
+    batch_delay: Duration,
+    /// reused for every event sent and received, no allocations on the hot path
+    encode_buf: Mutex<Vec<u8>>,
+    reliable_buf: Mutex<Vec<u8>>,
+    send_buf: Mutex<Vec<u8>>,
+    recv_buf: Mutex<Vec<u8>>,
+}
+
+/// Answer a request of the peer at `addr`.
+fn serve(
+    data: &Offers,
+    peers: &Peers,
+    stats: &Stats,
+    addr: SocketAddr,
+    req: DataRequest,
+    payload: Vec<u8>,
+) -> Result<Option<Vec<u8>>, RemoteError> {
+    let _span = debug_span!("request", peer = %addr).entered();
+    let peer = peers.seen(addr);
+    if req == DataRequest::Handshake {
+        let info = PeerInfo::decode(&payload)
+            .map_err(|e| RemoteError::new(ErrorCode::InvalidPayload, e))?;
+        info!(
+            hostname = info.hostname,
+            version = info.version,
+            "handshake"
+        );
+        if let Some(pos) = peer {
+            peers.set_info(pos, info);
+        }
+    }
+    let data = data.read().unwrap();
+    let buf = match data.get(&req) {
+        Some(buf) => (**buf).as_ref().to_vec(),
+        None => {
+            debug!(request = ?req, "no data offered");
+            return Ok(None);
+        }
+    };
+    debug!(request = ?req, len = buf.len(), "serving data");
+    match (req, peer) {
+        (DataRequest::KeyMap, Some(pos)) => stats.keymap_request(pos),
+        (DataRequest::Clipboard, Some(pos)) => peers.notify(Notification::ClipboardSynced(pos)),
+        _ => {}
+    }
+    Ok(Some(buf))
+}
+
+/// Key, button, modifier and control events change state on the peer
+/// and must not get lost, see [`reliable`].
+fn is_reliable(e: &event::Event) -> bool {
+    matches!(
+        e,
+        event::Event::Keyboard(_)
+            | event::Event::Pointer(PointerEvent::Button { .. })
+            | event::Event::Control(_)
+    )
+}
+
+/// Motion and scrolling are sent together per frame, see [`batch`].
+fn is_batched(e: &event::Event) -> bool {
+    matches!(
+        e,
+        event::Event::Pointer(
+            PointerEvent::Motion { .. }
+                | PointerEvent::Absolute { .. }
+                | PointerEvent::Axis { .. }
+                | PointerEvent::Frame
+        )
+    )
+}
+
+/// random enough to tell restarts apart
+fn session_id() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    nanos ^ std::process::id().rotate_left(16)
 }
 
 impl Connection {
     pub fn new(config: Config) -> Connection {
-        let clients = ClientAddrs {
-            left: config.client.left.resolve(),
-            right: config.client.right.resolve(),
-            _top: config.client.top.resolve(),
-            _bottom: config.client.bottom.resolve(),
-        };
-        let data: Arc<RwLock<HashMap<DataRequest, Mmap>>> = Arc::new(RwLock::new(HashMap::new()));
-        let thread_data = data.clone();
+        let mut peers = HashMap::new();
+        for (pos, client) in [
+            (Position::Left, config.client.left),
+            (Position::Right, config.client.right),
+            (Position::Top, config.client.top),
+            (Position::Bottom, config.client.bottom),
+        ] {
+            let mut keyboard = client.as_ref().and_then(|c| c.keyboard).unwrap_or_default();
+            if keyboard == KeyboardMode::Keysym && !cfg!(feature = "xkb") {
+                warn!(position = %pos, "keysym mode not compiled in, using keycodes");
+                keyboard = KeyboardMode::Keycode;
+            }
+            if let Some(addr) = client.resolve() {
+                info!(position = %pos, %addr, ?keyboard, "configured peer");
+                let peer = Peer {
+                    addr,
+                    enabled: true,
+                    connected: false,
+                    capabilities: None,
+                    scale: 1.0,
+                    keyboard,
+                    keymap: KeymapState::Unused,
+                };
+                peers.insert(pos, peer);
+            }
+        }
+        let peers = Peers::new(peers);
+        let data: Offers = Arc::new(RwLock::new(HashMap::new()));
+        let stats = Stats::new();
         let port = config.port.unwrap_or(42069);
+        let batch_delay = Duration::from_millis(config.batch_delay.unwrap_or(DEFAULT_BATCH_DELAY));
         let listen_addr = SocketAddr::new("0.0.0.0".parse().unwrap(), port);
-        thread::spawn(move || {
-            let sock = TcpListener::bind(listen_addr).unwrap();
-            for stream in sock.incoming() {
-                if let Ok(stream) = stream {
-                    handle_request(&thread_data, stream);
-                }
-            }
-        });
+        let listener = TcpListener::bind(listen_addr)
+            .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+            .map_err(|e| error!(%listen_addr, "could not bind tcp listener: {}", e))
+            .ok();
         let sock = UdpSocket::bind(listen_addr);
         let sock = match sock {
             Ok(sock) => sock,
             Err(e) => match e.kind() {
                 std::io::ErrorKind::AddrInUse => {
-                    eprintln!("Server already running on port {}", port);
+                    error!("Server already running on port {}", port);
                     exit(1);
                 }
                 _ => panic!("{}", e),
             },
         };
-        let c = Connection {
+        if let Err(e) = sock.set_nonblocking(true) {
+            panic!("{}", e);
+        }
+        info!(%listen_addr, "listening for events");
+        Connection {
             udp_socket: sock,
-            client: clients,
+            reactor: Mutex::new(Reactor::new(listener, port)),
+            peers,
+            stats,
             offer_data: data,
+            scale: RwLock::new(1.0),
+            keymap_hash: RwLock::new(None),
+            session: session_id(),
+            sequencers: Mutex::new(HashMap::new()),
+            windows: Mutex::new(HashMap::new()),
+            outboxes: Mutex::new(HashMap::new()),
+            inboxes: Mutex::new(HashMap::new()),
+            incoming: Mutex::new(VecDeque::new()),
+            batch: Mutex::new(Batch::new()),
+            batch_delay,
+            encode_buf: Mutex::new(Vec::new()),
+            reliable_buf: Mutex::new(Vec::new()),
+            send_buf: Mutex::new(Vec::new()),
+            recv_buf: Mutex::new(vec![0; MAX_DATAGRAM]),
+        }
+    }
+
+    /// File descriptors of the event socket and the control channels
+    /// with the readiness to wait for, see [`Connection::dispatch`]
+    /// and [`Connection::receive_event`].
+    /// Changes as requests come and go, so ask again on every iteration.
+    pub fn fds(&self) -> Vec<(RawFd, Interest)> {
+        let mut fds = vec![(self.udp_socket.as_raw_fd(), Interest::Read)];
+        fds.extend(self.reactor.lock().unwrap().interest());
+        fds
+    }
+
+    /// time until the next request times out or is retried,
+    /// the next event is retransmitted or pending pointer events are sent
+    pub fn timeout(&self) -> Option<Duration> {
+        let outboxes = self.outboxes.lock().unwrap();
+        let retransmit = outboxes.values().filter_map(|o| o.deadline());
+        let batch = self.batch.lock().unwrap().deadline();
+        let now = Instant::now();
+        let deadline = retransmit.chain(batch).min();
+        let deadline = deadline.map(|at| at.saturating_duration_since(now));
+        let requests = self.reactor.lock().unwrap().timeout();
+        deadline.into_iter().chain(requests).min()
+    }
+
+    /// Advance the connection without blocking: take in acknowledgements
+    /// and events, retransmit unacknowledged events, serve requests of peers
+    /// and handle their pushes and the answers to ours.
+    pub fn dispatch(&self) {
+        self.receive();
+        self.retransmit();
+        {
+            let mut batch = self.batch.lock().unwrap();
+            if batch.deadline().is_some_and(|at| at <= Instant::now()) {
+                self.send_batch(&mut batch);
+            }
+        }
+        let mut reactor = self.reactor.lock().unwrap();
+        reactor.retain(|then| !then.cancelled());
+        let outcomes = reactor.dispatch(|addr, req, payload| {
+            serve(
+                &self.offer_data,
+                &self.peers,
+                &self.stats,
+                addr,
+                req,
+                payload,
+            )
+        });
+        for outcome in outcomes {
+            let (then, response) = match outcome {
+                Outcome::Opened(addr) => {
+                    // tell the peer about our keymap right away
+                    let hash = *self.keymap_hash.read().unwrap();
+                    if let (Some(_), Some(hash)) = (self.peers.seen(addr), hash) {
+                        reactor.push(addr, Push::KeyMapChanged(hash));
+                    }
+                    continue;
+                }
+                Outcome::Closed(addr) => {
+                    debug!(%addr, "control channel closed");
+                    continue;
+                }
+                Outcome::Pushed(addr, push) => {
+                    self.pushed(addr, push);
+                    continue;
+                }
+                Outcome::Finished(then, response) => (then, response),
+            };
+            match then {
+                Then::Reply(slot) => *slot.lock().unwrap() = Some(response),
+                Then::Handshake(pos) => {
+                    if !self.complete_handshake(pos, response) {
+                        // peers older than the handshake only report capabilities
+                        if let Some(peer) = self.peers.get(pos) {
+                            let then = Then::Capabilities(pos);
+                            reactor.request(
+                                peer.addr,
+                                DataRequest::Capabilities,
+                                None,
+                                false,
+                                then,
+                            );
+                        }
+                    }
+                }
+                Then::Capabilities(pos) => {
+                    let capabilities = match response {
+                        Ok(Some(data)) if data.len() == 4 => {
+                            Capabilities::from(u32::from_ne_bytes(data[..].try_into().unwrap()))
+                        }
+                        Ok(_) => Capabilities::ALL,
+                        Err(e) => {
+                            debug!(position = %pos, "could not query capabilities: {}", e);
+                            continue;
+                        }
+                    };
+                    info!(position = %pos, ?capabilities, "peer capabilities");
+                    self.peers.set_capabilities(pos, capabilities);
+                }
+            }
+        }
+    }
+
+    /// Wait for the control channels up to `timeout`, then dispatch them.
+    /// Serves requests without an event loop, e.g. while blocking elsewhere.
+    pub fn drive(&self, timeout: Duration) {
+        let fds = self.reactor.lock().unwrap().interest();
+        let timeout = self.timeout().map_or(timeout, |t| t.min(timeout));
+        if let Err(e) = poll::wait(&fds, Some(timeout)) {
+            warn!("poll failed: {}", e);
+        }
+        self.dispatch();
+    }
+
+    pub fn peers(&self) -> Peers {
+        self.peers.clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+
+    pub fn offer_data<D>(&self, req: DataRequest, d: D)
+    where
+        D: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        debug!(request = ?req, len = d.as_ref().len(), "offering data");
+        self.offer_data.write().unwrap().insert(req, Box::new(d));
+        if req == DataRequest::Clipboard {
+            self.push(Push::ClipboardChanged);
+        }
+    }
+
+    /// Offer `keymap` and tell the peers about it, unless it is unchanged.
+    pub fn offer_keymap<D>(&self, keymap: D)
+    where
+        D: AsRef<[u8]> + Send + Sync + 'static,
+    {
+        let hash = keymap_hash(keymap.as_ref());
+        if self.keymap_hash.write().unwrap().replace(hash) == Some(hash) {
+            // compositors send the keymap again on every keyboard enter
+            debug!(hash = format!("{:016x}", hash), "keymap unchanged");
+            return;
+        }
+        self.offer_data(DataRequest::KeyMap, keymap);
+        self.push(Push::KeyMapChanged(hash));
+    }
+
+    /// Make sure the peer at `pos` knows the offered keymap.
+    /// Its hash is pushed whenever a control channel opens and whenever
+    /// the keymap changes, so this only opens a channel if there is none.
+    /// Peers that could not be reached are tried again on the next call,
+    /// e.g. when the peer is entered the next time.
+    pub fn announce_keymap(&self, pos: Position) {
+        if self.keymap_hash.read().unwrap().is_none() {
+            return;
+        }
+        if let Some(peer) = self.peers.get(pos).filter(|p| p.enabled) {
+            self.reactor.lock().unwrap().connect(peer.addr);
+        }
+    }
+
+    /// Send `push` to every enabled peer, opening channels where needed.
+    fn push(&self, push: Push) {
+        let mut reactor = self.reactor.lock().unwrap();
+        for (_, peer) in self.peers.list() {
+            if peer.enabled {
+                reactor.push(peer.addr, push);
+                reactor.connect(peer.addr);
+            }
+        }
+    }
+
+    /// handle a change the peer at `addr` told us about
+    fn pushed(&self, addr: SocketAddr, push: Push) {
+        let pos = match self.peers.seen(addr) {
+            Some(pos) => pos,
+            None => {
+                debug!(%addr, ?push, "ignoring push of unknown or disabled peer");
+                return;
+            }
         };
-        c
+        match push {
+            Push::KeyMapChanged(hash) => {
+                info!(position = %pos, hash = format!("{:016x}", hash), "peer keymap changed");
+                self.peers.notify(Notification::KeyMapChanged(pos, hash));
+            }
+            // nothing is done with the clipboard yet
+            Push::ClipboardChanged => {
+                debug!(position = %pos, "ignoring clipboard change of peer")
+            }
+        }
     }
 
-    pub fn offer_data(&self, req: DataRequest, d: Mmap) {
-        self.offer_data.write().unwrap().insert(req, d);
+    /// Request `req` from the peer at `pos` in the background.
+    /// With `retry`, failed attempts and empty answers are repeated,
+    /// backing off exponentially, until the peer offers something.
+    pub fn request(&self, pos: Position, req: DataRequest, retry: bool) -> Option<Reply> {
+        let addr = self.peers.get(pos)?.addr;
+        let slot = Arc::new(Mutex::new(None));
+        let then = Then::Reply(slot.clone());
+        self.reactor
+            .lock()
+            .unwrap()
+            .request(addr, req, None, retry, then);
+        Some(Reply(slot))
     }
 
+    /// Request `req` from the server, blocking until it is answered.
+    #[instrument(skip(self))]
     pub fn receive_data(&self, req: DataRequest) -> Option<Vec<u8>> {
-        let mut sock = TcpStream::connect(self.client.left.unwrap()).unwrap();
-        sock.write(&u32::from(req).to_ne_bytes()).unwrap();
-        sock.flush().unwrap();
-        let mut buf = [0u8; 8];
-        sock.read_exact(&mut buf[..]).unwrap();
-        let len = usize::from_ne_bytes(buf);
-        if len == 0 {
-            return None;
+        let reply = self.request(Position::Left, req, false)?;
+        loop {
+            match reply.try_take() {
+                Some(response) => return response.ok()?,
+                None => self.drive(REQUEST_TIMEOUT),
+            }
+        }
+    }
+
+    /// Announce `info` to peers performing a handshake.
+    pub fn set_local_info(&self, info: &PeerInfo) {
+        *self.scale.write().unwrap() = screen::scale(&info.outputs);
+        self.offer_data(DataRequest::Handshake, info.encode());
+    }
+
+    /// Exchange [`PeerInfo`] with the peer at `pos` in the background,
+    /// requires [`Connection::set_local_info`] first.
+    /// Falls back to querying the capabilities of peers without handshake.
+    pub fn start_handshake(&self, pos: Position) {
+        let addr = match self.peers.get(pos) {
+            Some(peer) => peer.addr,
+            None => return,
+        };
+        let local = match self.offer_data.read().unwrap().get(&DataRequest::Handshake) {
+            Some(local) => (**local).as_ref().to_vec(),
+            None => return,
+        };
+        let mut reactor = self.reactor.lock().unwrap();
+        let pending = reactor
+            .tags()
+            .any(|then| matches!(then, Then::Handshake(p) | Then::Capabilities(p) if *p == pos));
+        if !pending {
+            let then = Then::Handshake(pos);
+            reactor.request(addr, DataRequest::Handshake, Some(&local), false, then);
+        }
+    }
+
+    /// Exchange [`PeerInfo`] with the peer at `pos`, blocking until done.
+    /// Requests of peers are served meanwhile.
+    #[instrument(skip(self))]
+    pub fn handshake(&self, pos: Position) -> Option<PeerInfo> {
+        self.start_handshake(pos);
+        let pending = || {
+            let reactor = self.reactor.lock().unwrap();
+            let mut tags = reactor.tags();
+            tags.any(|then| matches!(then, Then::Handshake(p) if *p == pos))
+        };
+        while pending() {
+            self.drive(REQUEST_TIMEOUT);
+        }
+        self.peers.info(pos)
+    }
+
+    /// remember the info of the peer at `pos`, false if the handshake failed
+    fn complete_handshake(&self, pos: Position, response: Response) -> bool {
+        let data = match response {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                debug!(position = %pos, "peer offers no handshake");
+                return false;
+            }
+            Err(e) => {
+                debug!(position = %pos, "handshake failed: {}", e);
+                return false;
+            }
+        };
+        let info = match PeerInfo::decode(&data) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!(position = %pos, "handshake failed: {}", e);
+                return false;
+            }
+        };
+        info!(
+            position = %pos,
+            hostname = info.hostname,
+            backend = info.backend,
+            outputs = ?info.outputs,
+            "handshake completed"
+        );
+        if info.version != PROTOCOL_VERSION {
+            warn!(
+                version = info.version,
+                "peer speaks protocol version {}, we speak {}", info.version, PROTOCOL_VERSION
+            );
         }
-        let mut data: Vec<u8> = vec![0u8; len];
-        sock.read_exact(&mut data[..]).unwrap();
-        Some(data)
+        self.peers.set_info(pos, info);
+        true
     }
 
-    pub fn send_event<E: Encode>(&self, e: E) {
-        // TODO check which client
-        if let Some(addr) = self.client.right {
-            self.udp_socket.send_to(&e.encode(), addr).unwrap();
+    /// send `e` to the active peer, if any
+    pub fn send_event(&self, e: event::Event) {
+        if let Some(pos) = self.peers.active() {
+            self.send_event_to(pos, e);
         }
     }
 
-    pub fn receive_event(&self) -> Option<Event> {
-        let mut buf = vec![0u8; 21];
-        if let Ok((_amt, _src)) = self.udp_socket.recv_from(&mut buf) {
-            Some(Event::decode(buf))
+    /// send `e` to the peer at `pos`, active or not
+    pub fn send_event_to(&self, pos: Position, e: event::Event) {
+        if let Some(peer) = self.peers.get(pos) {
+            let addr = peer.addr;
+            if !peer.capabilities.unwrap_or_default().supports(&e) {
+                trace!(%addr, kind = e.kind(), "not supported by peer");
+                return;
+            }
+            let version = self.peers.version(pos);
+            if e.since() > version {
+                trace!(%addr, kind = e.kind(), version, "not known to peer");
+                return;
+            }
+            // keep the distance in pixels on outputs with different scale
+            let e = match e {
+                event::Event::Pointer(PointerEvent::Motion { time, dx, dy }) => {
+                    let factor = *self.scale.read().unwrap() / peer.scale;
+                    event::Event::Pointer(PointerEvent::Motion {
+                        time,
+                        dx: dx * factor,
+                        dy: dy * factor,
+                    })
+                }
+                e => e,
+            };
+            let mut buf = self.encode_buf.lock().unwrap();
+            buf.clear();
+            e.encode_for(version, &mut buf);
+            let mut batch = self.batch.lock().unwrap();
+            let batching = batch::enabled(self.batch_delay, version);
+            if is_batched(&e) && batching {
+                if !batch.fits(pos, &buf) {
+                    self.send_batch(&mut batch);
+                }
+                batch.push(pos, e.kind(), &buf, Instant::now() + self.batch_delay);
+                if e == event::Event::Pointer(PointerEvent::Frame) {
+                    self.send_batch(&mut batch);
+                }
+                return;
+            }
+            // keep the order of pointer events and the rest
+            self.send_batch(&mut batch);
+            drop(batch);
+            let result = if is_reliable(&e) {
+                let mut outboxes = self.outboxes.lock().unwrap();
+                let outbox = outboxes
+                    .entry(pos)
+                    .or_insert_with(|| Outbox::new(self.session));
+                let mut datagram = self.reliable_buf.lock().unwrap();
+                outbox.push(&buf, Instant::now(), &mut datagram);
+                trace!(%addr, len = datagram.len(), "sending event");
+                self.send_datagram(pos, &datagram, addr)
+            } else {
+                trace!(%addr, len = buf.len(), "sending event");
+                self.send_datagram(pos, &buf, addr)
+            };
+            match result {
+                Ok(len) => self.stats.sent(pos, e.kind(), len),
+                Err(e) => warn!(%addr, "could not send event: {}", e),
+            }
+        }
+    }
+
+    /// send reliable events again that were not acknowledged in time
+    fn retransmit(&self) {
+        let now = Instant::now();
+        for (pos, outbox) in self.outboxes.lock().unwrap().iter_mut() {
+            let r = outbox.retransmit(now);
+            if r.dropped > 0 {
+                warn!(position = %pos, events = r.dropped, "peer does not acknowledge, dropping events");
+            }
+            let addr = match self.peers.get(*pos) {
+                Some(peer) => peer.addr,
+                None => continue,
+            };
+            for buf in r.datagrams {
+                trace!(%addr, len = buf.len(), "retransmitting event");
+                match self.send_datagram(*pos, &buf, addr) {
+                    Ok(_) => self.stats.retransmitted(*pos),
+                    Err(e) => warn!(%addr, "could not send event: {}", e),
+                }
+            }
+        }
+    }
+
+    /// send the pending pointer events, if any
+    fn send_batch(&self, batch: &mut Batch) {
+        if let Some(Pending {
+            pos,
+            datagram,
+            kinds,
+        }) = batch.pending()
+        {
+            if let Some(peer) = self.peers.get(pos) {
+                let addr = peer.addr;
+                trace!(%addr, events = kinds.len(), len = datagram.len(), "sending pointer events");
+                match self.send_datagram(pos, datagram, addr) {
+                    // the bytes are accounted to the first event
+                    Ok(len) => {
+                        for (i, kind) in kinds.iter().enumerate() {
+                            self.stats.sent(pos, kind, if i == 0 { len } else { 0 });
+                        }
+                    }
+                    Err(e) => warn!(%addr, "could not send events: {}", e),
+                }
+            }
+        }
+        batch.clear();
+    }
+
+    /// send `buf` to the peer at `pos` with the next sequence number
+    fn send_datagram(&self, pos: Position, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        let mut datagram = self.send_buf.lock().unwrap();
+        self.sequencers
+            .lock()
+            .unwrap()
+            .entry(pos)
+            .or_insert_with(|| Sequencer::new(self.session))
+            .wrap(buf, &mut datagram);
+        self.udp_socket.send_to(&datagram, addr)
+    }
+
+    /// Next received event without blocking,
+    /// `None` if nothing is pending.
+    pub fn receive_event(&self) -> Option<event::Event> {
+        if self.incoming.lock().unwrap().is_empty() {
+            self.receive();
+        }
+        self.incoming.lock().unwrap().pop_front()
+    }
+
+    /// take in all pending datagrams
+    fn receive(&self) {
+        let mut buf = self.recv_buf.lock().unwrap();
+        loop {
+            let (amt, src) = match self.udp_socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("recv_from failed: {}", e);
+                    return;
+                }
+            };
+            self.handle_datagram(&buf[..amt], src);
+        }
+    }
+
+    fn handle_datagram(&self, buf: &[u8], src: SocketAddr) {
+        // drop events of unknown or disabled peers
+        let pos = match self.peers.seen(src) {
+            Some(pos) => pos,
+            None => {
+                debug!(peer = %src, "dropping packet of unknown or disabled peer");
+                self.stats.dropped(self.peers.position(src));
+                return;
+            }
+        };
+        let len = buf.len();
+        let (late, buf) = match Sequenced::parse(buf) {
+            Some((header, buf)) => {
+                let mut windows = self.windows.lock().unwrap();
+                let window = windows.entry(pos).or_insert_with(Window::new);
+                let arrival = window.receive(header);
+                if arrival.lost > 0 {
+                    self.stats.lost(pos, arrival.lost);
+                }
+                match arrival.order {
+                    Order::Newest => (false, buf),
+                    Order::Late => {
+                        self.stats.reordered(pos);
+                        (true, buf)
+                    }
+                    Order::Duplicate => {
+                        trace!(peer = %src, seq = header.seq, "duplicate datagram");
+                        self.stats.duplicate(pos);
+                        return;
+                    }
+                }
+            }
+            None => (false, buf),
+        };
+        if let Some(ack) = Ack::parse(buf) {
+            if let Some(outbox) = self.outboxes.lock().unwrap().get_mut(&pos) {
+                outbox.ack(ack, Instant::now());
+            }
+            return;
+        }
+        if let Some((header, buf)) = Reliable::parse(buf) {
+            // reliable events are never batched, acknowledged even if
+            // they cannot be decoded, retransmitting would not help
+            let event = match event::Event::decode(buf) {
+                Ok(event) => Some(event),
+                Err(e) => {
+                    warn!(peer = %src, "dropping packet: {}", e);
+                    self.stats.decode_error(pos);
+                    None
+                }
+            };
+            let mut inboxes = self.inboxes.lock().unwrap();
+            let inbox = inboxes.entry(pos).or_insert_with(Inbox::new);
+            let received = inbox.receive(header, event);
+            if received.duplicate {
+                trace!(peer = %src, seq = header.seq, "duplicate event");
+                self.stats.duplicate(pos);
+            } else if let Some(event) = &event {
+                self.count_received(pos, src, event, len);
+            }
+            if let Err(e) = self.send_datagram(pos, received.ack, src) {
+                warn!(peer = %src, "could not acknowledge event: {}", e);
+            }
+            let mut incoming = self.incoming.lock().unwrap();
+            for event in received.events.iter().flatten() {
+                self.deliver(&mut incoming, pos, *event);
+            }
+            return;
+        }
+        let mut incoming = self.incoming.lock().unwrap();
+        let mut bytes = len;
+        for event in datagram::events(buf) {
+            let event = match event.and_then(event::Event::decode) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!(peer = %src, "dropping packet: {}", e);
+                    self.stats.decode_error(pos);
+                    return;
+                }
+            };
+            let motion = matches!(
+                event,
+                event::Event::Pointer(PointerEvent::Motion { .. } | PointerEvent::Absolute { .. })
+            );
+            if late && motion {
+                // the pointer already moved on
+                trace!(peer = %src, "dropping late motion");
+                continue;
+            }
+            // the bytes are accounted to the first event
+            self.count_received(pos, src, &event, std::mem::take(&mut bytes));
+            self.deliver(&mut incoming, pos, event);
+        }
+    }
+
+    fn count_received(&self, pos: Position, src: SocketAddr, event: &event::Event, bytes: usize) {
+        self.stats.received(pos, event.kind(), bytes);
+        if logging::log_keys() {
+            trace!(peer = %src, ?event, "received event");
         } else {
-            None
+            trace!(peer = %src, kind = event.kind(), "received event");
+        }
+    }
+
+    fn deliver(&self, incoming: &mut VecDeque<event::Event>, pos: Position, event: event::Event) {
+        if incoming.len() >= MAX_INCOMING {
+            // nobody takes events, e.g. on the server
+            incoming.pop_front();
+            self.stats.dropped(Some(pos));
         }
+        incoming.push_back(event);
     }
 }
@@ -0,0 +1,177 @@
+//! Batching of pointer events.
+//!
+//! A mouse reports motion, scrolling and a frame up to 1000 times a second
+//! (and more), one datagram per event means as many wakeups on both sides.
+//! Instead the pointer events of a frame are collected and sent together
+//! once the frame ends, or when the batching delay elapsed for backends
+//! reporting no frames. Buttons are reliable events and never batched,
+//! but end the batch before them to keep the order.
+
+use std::time::{Duration, Instant};
+
+use lan_mouse_proto::datagram::{BATCH, BATCH_SINCE, MAX_BATCH};
+
+use super::Position;
+
+/// whether pointer events to a peer speaking `version` are batched,
+/// older peers get one datagram per event
+pub(super) fn enabled(delay: Duration, version: u32) -> bool {
+    !delay.is_zero() && version >= BATCH_SINCE
+}
+
+/// Pointer events waiting to be sent to one peer.
+pub(super) struct Batch {
+    pos: Option<Position>,
+    buf: Vec<u8>,
+    /// event types, for the statistics
+    kinds: Vec<&'static str>,
+    deadline: Option<Instant>,
+}
+
+/// A batch ready to send.
+pub(super) struct Pending<'a> {
+    pub pos: Position,
+    pub datagram: &'a [u8],
+    pub kinds: &'a [&'static str],
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch {
+            pos: None,
+            buf: vec![BATCH],
+            kinds: vec![],
+            deadline: None,
+        }
+    }
+
+    /// whether `event` can be added for the peer at `pos`
+    /// or the batch has to be flushed first
+    pub fn fits(&self, pos: Position, event: &[u8]) -> bool {
+        self.pos.is_none_or(|p| p == pos) && self.buf.len() + event.len() <= MAX_BATCH
+    }
+
+    /// add an encoded event, to be sent by `deadline` at the latest
+    pub fn push(&mut self, pos: Position, kind: &'static str, event: &[u8], deadline: Instant) {
+        self.pos = Some(pos);
+        self.buf.extend_from_slice(event);
+        self.kinds.push(kind);
+        self.deadline.get_or_insert(deadline);
+    }
+
+    /// when the batch has to be sent
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// the datagram of the batch, a single event is sent as is
+    pub fn pending(&self) -> Option<Pending<'_>> {
+        let datagram = match self.kinds.len() {
+            1 => &self.buf[1..],
+            _ => &self.buf,
+        };
+        Some(Pending {
+            pos: self.pos?,
+            datagram,
+            kinds: &self.kinds,
+        })
+    }
+
+    /// start over after sending, keeping the allocations
+    pub fn clear(&mut self) {
+        self.pos = None;
+        self.deadline = None;
+        self.kinds.clear();
+        self.buf.truncate(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lan_mouse_proto::{
+        datagram,
+        event::{Event, PointerEvent},
+        Encode, PROTOCOL_VERSION,
+    };
+
+    use super::*;
+
+    fn motion() -> Vec<u8> {
+        Event::Pointer(PointerEvent::Motion {
+            time: 0,
+            dx: 1.0,
+            dy: 1.0,
+        })
+        .encode()
+    }
+
+    #[test]
+    fn fill() {
+        let mut batch = Batch::new();
+        let (event, at) = (motion(), Instant::now());
+        let mut n = 0;
+        while batch.fits(Position::Left, &event) {
+            batch.push(Position::Left, "motion", &event, at);
+            n += 1;
+        }
+        assert_eq!(n, (MAX_BATCH - 1) / event.len());
+        let pending = batch.pending().unwrap();
+        assert_eq!(pending.pos, Position::Left);
+        assert_eq!(pending.kinds.len(), n);
+        assert!(pending.datagram.len() <= MAX_BATCH);
+        assert_eq!(pending.datagram[0], BATCH);
+        let events: Vec<_> = datagram::events(pending.datagram).collect();
+        assert_eq!(events.len(), n);
+        assert!(events.iter().all(|e| *e == Ok(&event[..])));
+    }
+
+    #[test]
+    fn does_not_fit() {
+        let mut batch = Batch::new();
+        assert!(batch.fits(Position::Left, &[0; MAX_BATCH - 1]));
+        assert!(!batch.fits(Position::Left, &[0; MAX_BATCH]));
+        batch.push(Position::Left, "motion", &motion(), Instant::now());
+        // another peer
+        assert!(!batch.fits(Position::Right, &motion()));
+        assert!(batch.fits(Position::Left, &motion()));
+        batch.clear();
+        assert!(batch.fits(Position::Right, &motion()));
+    }
+
+    #[test]
+    fn single_event() {
+        let mut batch = Batch::new();
+        assert!(batch.pending().is_none());
+        batch.push(Position::Top, "motion", &motion(), Instant::now());
+        let pending = batch.pending().unwrap();
+        assert_eq!(pending.pos, Position::Top);
+        assert_eq!(pending.datagram, motion());
+        assert_eq!(pending.kinds, ["motion"]);
+        batch.clear();
+        assert!(batch.pending().is_none());
+    }
+
+    #[test]
+    fn deadline() {
+        let mut batch = Batch::new();
+        assert_eq!(batch.deadline(), None);
+        let first = Instant::now();
+        batch.push(Position::Left, "motion", &motion(), first);
+        // sent by the deadline of the oldest event
+        let later = first + Duration::from_millis(5);
+        batch.push(Position::Left, "motion", &motion(), later);
+        assert_eq!(batch.deadline(), Some(first));
+        batch.clear();
+        assert_eq!(batch.deadline(), None);
+        batch.push(Position::Left, "motion", &motion(), later);
+        assert_eq!(batch.deadline(), Some(later));
+    }
+
+    #[test]
+    fn older_peers() {
+        let delay = Duration::from_millis(1);
+        assert!(enabled(delay, PROTOCOL_VERSION));
+        assert!(!enabled(delay, BATCH_SINCE - 1));
+        assert!(!enabled(Duration::ZERO, PROTOCOL_VERSION));
+    }
+}
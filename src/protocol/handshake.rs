@@ -0,0 +1,34 @@
+//! Peer information exchanged through [`DataRequest::Handshake`].
+//!
+//! The requesting side sends its own [`PeerInfo`] along with the request
+//! and receives the one of the peer in return, so both sides know
+//! each other after a single round trip. The wire format is defined in
+//! [`lan_mouse_proto::handshake`].
+//!
+//! [`DataRequest::Handshake`]: super::DataRequest::Handshake
+
+use std::fs;
+
+use crate::{config::KeyboardMode, event::Capabilities, screen};
+
+use super::{PeerInfo, PROTOCOL_VERSION};
+
+/// information about this host, using the given backend
+pub fn local_info(backend: &str, capabilities: Capabilities, keymap_format: u32) -> PeerInfo {
+    PeerInfo {
+        hostname: hostname(),
+        version: PROTOCOL_VERSION,
+        capabilities,
+        backend: backend.into(),
+        outputs: screen::layout(),
+        keymap_format,
+        keyboard: KeyboardMode::Keycode,
+    }
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_string())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default()
+}
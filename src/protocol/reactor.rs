@@ -0,0 +1,589 @@
+//! Control channels driven by the event loop.
+//!
+//! The [`Reactor`] keeps one persistent TCP connection per peer,
+//! dialed on demand or accepted from the peer, and multiplexes
+//! requests, responses and pushes in both directions over it.
+//! Every channel is advanced without blocking by [`Reactor::dispatch`]
+//! whenever the loop wakes up, so a slow or unreachable peer only delays its own requests.
+//! Each request has a deadline, ours may be retried with exponential backoff.
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::unix::prelude::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
+
+use lan_mouse_proto::message::{self, Message};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tracing::{debug, info, warn};
+
+use crate::poll::Interest;
+
+use super::{DataRequest, Decode, DecodeError, Encode, ErrorCode, Push, RemoteError};
+
+/// time a request may take from sending it to its response,
+/// and time a new channel may take to connect or introduce itself
+pub(super) const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// delays between attempts of retried requests
+const RETRY_MIN: Duration = Duration::from_millis(100);
+const RETRY_MAX: Duration = Duration::from_secs(10);
+
+/// answer of a peer, `None` if it has nothing to offer
+pub type Response = io::Result<Option<Vec<u8>>>;
+
+/// What happened while dispatching.
+pub(super) enum Outcome<T> {
+    /// a channel to the peer listening on the address is ready
+    Opened(SocketAddr),
+    Closed(SocketAddr),
+    Pushed(SocketAddr, Push),
+    /// one of our requests finished, successfully or not
+    Finished(T, Response),
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// A connection to a peer carrying messages in both directions.
+struct Channel {
+    key: u64,
+    stream: TcpStream,
+    /// where the peer listens, `None` until its hello arrived
+    peer: Option<SocketAddr>,
+    /// waiting for the connection or the hello of the peer
+    opening: bool,
+    /// for connecting, introducing itself or answering the keepalive
+    deadline: Option<Instant>,
+    /// id of the unanswered keepalive request
+    keepalive: Option<u32>,
+    rbuf: Vec<u8>,
+    wbuf: Vec<u8>,
+    /// bytes of `wbuf` already written
+    written: usize,
+    next_id: u32,
+    /// set on errors, dropped after dispatching
+    closed: bool,
+}
+
+impl Channel {
+    fn send(&mut self, msg: &Message) {
+        msg.encode_into(&mut self.wbuf);
+    }
+
+    /// complete a pending connect, true once connected
+    fn connected(&mut self) -> io::Result<bool> {
+        if let Some(e) = self.stream.take_error()? {
+            return Err(e);
+        }
+        match self.stream.peer_addr() {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// write as much of the buffer as possible
+    fn flush(&mut self) -> io::Result<()> {
+        while self.written < self.wbuf.len() {
+            match self.stream.write(&self.wbuf[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if would_block(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        self.wbuf.clear();
+        self.written = 0;
+        Ok(())
+    }
+
+    /// next complete message, reading from the socket as needed
+    fn receive(&mut self) -> io::Result<Option<Result<Message, DecodeError>>> {
+        loop {
+            if let Some((frame, len)) = message::split_frame(&self.rbuf).map_err(invalid_data)? {
+                let msg = Message::decode(frame);
+                self.rbuf.drain(..len);
+                return Ok(Some(msg));
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.rbuf.extend_from_slice(&chunk[..n]),
+                Err(e) if would_block(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Ask the peer for a sign of life after one of our requests timed out,
+    /// the channel is closed if the answer does not arrive in time either.
+    fn probe(&mut self, now: Instant) {
+        if self.opening || self.keepalive.is_some() {
+            return;
+        }
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+        // answered by every peer, with or without data
+        self.send(&Message::Request {
+            id,
+            req: DataRequest::Capabilities,
+            payload: vec![],
+        });
+        self.keepalive = Some(id);
+        self.deadline = Some(now + REQUEST_TIMEOUT);
+    }
+
+    /// true if `id` answers the keepalive, which is then cleared
+    fn alive(&mut self, id: u32) -> bool {
+        if self.keepalive != Some(id) {
+            return false;
+        }
+        self.keepalive = None;
+        self.deadline = None;
+        true
+    }
+
+    fn interest(&self) -> Interest {
+        let connecting = self.opening && self.peer.is_some();
+        if connecting || !self.wbuf.is_empty() {
+            Interest::Write
+        } else {
+            Interest::Read
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Stage {
+    /// waiting to (re)try at the given time
+    Idle(Instant),
+    /// sent on a channel, waiting for the response
+    Sent {
+        channel: u64,
+        id: u32,
+        deadline: Instant,
+    },
+}
+
+/// One of our requests to a peer.
+struct Outgoing<T> {
+    addr: SocketAddr,
+    req: DataRequest,
+    payload: Vec<u8>,
+    stage: Stage,
+    /// delay before the next attempt, `None` if not retried
+    backoff: Option<Duration>,
+    attempt: u32,
+    tag: T,
+}
+
+/// Drives the control channels, `T` is attached to each of our requests
+/// and returned once it finished.
+pub(super) struct Reactor<T> {
+    listener: Option<TcpListener>,
+    /// told to the peers we dial, so they know where we listen
+    port: u16,
+    channels: Vec<Channel>,
+    requests: Vec<Outgoing<T>>,
+    next_key: u64,
+}
+
+impl<T> Reactor<T> {
+    pub fn new(listener: Option<TcpListener>, port: u16) -> Self {
+        Reactor {
+            listener,
+            port,
+            channels: vec![],
+            requests: vec![],
+            next_key: 0,
+        }
+    }
+
+    /// Request `req` from the peer at `addr`, sending `payload` along.
+    /// With `retry`, failed attempts and empty answers are repeated
+    /// with exponential backoff until the peer offers something.
+    pub fn request(
+        &mut self,
+        addr: SocketAddr,
+        req: DataRequest,
+        payload: Option<&[u8]>,
+        retry: bool,
+        tag: T,
+    ) {
+        self.requests.push(Outgoing {
+            addr,
+            req,
+            payload: payload.map(<[u8]>::to_vec).unwrap_or_default(),
+            stage: Stage::Idle(Instant::now()),
+            backoff: retry.then_some(RETRY_MIN),
+            attempt: 0,
+            tag,
+        });
+    }
+
+    /// Send `push` to the peer at `addr` if a channel to it is open.
+    pub fn push(&mut self, addr: SocketAddr, push: Push) {
+        let channel = match self.channel(addr) {
+            Some(i) if !self.channels[i].opening => &mut self.channels[i],
+            _ => return,
+        };
+        debug!(%addr, ?push, "pushing");
+        channel.send(&Message::Push(push));
+    }
+
+    /// Open a channel to the peer at `addr` unless there is one,
+    /// [`Outcome::Opened`] follows once it is ready.
+    pub fn connect(&mut self, addr: SocketAddr) {
+        if self.channel(addr).is_none() {
+            self.dial(addr);
+        }
+    }
+
+    /// tags of our requests in flight
+    pub fn tags(&self) -> impl Iterator<Item = &T> {
+        self.requests.iter().map(|r| &r.tag)
+    }
+
+    /// drop our requests whose tag does not satisfy `keep`
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        self.requests.retain(|r| {
+            let keep = keep(&r.tag);
+            if !keep {
+                debug!(addr = %r.addr, request = ?r.req, "request cancelled");
+            }
+            keep
+        });
+    }
+
+    /// file descriptors and the readiness the reactor waits for
+    pub fn interest(&self) -> Vec<(RawFd, Interest)> {
+        let listener = self
+            .listener
+            .iter()
+            .map(|l| (l.as_raw_fd(), Interest::Read));
+        let channels = self
+            .channels
+            .iter()
+            .map(|c| (c.stream.as_raw_fd(), c.interest()));
+        listener.chain(channels).collect()
+    }
+
+    /// time until the next deadline or retry, `None` if there is none
+    pub fn timeout(&self) -> Option<Duration> {
+        let channels = self.channels.iter().filter_map(|c| c.deadline);
+        let requests = self.requests.iter().map(|r| match r.stage {
+            Stage::Idle(at) => at,
+            Stage::Sent { deadline, .. } => deadline,
+        });
+        let next = channels.chain(requests).min()?;
+        Some(next.saturating_duration_since(Instant::now()))
+    }
+
+    /// Advance all channels without blocking.
+    /// Requests of peers are answered through `serve`,
+    /// which is given the address the peer listens on.
+    pub fn dispatch(
+        &mut self,
+        mut serve: impl FnMut(SocketAddr, DataRequest, Vec<u8>) -> Result<Option<Vec<u8>>, RemoteError>,
+    ) -> Vec<Outcome<T>> {
+        let mut outcomes = vec![];
+        let now = Instant::now();
+        self.accept();
+        self.send_requests(now, &mut outcomes);
+
+        for i in 0..self.channels.len() {
+            if let Err(e) = self.progress(i, now, &mut serve, &mut outcomes) {
+                let channel = &mut self.channels[i];
+                match channel.peer {
+                    Some(peer) => debug!(%peer, "channel closed: {}", e),
+                    None => debug!("channel closed: {}", e),
+                }
+                channel.closed = true;
+            }
+        }
+
+        // no response in time, only the request fails
+        // and the channel is probed
+        for i in (0..self.requests.len()).rev() {
+            let r = &self.requests[i];
+            let channel = match r.stage {
+                Stage::Sent {
+                    channel, deadline, ..
+                } if now >= deadline => channel,
+                _ => continue,
+            };
+            debug!(addr = %r.addr, request = ?r.req, "request timed out");
+            if let Some(c) = self.channels.iter_mut().find(|c| c.key == channel) {
+                c.probe(now);
+            }
+            let e = io::Error::new(io::ErrorKind::TimedOut, "no response");
+            self.finish(i, Err(e), now, &mut outcomes);
+        }
+
+        // fail the requests sent on closed channels
+        for i in (0..self.requests.len()).rev() {
+            let closed = match self.requests[i].stage {
+                Stage::Sent { channel, .. } => {
+                    !self.channels.iter().any(|c| c.key == channel && !c.closed)
+                }
+                Stage::Idle(_) => false,
+            };
+            if closed {
+                let e = io::Error::new(io::ErrorKind::TimedOut, "no response");
+                self.finish(i, Err(e), now, &mut outcomes);
+            }
+        }
+        self.channels.retain(|c| {
+            if c.closed && !c.opening {
+                outcomes.push(Outcome::Closed(c.peer.unwrap()));
+            }
+            !c.closed
+        });
+        outcomes
+    }
+
+    /// connect, read and write the channel at index `i`
+    fn progress(
+        &mut self,
+        i: usize,
+        now: Instant,
+        serve: &mut impl FnMut(SocketAddr, DataRequest, Vec<u8>) -> Result<Option<Vec<u8>>, RemoteError>,
+        outcomes: &mut Vec<Outcome<T>>,
+    ) -> io::Result<()> {
+        let channel = &mut self.channels[i];
+        if channel.deadline.is_some_and(|d| now >= d) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if let (true, Some(peer)) = (channel.opening, channel.peer) {
+            if !channel.connected()? {
+                return Ok(());
+            }
+            debug!(%peer, "channel opened");
+            channel.opening = false;
+            channel.deadline = None;
+            outcomes.push(Outcome::Opened(peer));
+        }
+        loop {
+            let channel = &mut self.channels[i];
+            let key = channel.key;
+            let msg = match channel.receive()? {
+                Some(Ok(msg)) => msg,
+                Some(Err(DecodeError::UnknownRequest { id, req })) => {
+                    let error = RemoteError::new(
+                        ErrorCode::UnknownRequest,
+                        format!("unknown request {}", req),
+                    );
+                    channel.send(&Message::Error { id, error });
+                    continue;
+                }
+                Some(Err(e @ (DecodeError::UnknownMessage(_) | DecodeError::UnknownPush(_)))) => {
+                    // sent by a newer peer
+                    debug!("ignoring message: {}", e);
+                    continue;
+                }
+                Some(Err(e)) => return Err(invalid_data(e)),
+                None => break,
+            };
+            let (peer, msg) = match (channel.peer, msg) {
+                (Some(peer), Message::Hello { .. }) => {
+                    return Err(invalid_data(format!("{} introduced itself twice", peer)))
+                }
+                (Some(peer), msg) => (peer, msg),
+                (None, Message::Hello { port }) => {
+                    let peer = SocketAddr::new(channel.stream.peer_addr()?.ip(), port);
+                    debug!(%peer, "channel opened");
+                    channel.peer = Some(peer);
+                    channel.opening = false;
+                    channel.deadline = None;
+                    outcomes.push(Outcome::Opened(peer));
+                    continue;
+                }
+                (None, msg) => return Err(invalid_data(format!("{:?} before hello", msg))),
+            };
+            match msg {
+                Message::Request { id, req, payload } => {
+                    let msg = match serve(peer, req, payload) {
+                        Ok(data) => Message::Response { id, data },
+                        Err(error) => {
+                            warn!(%peer, request = ?req, "request failed: {}", error.message);
+                            Message::Error { id, error }
+                        }
+                    };
+                    channel.send(&msg);
+                }
+                Message::Response { id, .. } | Message::Error { id, .. } if channel.alive(id) => {
+                    debug!(%peer, "keepalive answered");
+                }
+                Message::Response { id, data } => self.respond(key, id, Ok(data), now, outcomes),
+                Message::Error { id, error } => {
+                    self.respond(key, id, Err(io::Error::other(error)), now, outcomes)
+                }
+                Message::Push(push) => outcomes.push(Outcome::Pushed(peer, push)),
+                Message::Hello { .. } => unreachable!(),
+            }
+        }
+        self.channels[i].flush()
+    }
+
+    /// handle the response to request `id` sent on channel `key`
+    fn respond(
+        &mut self,
+        key: u64,
+        id: u32,
+        response: Response,
+        now: Instant,
+        outcomes: &mut Vec<Outcome<T>>,
+    ) {
+        let i = self.requests.iter().position(
+            |r| matches!(r.stage, Stage::Sent { channel, id: i, .. } if channel == key && i == id),
+        );
+        match i {
+            Some(i) => self.finish(i, response, now, outcomes),
+            // cancelled meanwhile
+            None => debug!(id, "response to unknown request"),
+        }
+    }
+
+    /// complete request `i` or retry it later
+    fn finish(
+        &mut self,
+        i: usize,
+        response: Response,
+        now: Instant,
+        outcomes: &mut Vec<Outcome<T>>,
+    ) {
+        let r = &mut self.requests[i];
+        let retry = match &response {
+            Ok(Some(_)) => false,
+            Ok(None) => true,
+            Err(e) => {
+                debug!(addr = %r.addr, attempt = r.attempt, "request failed: {}", e);
+                true
+            }
+        };
+        if let (true, Some(backoff)) = (retry, r.backoff) {
+            if r.attempt == 1 {
+                info!(request = ?r.req, addr = %r.addr, "peer not ready, retrying");
+            }
+            r.stage = Stage::Idle(now + backoff);
+            r.backoff = Some((backoff * 2).min(RETRY_MAX));
+            return;
+        }
+        let r = self.requests.swap_remove(i);
+        outcomes.push(Outcome::Finished(r.tag, response));
+    }
+
+    /// send due requests on the channel to their peer
+    fn send_requests(&mut self, now: Instant, outcomes: &mut Vec<Outcome<T>>) {
+        for i in (0..self.requests.len()).rev() {
+            let r = &self.requests[i];
+            match r.stage {
+                Stage::Idle(at) if now >= at => {}
+                _ => continue,
+            }
+            let addr = r.addr;
+            let c = match self.channel(addr).or_else(|| self.dial(addr)) {
+                Some(c) => c,
+                None => {
+                    let e = io::Error::new(io::ErrorKind::NotConnected, "could not connect");
+                    self.requests[i].attempt += 1;
+                    self.finish(i, Err(e), now, outcomes);
+                    continue;
+                }
+            };
+            let channel = &mut self.channels[c];
+            let r = &mut self.requests[i];
+            let id = channel.next_id;
+            channel.next_id = id.wrapping_add(1);
+            channel.send(&Message::Request {
+                id,
+                req: r.req,
+                payload: r.payload.clone(),
+            });
+            r.attempt += 1;
+            r.stage = Stage::Sent {
+                channel: channel.key,
+                id,
+                deadline: now + REQUEST_TIMEOUT,
+            };
+        }
+    }
+
+    /// index of a usable channel to the peer at `addr`
+    fn channel(&self, addr: SocketAddr) -> Option<usize> {
+        self.channels
+            .iter()
+            .position(|c| c.peer == Some(addr) && !c.closed)
+    }
+
+    /// start connecting to the peer at `addr`, the index of the new channel
+    fn dial(&mut self, addr: SocketAddr) -> Option<usize> {
+        let connect = || {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+            socket.set_nonblocking(true)?;
+            match socket.connect(&SockAddr::from(addr)) {
+                Ok(()) => {}
+                Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+                Err(e) => return Err(e),
+            }
+            io::Result::Ok(TcpStream::from(socket))
+        };
+        let stream = match connect() {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!(%addr, "could not connect: {}", e);
+                return None;
+            }
+        };
+        debug!(%addr, "connecting");
+        let mut channel = self.new_channel(stream, Some(addr));
+        channel.send(&Message::Hello { port: self.port });
+        self.channels.push(channel);
+        Some(self.channels.len() - 1)
+    }
+
+    fn new_channel(&mut self, stream: TcpStream, peer: Option<SocketAddr>) -> Channel {
+        let key = self.next_key;
+        self.next_key += 1;
+        Channel {
+            key,
+            stream,
+            peer,
+            opening: true,
+            deadline: Some(Instant::now() + REQUEST_TIMEOUT),
+            keepalive: None,
+            rbuf: vec![],
+            wbuf: vec![],
+            written: 0,
+            next_id: 0,
+            closed: false,
+        }
+    }
+
+    fn accept(&mut self) {
+        while let Some(listener) = &self.listener {
+            let (stream, addr) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if would_block(&e) => return,
+                Err(e) => {
+                    warn!("could not accept channel: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!(%addr, "could not accept channel: {}", e);
+                continue;
+            }
+            debug!(%addr, "channel accepted");
+            let channel = self.new_channel(stream, None);
+            self.channels.push(channel);
+        }
+    }
+}
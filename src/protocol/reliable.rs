@@ -0,0 +1,421 @@
+//! Reliable delivery of state changing events.
+//!
+//! A lost key or button release leaves it stuck on the peer,
+//! so keys, buttons and modifiers are numbered per peer and kept by the sender
+//! until the peer acknowledges them, retransmitting with backoff meanwhile.
+//! The receiver delivers them exactly once and in order, buffering
+//! events that overtook a lost one. Motion and scrolling stay unreliable,
+//! a lost one is superseded by the next anyway.
+//!
+//! Every sender picks a random session, so a restarted sender starting over
+//! at sequence number 0 is not mistaken for duplicates. Reliable datagrams
+//! carry the oldest unacknowledged sequence number of the sender as well,
+//! which lets a restarted receiver pick up where the sender is.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use lan_mouse_proto::datagram::{Ack, Reliable};
+
+const RETRANSMIT_MIN: Duration = Duration::from_millis(20);
+const RETRANSMIT_MAX: Duration = Duration::from_secs(1);
+
+/// events not acknowledged for this long are given up, the peer is likely gone
+const GIVE_UP: Duration = Duration::from_secs(10);
+
+/// events buffered behind a lost one
+const MAX_AHEAD: usize = 256;
+
+/// whether sequence number `a` comes before `b`, allowing for wrap around
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Sending side towards one peer.
+pub(super) struct Outbox {
+    session: u32,
+    next: u32,
+    /// sequence number, encoded event and time it was first sent
+    unacked: VecDeque<(u32, Vec<u8>, Instant)>,
+    /// buffers of acknowledged events, reused for new ones
+    spare: Vec<Vec<u8>>,
+    retransmit_at: Option<Instant>,
+    backoff: Duration,
+}
+
+/// Result of [`Outbox::retransmit`].
+pub(super) struct Retransmit {
+    pub datagrams: Vec<Vec<u8>>,
+    /// events given up on
+    pub dropped: usize,
+}
+
+impl Outbox {
+    pub fn new(session: u32) -> Self {
+        Outbox {
+            session,
+            next: 0,
+            unacked: VecDeque::new(),
+            spare: vec![],
+            retransmit_at: None,
+            backoff: RETRANSMIT_MIN,
+        }
+    }
+
+    /// Number the encoded `event`, writes the datagram to send to `buf`.
+    pub fn push(&mut self, event: &[u8], now: Instant, buf: &mut Vec<u8>) {
+        let seq = self.next;
+        self.next = seq.wrapping_add(1);
+        buf.clear();
+        self.datagram(seq, event, buf);
+        let mut stored = self.spare.pop().unwrap_or_default();
+        stored.clear();
+        stored.extend_from_slice(event);
+        self.unacked.push_back((seq, stored, now));
+        if self.retransmit_at.is_none() {
+            self.backoff = RETRANSMIT_MIN;
+            self.retransmit_at = Some(now + RETRANSMIT_MIN);
+        }
+    }
+
+    /// the peer received everything before `ack.next`
+    pub fn ack(&mut self, ack: Ack, now: Instant) {
+        if ack.session != self.session {
+            return;
+        }
+        let before_len = self.unacked.len();
+        while self
+            .unacked
+            .front()
+            .is_some_and(|(seq, ..)| before(*seq, ack.next))
+        {
+            self.release_front();
+        }
+        if self.unacked.is_empty() {
+            self.retransmit_at = None;
+        } else if self.unacked.len() < before_len {
+            // progress, the peer is alive
+            self.backoff = RETRANSMIT_MIN;
+            self.retransmit_at = Some(now + RETRANSMIT_MIN);
+        }
+    }
+
+    /// Unacknowledged events to send again, if due.
+    pub fn retransmit(&mut self, now: Instant) -> Retransmit {
+        let mut r = Retransmit {
+            datagrams: vec![],
+            dropped: 0,
+        };
+        if self.retransmit_at.is_none_or(|at| now < at) {
+            return r;
+        }
+        while self
+            .unacked
+            .front()
+            .is_some_and(|(_, _, sent)| now.duration_since(*sent) >= GIVE_UP)
+        {
+            self.release_front();
+            r.dropped += 1;
+        }
+        r.datagrams = self
+            .unacked
+            .iter()
+            .map(|(seq, event, _)| {
+                let mut buf = Vec::with_capacity(Reliable::LEN + event.len());
+                self.datagram(*seq, event, &mut buf);
+                buf
+            })
+            .collect();
+        self.backoff = (self.backoff * 2).min(RETRANSMIT_MAX);
+        if self.unacked.is_empty() {
+            self.retransmit_at = None;
+        } else {
+            self.retransmit_at = Some(now + self.backoff);
+        }
+        r
+    }
+
+    /// next time [`Outbox::retransmit`] has something to do
+    pub fn deadline(&self) -> Option<Instant> {
+        self.retransmit_at
+    }
+
+    /// forget the oldest event, keeping its buffer
+    fn release_front(&mut self) {
+        if let Some((_, event, _)) = self.unacked.pop_front() {
+            self.spare.push(event);
+        }
+    }
+
+    fn datagram(&self, seq: u32, event: &[u8], buf: &mut Vec<u8>) {
+        let first = self.unacked.front().map_or(seq, |(seq, ..)| *seq);
+        Reliable {
+            session: self.session,
+            first,
+            seq,
+        }
+        .write(buf);
+        buf.extend_from_slice(event);
+    }
+}
+
+/// Receiving side for one peer, `T` is the decoded event.
+pub(super) struct Inbox<T> {
+    session: Option<u32>,
+    /// next sequence number to deliver
+    next: u32,
+    /// events that overtook a lost one
+    ahead: HashMap<u32, T>,
+    /// buffers of [`Received`], reused for every event
+    events: Vec<T>,
+    ack: Vec<u8>,
+}
+
+/// Result of [`Inbox::receive`].
+pub(super) struct Received<'a, T> {
+    /// events now deliverable, in order
+    pub events: &'a [T],
+    pub duplicate: bool,
+    /// acknowledgement to send back
+    pub ack: &'a [u8],
+}
+
+impl<T> Inbox<T> {
+    pub fn new() -> Self {
+        Inbox {
+            session: None,
+            next: 0,
+            ahead: HashMap::new(),
+            events: vec![],
+            ack: Vec::with_capacity(Ack::LEN),
+        }
+    }
+
+    pub fn receive(&mut self, header: Reliable, event: T) -> Received<'_, T> {
+        if self.session != Some(header.session) {
+            // new or restarted sender
+            self.session = Some(header.session);
+            self.next = header.first;
+            self.ahead.clear();
+        } else if before(self.next, header.first) {
+            // the sender gave up on some events
+            self.next = header.first;
+            self.ahead.retain(|seq, _| !before(*seq, header.first));
+        }
+        let duplicate = before(header.seq, self.next) || self.ahead.contains_key(&header.seq);
+        // the one everything waits for is always taken
+        let room = header.seq == self.next || self.ahead.len() < MAX_AHEAD;
+        if !duplicate && room {
+            self.ahead.insert(header.seq, event);
+        }
+        self.events.clear();
+        while let Some(event) = self.ahead.remove(&self.next) {
+            self.events.push(event);
+            self.next = self.next.wrapping_add(1);
+        }
+        self.ack.clear();
+        Ack {
+            session: header.session,
+            next: self.next,
+        }
+        .write(&mut self.ack);
+        Received {
+            events: &self.events,
+            duplicate,
+            ack: &self.ack,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: u32 = 7;
+
+    fn push(outbox: &mut Outbox, event: u8, now: Instant) -> Reliable {
+        let mut buf = vec![];
+        outbox.push(&[event], now, &mut buf);
+        let (header, rest) = Reliable::parse(&buf).unwrap();
+        assert_eq!(rest, [event]);
+        header
+    }
+
+    /// sequence numbers of the retransmitted datagrams
+    fn retransmitted(outbox: &mut Outbox, now: Instant) -> Vec<u32> {
+        let r = outbox.retransmit(now);
+        assert_eq!(r.dropped, 0);
+        let headers = r.datagrams.iter().map(|d| Reliable::parse(d).unwrap().0);
+        headers.map(|h| h.seq).collect()
+    }
+
+    fn ack(next: u32) -> Ack {
+        Ack {
+            session: SESSION,
+            next,
+        }
+    }
+
+    fn header(first: u32, seq: u32) -> Reliable {
+        Reliable {
+            session: SESSION,
+            first,
+            seq,
+        }
+    }
+
+    #[test]
+    fn retransmit_until_acked() {
+        let now = Instant::now();
+        let mut outbox = Outbox::new(SESSION);
+        assert_eq!(push(&mut outbox, 1, now), header(0, 0));
+        assert_eq!(push(&mut outbox, 2, now), header(0, 1));
+        assert!(retransmitted(&mut outbox, now).is_empty());
+
+        let at = outbox.deadline().unwrap();
+        assert_eq!(at, now + RETRANSMIT_MIN);
+        assert_eq!(retransmitted(&mut outbox, at), [0, 1]);
+        // backing off
+        assert_eq!(outbox.deadline(), Some(at + RETRANSMIT_MIN * 2));
+
+        // acks of other sessions are ignored
+        outbox.ack(
+            Ack {
+                session: 8,
+                next: 2,
+            },
+            at,
+        );
+        assert_eq!(outbox.unacked.len(), 2);
+        outbox.ack(ack(1), at);
+        let at = outbox.deadline().unwrap();
+        let r = outbox.retransmit(at);
+        let (h, event) = Reliable::parse(&r.datagrams[0]).unwrap();
+        assert_eq!((h, event), (header(1, 1), &[2][..]));
+
+        outbox.ack(ack(2), at);
+        assert_eq!(outbox.deadline(), None);
+        assert!(retransmitted(&mut outbox, at + RETRANSMIT_MAX).is_empty());
+    }
+
+    #[test]
+    fn give_up() {
+        let now = Instant::now();
+        let mut outbox = Outbox::new(SESSION);
+        push(&mut outbox, 1, now);
+        push(&mut outbox, 2, now + GIVE_UP / 2);
+        let r = outbox.retransmit(now + GIVE_UP);
+        assert_eq!(r.dropped, 1);
+        assert_eq!(r.datagrams.len(), 1);
+        // the peer is told to skip the dropped event
+        let h = Reliable::parse(&r.datagrams[0]).unwrap().0;
+        assert_eq!(h, header(1, 1));
+    }
+
+    #[test]
+    fn reuse_buffers() {
+        let now = Instant::now();
+        let mut outbox = Outbox::new(SESSION);
+        push(&mut outbox, 1, now);
+        push(&mut outbox, 2, now);
+        outbox.ack(ack(2), now);
+        assert_eq!(outbox.spare.len(), 2);
+        push(&mut outbox, 3, now);
+        assert_eq!(outbox.spare.len(), 1);
+        assert_eq!(outbox.unacked[0].1, [3]);
+    }
+
+    #[test]
+    fn deliver_in_order() {
+        let mut inbox = Inbox::new();
+        let r = inbox.receive(header(0, 0), 'a');
+        assert_eq!(r.events, ['a']);
+        assert_eq!(Ack::parse(r.ack), Some(ack(1)));
+
+        // 1 is lost, 2 and 3 wait for it
+        let r = inbox.receive(header(1, 2), 'c');
+        assert!(r.events.is_empty() && !r.duplicate);
+        assert_eq!(Ack::parse(r.ack), Some(ack(1)));
+        inbox.receive(header(1, 3), 'd');
+        let r = inbox.receive(header(1, 1), 'b');
+        assert_eq!(r.events, ['b', 'c', 'd']);
+        assert_eq!(Ack::parse(r.ack), Some(ack(4)));
+
+        // retransmitted, delivered before
+        let r = inbox.receive(header(1, 2), 'c');
+        assert!(r.events.is_empty() && r.duplicate);
+        assert_eq!(Ack::parse(r.ack), Some(ack(4)));
+    }
+
+    #[test]
+    fn duplicate_ahead() {
+        let mut inbox = Inbox::new();
+        inbox.receive(header(0, 0), 'a');
+        assert!(!inbox.receive(header(1, 2), 'c').duplicate);
+        assert!(inbox.receive(header(1, 2), 'c').duplicate);
+        assert_eq!(inbox.receive(header(1, 1), 'b').events, ['b', 'c']);
+    }
+
+    #[test]
+    fn sender_gave_up() {
+        let mut inbox = Inbox::new();
+        inbox.receive(header(0, 0), 'a');
+        inbox.receive(header(1, 3), 'd');
+        // 1 and 2 were dropped by the sender
+        let r = inbox.receive(header(3, 4), 'e');
+        assert_eq!(r.events, ['d', 'e']);
+        assert_eq!(Ack::parse(r.ack), Some(ack(5)));
+    }
+
+    #[test]
+    fn restarted_sender() {
+        let mut inbox = Inbox::new();
+        inbox.receive(header(0, 0), 'a');
+        inbox.receive(header(0, 1), 'b');
+        let restarted = Reliable {
+            session: 8,
+            first: 0,
+            seq: 0,
+        };
+        let r = inbox.receive(restarted, 'c');
+        assert_eq!(r.events, ['c']);
+        assert!(!r.duplicate);
+    }
+
+    #[test]
+    fn wrap_around() {
+        let now = Instant::now();
+        let mut outbox = Outbox::new(SESSION);
+        outbox.next = u32::MAX;
+        let first = push(&mut outbox, 1, now);
+        let second = push(&mut outbox, 2, now);
+        assert_eq!(first, header(u32::MAX, u32::MAX));
+        assert_eq!(second, header(u32::MAX, 0));
+
+        let mut inbox = Inbox::new();
+        assert!(inbox.receive(second, 'b').events.is_empty());
+        let r = inbox.receive(first, 'a');
+        assert_eq!(r.events, ['a', 'b']);
+        let ack = Ack::parse(r.ack).unwrap();
+        assert_eq!(ack.next, 1);
+
+        outbox.ack(ack, now);
+        assert_eq!(outbox.deadline(), None);
+    }
+
+    #[test]
+    fn bounded_ahead() {
+        let mut inbox = Inbox::new();
+        inbox.receive(header(0, 0), 0);
+        for seq in 2..MAX_AHEAD as u32 + 3 {
+            inbox.receive(header(1, seq), seq);
+        }
+        // everything past the limit is dropped, and retransmitted later,
+        // but not the missing one
+        let r = inbox.receive(header(1, 1), 1);
+        assert_eq!(r.events.len(), MAX_AHEAD + 1);
+        assert_eq!(Ack::parse(r.ack), Some(ack(MAX_AHEAD as u32 + 2)));
+    }
+}
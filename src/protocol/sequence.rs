@@ -0,0 +1,207 @@
+//! Sequence numbers of datagrams.
+//!
+//! Every datagram to a peer is numbered, so the receiver can tell
+//! datagrams that were duplicated or overtaken on the way.
+//! Late relative and absolute motion is dropped, applying it would move
+//! the pointer backwards, everything else is still delivered: reliable
+//! events are put back in order by [`super::reliable`],
+//! scrolling adds up the same in any order.
+//!
+//! Like reliable events, sequence numbers belong to the session of the sender,
+//! a restarted sender is not mistaken for sending old datagrams.
+
+use lan_mouse_proto::datagram::Sequenced;
+
+/// number of datagrams before the newest one tracked for duplicates
+const WINDOW: u32 = 64;
+
+/// Numbers the datagrams to one peer.
+pub(super) struct Sequencer {
+    session: u32,
+    next: u32,
+}
+
+impl Sequencer {
+    pub fn new(session: u32) -> Self {
+        Sequencer { session, next: 0 }
+    }
+
+    /// `datagram` with the next sequence number in front, written to `buf`
+    pub fn wrap(&mut self, datagram: &[u8], buf: &mut Vec<u8>) {
+        let seq = self.next;
+        self.next = seq.wrapping_add(1);
+        buf.clear();
+        Sequenced {
+            session: self.session,
+            seq,
+        }
+        .write(buf);
+        buf.extend_from_slice(datagram);
+    }
+}
+
+/// How a datagram arrived relative to the ones before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Order {
+    /// newer than all datagrams so far
+    Newest,
+    /// overtaken by a newer datagram
+    Late,
+    /// received before
+    Duplicate,
+}
+
+/// Result of [`Window::receive`].
+pub(super) struct Arrival {
+    pub order: Order,
+    /// datagrams that fell out of the window without arriving
+    pub lost: u64,
+}
+
+/// Datagrams received from one peer.
+pub(super) struct Window {
+    session: Option<u32>,
+    newest: u32,
+    /// bit `i` is set if `newest - i` was received
+    received: u64,
+}
+
+impl Window {
+    pub fn new() -> Self {
+        Window {
+            session: None,
+            newest: 0,
+            received: 0,
+        }
+    }
+
+    pub fn receive(&mut self, header: Sequenced) -> Arrival {
+        if self.session != Some(header.session) {
+            // new or restarted sender
+            self.session = Some(header.session);
+            self.newest = header.seq;
+            // nothing before counts as lost
+            self.received = u64::MAX;
+            return Arrival {
+                order: Order::Newest,
+                lost: 0,
+            };
+        }
+        let ahead = header.seq.wrapping_sub(self.newest);
+        if ahead != 0 && ahead < u32::MAX / 2 {
+            // advance the window, counting datagrams that never came
+            let shifted = ahead.min(WINDOW);
+            let gone = match shifted {
+                WINDOW => self.received,
+                n => self.received >> (WINDOW - n),
+            };
+            let lost = (shifted - gone.count_ones()) as u64 + (ahead - shifted) as u64;
+            self.received = match shifted {
+                WINDOW => 1,
+                n => (self.received << n) | 1,
+            };
+            self.newest = header.seq;
+            return Arrival {
+                order: Order::Newest,
+                lost,
+            };
+        }
+        let behind = self.newest.wrapping_sub(header.seq);
+        let order = if behind >= WINDOW {
+            // too old to tell, reliable events are deduplicated anyway
+            Order::Late
+        } else if self.received & (1 << behind) != 0 {
+            Order::Duplicate
+        } else {
+            self.received |= 1 << behind;
+            Order::Late
+        };
+        Arrival { order, lost: 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: u32 = 7;
+
+    fn receive(window: &mut Window, seq: u32) -> (Order, u64) {
+        let arrival = window.receive(Sequenced {
+            session: SESSION,
+            seq,
+        });
+        (arrival.order, arrival.lost)
+    }
+
+    #[test]
+    fn in_order() {
+        let mut window = Window::new();
+        for seq in 0..200 {
+            assert_eq!(receive(&mut window, seq), (Order::Newest, 0));
+        }
+    }
+
+    #[test]
+    fn late_and_duplicate() {
+        let mut window = Window::new();
+        receive(&mut window, 0);
+        assert_eq!(receive(&mut window, 2), (Order::Newest, 0));
+        assert_eq!(receive(&mut window, 1), (Order::Late, 0));
+        assert_eq!(receive(&mut window, 1), (Order::Duplicate, 0));
+        assert_eq!(receive(&mut window, 2), (Order::Duplicate, 0));
+        assert_eq!(receive(&mut window, 0), (Order::Duplicate, 0));
+    }
+
+    #[test]
+    fn lost_once_out_of_window() {
+        let mut window = Window::new();
+        receive(&mut window, 0);
+        // 1 might still arrive
+        for seq in 2..=WINDOW {
+            assert_eq!(receive(&mut window, seq), (Order::Newest, 0));
+        }
+        assert_eq!(receive(&mut window, WINDOW + 1), (Order::Newest, 1));
+        // too old to tell
+        assert_eq!(receive(&mut window, 1), (Order::Late, 0));
+    }
+
+    #[test]
+    fn jump() {
+        let mut window = Window::new();
+        receive(&mut window, 0);
+        // 1 to 136 fell out of the window right away
+        assert_eq!(receive(&mut window, 200), (Order::Newest, 136));
+        assert_eq!(receive(&mut window, 150), (Order::Late, 0));
+        // the rest but 150 once it moves on
+        assert_eq!(receive(&mut window, 264), (Order::Newest, 62));
+    }
+
+    #[test]
+    fn wrap_around() {
+        let mut sequencer = Sequencer::new(SESSION);
+        sequencer.next = u32::MAX;
+        let mut buf = vec![];
+        let mut window = Window::new();
+        for seq in [u32::MAX, 0, 1] {
+            sequencer.wrap(b"datagram", &mut buf);
+            let (header, datagram) = Sequenced::parse(&buf).unwrap();
+            assert_eq!(header.seq, seq);
+            assert_eq!(datagram, b"datagram");
+            assert_eq!(window.receive(header).order, Order::Newest);
+        }
+        assert_eq!(receive(&mut window, u32::MAX), (Order::Duplicate, 0));
+        assert_eq!(receive(&mut window, 3), (Order::Newest, 0));
+        assert_eq!(receive(&mut window, 2), (Order::Late, 0));
+    }
+
+    #[test]
+    fn restarted_sender() {
+        let mut window = Window::new();
+        receive(&mut window, 500);
+        let arrival = window.receive(Sequenced { session: 8, seq: 0 });
+        assert_eq!((arrival.order, arrival.lost), (Order::Newest, 0));
+        let arrival = window.receive(Sequenced { session: 8, seq: 1 });
+        assert_eq!((arrival.order, arrival.lost), (Order::Newest, 0));
+    }
+}
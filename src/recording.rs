@@ -0,0 +1,174 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    emulation::{Emulator, InputEmulation},
+    event::Event,
+    protocol::{Decode, Encode},
+};
+
+const MAGIC: &[u8; 6] = b"LMREC\0";
+const VERSION: u8 = 1;
+
+const RECORD_EVENT: u8 = 0;
+const RECORD_KEYMAP: u8 = 1;
+
+/// A single entry of a recording
+pub enum Record {
+    Event(Event),
+    KeyMap(Vec<u8>),
+}
+
+/// Writes received events into a file.
+///
+/// File format (all integers little endian):
+/// - header: `b"LMREC\0"`, version (u8)
+/// - records: timestamp in µs since start (u64), kind (u8), length (u32), payload
+///
+/// Event payloads use the wire format of events ([`Encode`]),
+/// keymap payloads are the raw keymap.
+pub struct Recorder {
+    out: BufWriter<File>,
+    start: Instant,
+    events: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        info!(path, "recording events");
+        Ok(Recorder {
+            out,
+            start: Instant::now(),
+            events: 0,
+        })
+    }
+
+    fn write(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let t = self.start.elapsed().as_micros() as u64;
+        self.out.write_all(&t.to_le_bytes())?;
+        self.out.write_all(&[kind])?;
+        self.out.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.out.write_all(payload)
+    }
+
+    pub fn record(&mut self, event: &Event) -> io::Result<()> {
+        self.events += 1;
+        self.write(RECORD_EVENT, &event.encode())
+    }
+
+    pub fn record_keymap(&mut self, keymap: &[u8]) -> io::Result<()> {
+        self.write(RECORD_KEYMAP, keymap)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+        info!(events = self.events, "recording finished");
+    }
+}
+
+/// Reads a recording created by [`Recorder`]
+pub struct Player {
+    input: BufReader<File>,
+}
+
+impl Player {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 6];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(format!("{}: not a lan-mouse recording", path).into());
+        }
+        let mut version = [0u8; 1];
+        input.read_exact(&mut version)?;
+        if version[0] != VERSION {
+            return Err(format!("{}: unsupported recording version {}", path, version[0]).into());
+        }
+        Ok(Player { input })
+    }
+
+    /// next record and its timestamp relative to the start of the recording
+    pub fn next_record(&mut self) -> io::Result<Option<(Duration, Record)>> {
+        let mut header = [0u8; 13];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let t = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+        let mut payload = vec![0u8; len];
+        self.input.read_exact(&mut payload)?;
+        let record = match header[8] {
+            RECORD_EVENT => {
+                let event = Event::decode(&payload)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Record::Event(event)
+            }
+            RECORD_KEYMAP => Record::KeyMap(payload),
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid record kind {}", kind),
+                ))
+            }
+        };
+        Ok(Some((Duration::from_micros(t), record)))
+    }
+
+    /// Pass all records to `f`, keeping their original timing.
+    ///
+    /// `speed` scales the playback rate, e.g. `2.0` replays twice as fast.
+    /// A speed of `0` replays without any delay.
+    pub fn play(mut self, speed: f64, mut f: impl FnMut(Record)) -> io::Result<()> {
+        let start = Instant::now();
+        let mut n = 0u64;
+        while let Some((t, record)) = self.next_record()? {
+            if speed > 0.0 {
+                let due = t.div_f64(speed);
+                if let Some(delay) = due.checked_sub(start.elapsed()) {
+                    thread::sleep(delay);
+                }
+            }
+            f(record);
+            n += 1;
+        }
+        debug!(records = n, elapsed = ?start.elapsed(), "replay finished");
+        Ok(())
+    }
+}
+
+/// Feed the recording at `path` into the emulation backend,
+/// see [`Player::play`] for `speed`.
+pub fn replay(
+    path: &str,
+    speed: f64,
+    emulation: &mut dyn InputEmulation,
+) -> Result<(), Box<dyn Error>> {
+    let player = Player::open(path)?;
+    info!(path, speed, "replaying recording");
+    let mut emulator = Emulator::default();
+    player.play(speed, |record| {
+        match record {
+            Record::Event(event) => emulator.consume(emulation, event),
+            Record::KeyMap(data) => emulation.set_keymap(&data),
+        }
+        // later records may still get through
+        if let Err(e) = emulation.flush() {
+            warn!("could not flush emulation: {}", e);
+        }
+    })?;
+    Ok(())
+}
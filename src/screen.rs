@@ -0,0 +1,116 @@
+//! Layout of the local outputs, exchanged with peers during the handshake.
+
+use std::error::Error;
+
+use tracing::debug;
+use wayland_client::{
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{wl_output, wl_registry},
+    Connection, Dispatch, QueueHandle, WEnum,
+};
+
+pub use lan_mouse_proto::handshake::Output;
+
+/// largest scale factor of `outputs`, 1 if unknown
+pub fn scale(outputs: &[Output]) -> f64 {
+    outputs.iter().map(|o| o.scale).fold(1.0, f64::max)
+}
+
+/// Query the outputs of the current session, empty if unknown.
+pub fn layout() -> Vec<Output> {
+    let result = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        wayland_outputs()
+    } else {
+        x11_outputs()
+    };
+    match result {
+        Ok(outputs) => {
+            debug!(?outputs, "output layout");
+            outputs
+        }
+        Err(e) => {
+            debug!("could not query outputs: {}", e);
+            vec![]
+        }
+    }
+}
+
+struct State {
+    outputs: Vec<Output>,
+}
+
+fn wayland_outputs() -> Result<Vec<Output>, Box<dyn Error>> {
+    let conn = Connection::connect_to_env()?;
+    let (globals, mut queue) = registry_queue_init::<State>(&conn)?;
+    let qh = queue.handle();
+    let mut state = State { outputs: vec![] };
+    for global in globals.contents().clone_list() {
+        if global.interface == "wl_output" && global.version >= 2 {
+            let idx = state.outputs.len();
+            state.outputs.push(Output::default());
+            globals
+                .registry()
+                .bind::<wl_output::WlOutput, _, _>(global.name, 2, &qh, idx);
+        }
+    }
+    queue.roundtrip(&mut state)?;
+    Ok(state.outputs)
+}
+
+#[cfg(feature = "x11")]
+fn x11_outputs() -> Result<Vec<Output>, Box<dyn Error>> {
+    let (conn, screen) = x11rb::connect(None)?;
+    let root = &x11rb::connection::Connection::setup(&conn).roots[screen];
+    Ok(vec![Output {
+        width: root.width_in_pixels as u32,
+        height: root.height_in_pixels as u32,
+        ..Default::default()
+    }])
+}
+
+#[cfg(not(feature = "x11"))]
+fn x11_outputs() -> Result<Vec<Output>, Box<dyn Error>> {
+    Err("X11 support not compiled in".into())
+}
+
+impl Dispatch<wl_output::WlOutput, usize> for State {
+    fn event(
+        state: &mut Self,
+        _: &wl_output::WlOutput,
+        event: wl_output::Event,
+        idx: &usize,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let output = &mut state.outputs[*idx];
+        match event {
+            wl_output::Event::Geometry { x, y, .. } => {
+                output.x = x;
+                output.y = y;
+            }
+            wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                ..
+            } if flags.contains(wl_output::Mode::Current) => {
+                output.width = width as u32;
+                output.height = height as u32;
+            }
+            wl_output::Event::Scale { factor } => output.scale = factor as f64,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+    fn event(
+        _: &mut State,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<State>,
+    ) {
+    }
+}
@@ -0,0 +1,195 @@
+//! Capture side: grabbed input is forwarded to the peer whose edge was crossed.
+
+use std::{error::Error, io, os::unix::prelude::RawFd, time::Duration};
+
+use tracing::warn;
+
+use crate::{
+    capture::{CaptureEvent, InputCapture},
+    dbus::Command,
+    event::{Capabilities, ControlEvent, Event},
+    poll::{self, Interest},
+    protocol::{self, Connection, PeerError, Position},
+};
+#[cfg(feature = "xkb")]
+use crate::{config::KeyboardMode, keysym::Resolver};
+
+pub struct Server {
+    connection: Connection,
+    capture: Box<dyn InputCapture>,
+    /// peer the grabbed input goes to
+    grabbed: Option<Position>,
+    /// resolves keysyms for peers in keysym mode
+    #[cfg(feature = "xkb")]
+    resolver: Option<Resolver>,
+}
+
+impl Server {
+    pub fn new(connection: Connection, capture: Box<dyn InputCapture>) -> Server {
+        // the server does not emulate anything
+        let info =
+            protocol::local_info(capture.name(), Capabilities::NONE, capture.keymap_format());
+        connection.set_local_info(&info);
+        // peers not reachable yet are asked again when entered
+        for (pos, _) in connection.peers().list() {
+            connection.start_handshake(pos);
+        }
+        Server {
+            connection,
+            capture,
+            grabbed: None,
+            #[cfg(feature = "xkb")]
+            resolver: None,
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// file descriptors to wait for before [`Server::dispatch`],
+    /// changes on every iteration
+    pub fn fds(&self) -> Vec<(RawFd, Interest)> {
+        let mut fds = poll::readable(self.capture.fds());
+        fds.extend(self.connection.fds());
+        fds
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.connection.timeout()
+    }
+
+    /// carry out a request of the D-Bus service
+    pub fn command(&mut self, command: Command) {
+        match command {
+            Command::SwitchTo(pos, reply) => {
+                let result = self.switch_to(pos);
+                if let Err(e) = &result {
+                    warn!(position = %pos, "not switching: {}", e);
+                }
+                let _ = reply.send(result.map_err(|e| e.to_string()));
+            }
+            Command::Release => self.release(),
+        }
+    }
+
+    /// Advance the connection and the capture backend without blocking
+    /// and forward the captured input.
+    pub fn dispatch(&mut self) -> io::Result<()> {
+        self.connection.dispatch();
+        self.capture.dispatch()?;
+        while let Some(event) = self.capture.next_event() {
+            self.handle(event);
+        }
+        Ok(())
+    }
+
+    /// end the grab, if any
+    pub fn release(&mut self) {
+        if let Err(e) = self.capture.release() {
+            warn!("could not release grab: {}", e);
+        }
+    }
+
+    fn handle(&mut self, event: CaptureEvent) {
+        let peers = self.connection.peers();
+        match event {
+            CaptureEvent::Begin(pos) => {
+                if let Err(e) = peers.switch_to(pos) {
+                    warn!("not grabbing: {}", e);
+                    self.release();
+                    return;
+                }
+                self.grabbed = Some(pos);
+                self.enter(pos);
+            }
+            CaptureEvent::Input(e) => {
+                // released externally, e.g. by disabling the peer
+                if peers.active().is_none() {
+                    self.leave();
+                    self.release();
+                    return;
+                }
+                #[cfg(feature = "xkb")]
+                let e = match self.translate(e) {
+                    Some(e) => e,
+                    None => return,
+                };
+                self.connection.send_event(e);
+            }
+            CaptureEvent::End => self.leave(),
+            CaptureEvent::KeyMap(mmap) => {
+                #[cfg(feature = "xkb")]
+                {
+                    self.resolver = Resolver::new(&mmap)
+                        .map_err(|e| warn!("keysym mode unavailable: {}", e))
+                        .ok();
+                }
+                self.connection.offer_keymap(mmap)
+            }
+        }
+    }
+
+    /// start sending to the peer at `pos`, which is active already
+    fn enter(&self, pos: Position) {
+        self.connection
+            .send_event(Event::Control(ControlEvent::Enter));
+        self.connection.announce_keymap(pos);
+        let peers = self.connection.peers();
+        if peers.get(pos).is_some_and(|p| p.capabilities.is_none()) {
+            self.connection.start_handshake(pos);
+        }
+    }
+
+    /// stop sending to the peer input was grabbed for
+    fn leave(&mut self) {
+        // lets the peer release what is still held
+        if let Some(pos) = self.grabbed.take() {
+            self.connection
+                .send_event_to(pos, Event::Control(ControlEvent::Leave));
+        }
+        self.connection.peers().release();
+    }
+
+    /// Switch to the peer at `pos` on request. While grabbed only the peer
+    /// changes, otherwise the grab is reported as [`CaptureEvent::Begin`].
+    fn switch_to(&mut self, pos: Position) -> Result<(), Box<dyn Error>> {
+        let peers = self.connection.peers();
+        match self.grabbed {
+            Some(current) if current == pos => Ok(()),
+            Some(current) => {
+                peers.switch_to(pos)?;
+                self.connection
+                    .send_event_to(current, Event::Control(ControlEvent::Leave));
+                self.grabbed = Some(pos);
+                self.enter(pos);
+                Ok(())
+            }
+            None => {
+                match peers.get(pos) {
+                    None => return Err(PeerError::NoSuchPeer(pos).into()),
+                    Some(p) if !p.enabled => return Err(PeerError::Disabled(pos).into()),
+                    Some(_) => {}
+                }
+                Ok(self.capture.grab(pos)?)
+            }
+        }
+    }
+
+    /// resolve keys to keysyms if the active peer is in keysym mode
+    #[cfg(feature = "xkb")]
+    fn translate(&mut self, e: Event) -> Option<Event> {
+        let resolver = match &mut self.resolver {
+            Some(resolver) => resolver,
+            None => return Some(e),
+        };
+        // follow the modifier state regardless of the peer
+        let translated = resolver.translate(e);
+        let peers = self.connection.peers();
+        let active = peers.active().and_then(|pos| peers.get(pos));
+        match active {
+            Some(peer) if peer.keyboard == KeyboardMode::Keysym => translated,
+            _ => Some(e),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::protocol::Position;
+
+/// Counters for a single peer
+#[derive(Default, Clone, Debug)]
+pub struct PeerStats {
+    /// events sent, by event type
+    pub events_sent: BTreeMap<&'static str, u64>,
+    /// events received, by event type
+    pub events_received: BTreeMap<&'static str, u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// packets ignored because the peer is disabled
+    pub dropped: u64,
+    pub decode_errors: u64,
+    pub keymap_requests: u64,
+    /// key, button and modifier events sent again for lack of an acknowledgement
+    pub retransmitted: u64,
+    /// datagrams and key, button and modifier events received more than once
+    pub duplicates: u64,
+    /// datagrams overtaken by a later one
+    pub reordered: u64,
+    /// datagrams that never arrived
+    pub lost: u64,
+}
+
+impl PeerStats {
+    /// flat `name -> value` view, used by the control interface
+    pub fn to_map(&self) -> HashMap<String, u64> {
+        let mut map = HashMap::new();
+        for (kind, n) in &self.events_sent {
+            map.insert(format!("events_sent.{}", kind), *n);
+        }
+        for (kind, n) in &self.events_received {
+            map.insert(format!("events_received.{}", kind), *n);
+        }
+        map.insert("bytes_sent".into(), self.bytes_sent);
+        map.insert("bytes_received".into(), self.bytes_received);
+        map.insert("dropped".into(), self.dropped);
+        map.insert("decode_errors".into(), self.decode_errors);
+        map.insert("keymap_requests".into(), self.keymap_requests);
+        map.insert("retransmitted".into(), self.retransmitted);
+        map.insert("duplicates".into(), self.duplicates);
+        map.insert("reordered".into(), self.reordered);
+        map.insert("lost".into(), self.lost);
+        map
+    }
+}
+
+#[derive(Default)]
+struct StatsTable {
+    peers: HashMap<Position, PeerStats>,
+    /// packets from addresses that are not configured as peer
+    unknown_dropped: u64,
+}
+
+/// Shared handle to the traffic statistics of a [`Connection`](crate::protocol::Connection)
+#[derive(Clone, Default)]
+pub struct Stats(Arc<Mutex<StatsTable>>);
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update(&self, pos: Position, f: impl FnOnce(&mut PeerStats)) {
+        f(self.0.lock().unwrap().peers.entry(pos).or_default());
+    }
+
+    pub(crate) fn sent(&self, pos: Position, kind: &'static str, bytes: usize) {
+        self.update(pos, |s| {
+            *s.events_sent.entry(kind).or_default() += 1;
+            s.bytes_sent += bytes as u64;
+        });
+    }
+
+    pub(crate) fn received(&self, pos: Position, kind: &'static str, bytes: usize) {
+        self.update(pos, |s| {
+            *s.events_received.entry(kind).or_default() += 1;
+            s.bytes_received += bytes as u64;
+        });
+    }
+
+    pub(crate) fn dropped(&self, pos: Option<Position>) {
+        match pos {
+            Some(pos) => self.update(pos, |s| s.dropped += 1),
+            None => self.0.lock().unwrap().unknown_dropped += 1,
+        }
+    }
+
+    pub(crate) fn decode_error(&self, pos: Position) {
+        self.update(pos, |s| s.decode_errors += 1);
+    }
+
+    pub(crate) fn keymap_request(&self, pos: Position) {
+        self.update(pos, |s| s.keymap_requests += 1);
+    }
+
+    pub(crate) fn retransmitted(&self, pos: Position) {
+        self.update(pos, |s| s.retransmitted += 1);
+    }
+
+    pub(crate) fn duplicate(&self, pos: Position) {
+        self.update(pos, |s| s.duplicates += 1);
+    }
+
+    pub(crate) fn reordered(&self, pos: Position) {
+        self.update(pos, |s| s.reordered += 1);
+    }
+
+    pub(crate) fn lost(&self, pos: Position, n: u64) {
+        self.update(pos, |s| s.lost += n);
+    }
+
+    pub fn peer(&self, pos: Position) -> PeerStats {
+        self.0
+            .lock()
+            .unwrap()
+            .peers
+            .get(&pos)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn peers(&self) -> Vec<(Position, PeerStats)> {
+        let table = self.0.lock().unwrap();
+        table.peers.iter().map(|(p, s)| (*p, s.clone())).collect()
+    }
+
+    pub fn unknown_dropped(&self) -> u64 {
+        self.0.lock().unwrap().unknown_dropped
+    }
+
+    /// render all counters in the prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let table = self.0.lock().unwrap();
+        let mut peers: Vec<_> = table.peers.iter().collect();
+        peers.sort_by_key(|(pos, _)| pos.to_string());
+        let mut out = String::new();
+
+        let mut metric =
+            |name: &str, help: &str, values: &mut dyn Iterator<Item = (String, u64)>| {
+                let _ = writeln!(out, "# HELP lan_mouse_{} {}", name, help);
+                let _ = writeln!(out, "# TYPE lan_mouse_{} counter", name);
+                for (labels, value) in values {
+                    let _ = writeln!(out, "lan_mouse_{}{{{}}} {}", name, labels, value);
+                }
+            };
+
+        let by_kind = |f: fn(&PeerStats) -> &BTreeMap<&'static str, u64>| {
+            peers
+                .iter()
+                .flat_map(move |(pos, s)| {
+                    f(s).iter()
+                        .map(move |(kind, n)| (format!("peer=\"{}\",type=\"{}\"", pos, kind), *n))
+                })
+                .collect::<Vec<_>>()
+        };
+        let per_peer = |f: fn(&PeerStats) -> u64| {
+            peers
+                .iter()
+                .map(move |(pos, s)| (format!("peer=\"{}\"", pos), f(s)))
+                .collect::<Vec<_>>()
+        };
+
+        metric(
+            "events_sent_total",
+            "Events sent to a peer.",
+            &mut by_kind(|s| &s.events_sent).into_iter(),
+        );
+        metric(
+            "events_received_total",
+            "Events received from a peer.",
+            &mut by_kind(|s| &s.events_received).into_iter(),
+        );
+        metric(
+            "sent_bytes_total",
+            "Event payload bytes sent to a peer.",
+            &mut per_peer(|s| s.bytes_sent).into_iter(),
+        );
+        metric(
+            "received_bytes_total",
+            "Event payload bytes received from a peer.",
+            &mut per_peer(|s| s.bytes_received).into_iter(),
+        );
+        metric(
+            "dropped_packets_total",
+            "Packets dropped because the peer is disabled or unknown.",
+            &mut per_peer(|s| s.dropped)
+                .into_iter()
+                .chain([("peer=\"unknown\"".to_string(), table.unknown_dropped)]),
+        );
+        metric(
+            "decode_errors_total",
+            "Packets of a peer that could not be decoded.",
+            &mut per_peer(|s| s.decode_errors).into_iter(),
+        );
+        metric(
+            "keymap_requests_total",
+            "Keymap requests served to a peer.",
+            &mut per_peer(|s| s.keymap_requests).into_iter(),
+        );
+        metric(
+            "retransmitted_events_total",
+            "Key, button and modifier events sent again to a peer.",
+            &mut per_peer(|s| s.retransmitted).into_iter(),
+        );
+        metric(
+            "duplicate_events_total",
+            "Datagrams and key, button and modifier events of a peer received more than once.",
+            &mut per_peer(|s| s.duplicates).into_iter(),
+        );
+        metric(
+            "reordered_datagrams_total",
+            "Datagrams of a peer overtaken by a later one.",
+            &mut per_peer(|s| s.reordered).into_iter(),
+        );
+        metric(
+            "lost_datagrams_total",
+            "Datagrams of a peer that never arrived.",
+            &mut per_peer(|s| s.lost).into_iter(),
+        );
+        out
+    }
+}
+
+/// time a metrics client has to send its request and take the answer,
+/// requests are handled one after another
+const HTTP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// longest request line read, the rest of the request is ignored anyway
+const MAX_REQUEST_LINE: u64 = 8192;
+
+/// Serve the statistics in prometheus format on `127.0.0.1:port`.
+///
+/// Every request is answered with the current metrics,
+/// regardless of the requested path.
+pub fn serve_http(stats: Stats, port: u16) -> Result<(), Box<dyn Error>> {
+    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+    let listener = TcpListener::bind(addr)?;
+    info!(%addr, "serving metrics");
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            // a client sending nothing must not hold up the next ones
+            let timeouts = stream
+                .set_read_timeout(Some(HTTP_TIMEOUT))
+                .and_then(|_| stream.set_write_timeout(Some(HTTP_TIMEOUT)));
+            if let Err(e) = timeouts {
+                warn!("could not set timeouts of metrics client: {}", e);
+                continue;
+            }
+            // skip the request, there is only one resource
+            let mut request_line = String::new();
+            if let Err(e) = BufReader::new(&stream)
+                .take(MAX_REQUEST_LINE)
+                .read_line(&mut request_line)
+            {
+                debug!("dropping metrics client: {}", e);
+                continue;
+            }
+            debug!(request = request_line.trim_end(), "metrics request");
+            let body = stats.prometheus();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                warn!("could not send metrics: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
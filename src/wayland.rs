@@ -0,0 +1,133 @@
+//! Helpers shared by the wayland backends.
+
+use std::{io, ops::RangeInclusive};
+
+use tracing::debug;
+use wayland_client::{
+    backend::WaylandError,
+    globals::GlobalList,
+    protocol::{wl_keyboard, wl_pointer},
+    Dispatch, EventQueue, Proxy, QueueHandle,
+};
+
+use crate::event::{self, KeyboardEvent, PointerEvent};
+
+/// Collects the globals a backend could not bind,
+/// so all missing protocols are reported at once.
+#[derive(Default)]
+pub struct Probe {
+    missing: Vec<&'static str>,
+}
+
+impl Probe {
+    /// bind `I`, recording its interface name if unavailable
+    pub fn bind<I, D>(
+        &mut self,
+        globals: &GlobalList,
+        qh: &QueueHandle<D>,
+        version: RangeInclusive<u32>,
+    ) -> Option<I>
+    where
+        I: Proxy + 'static,
+        D: Dispatch<I, ()> + 'static,
+    {
+        match globals.bind(qh, version, ()) {
+            Ok(global) => Some(global),
+            Err(e) => {
+                let name = I::interface().name;
+                debug!(interface = name, "could not bind global: {}", e);
+                self.missing.push(name);
+                None
+            }
+        }
+    }
+
+    /// interfaces that could not be bound
+    pub fn missing(&self) -> &[&'static str] {
+        &self.missing
+    }
+}
+
+/// Read and dispatch the events of `queue` without blocking,
+/// then flush the requests sent meanwhile.
+pub fn dispatch<D>(queue: &mut EventQueue<D>, state: &mut D) -> io::Result<()> {
+    queue.dispatch_pending(state).map_err(io::Error::other)?;
+    if let Some(guard) = queue.prepare_read() {
+        match guard.read() {
+            Ok(_) => {}
+            Err(WaylandError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(io::Error::other(e)),
+        }
+    }
+    queue.dispatch_pending(state).map_err(io::Error::other)?;
+    queue.flush().map_err(io::Error::other)
+}
+
+/// The [`PointerEvent`] of a `wl_pointer` event, `None` if it has none:
+/// enter and leave are up to the backend, motion is relative
+/// and comes from the relative pointer protocol instead.
+pub fn pointer_event(e: wl_pointer::Event) -> Option<PointerEvent> {
+    let e = match e {
+        wl_pointer::Event::Button {
+            time,
+            button,
+            state,
+            ..
+        } => PointerEvent::Button {
+            time,
+            button,
+            state: u32::from(state),
+        },
+        wl_pointer::Event::Axis { time, axis, value } => PointerEvent::Axis {
+            time,
+            axis: u32::from(axis),
+            value,
+        },
+        wl_pointer::Event::Frame => PointerEvent::Frame,
+        _ => return None,
+    };
+    Some(e)
+}
+
+/// The [`KeyboardEvent`] of a `wl_keyboard` event, `None` if it has none.
+pub fn keyboard_event(e: wl_keyboard::Event) -> Option<KeyboardEvent> {
+    let e = match e {
+        wl_keyboard::Event::Key {
+            time, key, state, ..
+        } => KeyboardEvent::Key {
+            time,
+            key,
+            state: u32::from(state),
+        },
+        wl_keyboard::Event::Modifiers {
+            mods_depressed,
+            mods_latched,
+            mods_locked,
+            group,
+            ..
+        } => KeyboardEvent::Modifiers {
+            mods_depressed,
+            mods_latched,
+            mods_locked,
+            group,
+        },
+        _ => return None,
+    };
+    Some(e)
+}
+
+/// `state` of a [`PointerEvent::Button`]
+pub fn button_state(state: u32) -> wl_pointer::ButtonState {
+    match state {
+        event::BUTTON_PRESSED => wl_pointer::ButtonState::Pressed,
+        _ => wl_pointer::ButtonState::Released,
+    }
+}
+
+/// `axis` of a [`PointerEvent::Axis`]
+pub fn axis(axis: u32) -> wl_pointer::Axis {
+    match axis {
+        event::AXIS_HORIZONTAL => wl_pointer::Axis::HorizontalScroll,
+        _ => wl_pointer::Axis::VerticalScroll,
+    }
+}
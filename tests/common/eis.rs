@@ -0,0 +1,113 @@
+//! The EIS end of an ei connection, scripted by the tests.
+
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+};
+
+// ids of objects created by EIS
+pub const CONNECTION: u64 = 0xff00_0000_0000_0000;
+pub const SEAT: u64 = CONNECTION + 1;
+pub const DEVICE: u64 = CONNECTION + 2;
+pub const POINTER: u64 = CONNECTION + 3;
+pub const KEYBOARD: u64 = CONNECTION + 4;
+pub const PING: u64 = CONNECTION + 5;
+
+/// a string argument, nul terminated and padded to 4 bytes
+pub fn string(s: &str) -> Vec<u8> {
+    let len = s.len() + 1;
+    let mut arg = (len as u32).to_ne_bytes().to_vec();
+    arg.extend_from_slice(s.as_bytes());
+    arg.resize(4 + len + (4 - len % 4) % 4, 0);
+    arg
+}
+
+/// a `new_id` argument for an object of version 1
+pub fn new_id(id: u64) -> Vec<u8> {
+    [&id.to_ne_bytes()[..], &1u32.to_ne_bytes()].concat()
+}
+
+pub fn u32s(args: &[u32]) -> Vec<u8> {
+    args.iter().flat_map(|a| a.to_ne_bytes()).collect()
+}
+
+pub struct Eis(pub UnixStream);
+
+impl Eis {
+    pub fn send(&mut self, object: u64, opcode: u32, args: &[u8]) {
+        let mut msg = object.to_ne_bytes().to_vec();
+        msg.extend_from_slice(&(16 + args.len() as u32).to_ne_bytes());
+        msg.extend_from_slice(&opcode.to_ne_bytes());
+        msg.extend_from_slice(args);
+        self.0.write_all(&msg).unwrap();
+    }
+
+    /// next request as `(object, opcode, args)`
+    pub fn recv(&mut self) -> (u64, u32, Vec<u8>) {
+        let mut header = [0u8; 16];
+        self.0.read_exact(&mut header).unwrap();
+        let object = u64::from_ne_bytes(header[0..8].try_into().unwrap());
+        let len = u32::from_ne_bytes(header[8..12].try_into().unwrap()) as usize;
+        let opcode = u32::from_ne_bytes(header[12..16].try_into().unwrap());
+        let mut args = vec![0; len - 16];
+        self.0.read_exact(&mut args).unwrap();
+        (object, opcode, args)
+    }
+
+    /// answer the handshake of a client of the given context type
+    pub fn handshake(&mut self, context_type: u32) {
+        // ei_handshake.handshake_version
+        self.send(0, 0, &u32s(&[1]));
+        let mut interfaces = vec![];
+        loop {
+            match self.recv() {
+                // context_type
+                (0, 2, args) => assert_eq!(args, u32s(&[context_type]), "context type"),
+                // name
+                (0, 3, args) => assert_eq!(args, string("lan-mouse")),
+                // interface_version
+                (0, 4, args) => interfaces.push(args),
+                // finish
+                (0, 1, _) => break,
+                _ => {}
+            }
+        }
+        for interface in ["ei_seat", "ei_device", "ei_pointer", "ei_keyboard"] {
+            let announced = [string(interface), u32s(&[1])].concat();
+            assert!(
+                interfaces.contains(&announced),
+                "{} not announced",
+                interface
+            );
+        }
+        // ei_handshake.connection
+        self.send(0, 2, &[u32s(&[1]), new_id(CONNECTION)].concat());
+    }
+
+    /// a seat with pointer, keyboard and the unknown touch capability
+    pub fn offer_seat(&mut self) {
+        // ei_connection.seat
+        self.send(CONNECTION, 1, &new_id(SEAT));
+        // ei_seat.capability, done
+        for (mask, name) in [(1u64, "ei_pointer"), (2, "ei_keyboard"), (4, "ei_touch")] {
+            self.send(SEAT, 2, &[&mask.to_ne_bytes()[..], &string(name)].concat());
+        }
+        self.send(SEAT, 3, &[]);
+    }
+
+    /// expect ei_seat.bind, without the touch capability
+    pub fn expect_bind(&mut self) {
+        assert_eq!(self.recv(), (SEAT, 1, 3u64.to_ne_bytes().to_vec()));
+    }
+
+    /// add a device with pointer and keyboard, resumed with `serial`
+    pub fn add_device(&mut self, serial: u32) {
+        // ei_seat.device, ei_device.interface, ei_device.resumed
+        self.send(SEAT, 4, &new_id(DEVICE));
+        for (object, name) in [(POINTER, "ei_pointer"), (KEYBOARD, "ei_keyboard")] {
+            let interface = [&object.to_ne_bytes()[..], &string(name), &u32s(&[1])].concat();
+            self.send(DEVICE, 5, &interface);
+        }
+        self.send(DEVICE, 7, &u32s(&[serial]));
+    }
+}
@@ -0,0 +1,97 @@
+//! Helpers shared by the integration tests,
+//! not every test uses all of them.
+#![allow(dead_code)]
+
+use std::{
+    env,
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use lan_mouse::{
+    config::{Client, Clients, Config},
+    protocol::{Connection, Position},
+};
+
+#[cfg(feature = "libei")]
+pub mod eis;
+
+/// how long to wait for anything expected to happen
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// a connection on `port` with a single peer at `pos`, listening on `peer_port`
+pub fn connection(port: u16, pos: Position, peer_port: u16) -> Connection {
+    let peer = Client {
+        host_name: None,
+        ip: Some("127.0.0.1".parse().unwrap()),
+        port: Some(peer_port),
+        keyboard: None,
+    };
+    let mut client = Clients {
+        left: None,
+        right: None,
+        top: None,
+        bottom: None,
+    };
+    match pos {
+        Position::Left => client.left = Some(peer),
+        Position::Right => client.right = Some(peer),
+        Position::Top => client.top = Some(peer),
+        Position::Bottom => client.bottom = Some(peer),
+    }
+    Connection::new(Config {
+        client,
+        port: Some(port),
+        metrics_port: None,
+        evdev: None,
+        batch_delay: None,
+    })
+}
+
+/// serializes the tests, the bus address is passed through the environment
+static ENV: Mutex<()> = Mutex::new(());
+
+/// A private `dbus-daemon`, the session bus of this process while alive
+pub struct Bus {
+    daemon: Child,
+    pub address: String,
+    _env: MutexGuard<'static, ()>,
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// start a bus and make it the session bus of this process,
+/// `None` if `dbus-daemon` is not installed
+pub fn private_bus() -> Option<Bus> {
+    let guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    let daemon = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut daemon = match daemon {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("skipping, could not run dbus-daemon: {}", e);
+            return None;
+        }
+    };
+    let mut address = String::new();
+    BufReader::new(daemon.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+    let address = address.trim().to_string();
+    env::set_var("DBUS_SESSION_BUS_ADDRESS", &address);
+    Some(Bus {
+        daemon,
+        address,
+        _env: guard,
+    })
+}
@@ -0,0 +1,119 @@
+//! The control channel against a scripted peer speaking the wire format.
+
+mod common;
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connection, TIMEOUT};
+use lan_mouse::protocol::{Connection, DataRequest, Decode, Encode, Position, Reply, Response};
+use lan_mouse_proto::message::{split_frame, Message};
+
+struct Peer {
+    stream: TcpStream,
+    rbuf: Vec<u8>,
+}
+
+impl Peer {
+    fn accept(listener: &TcpListener) -> Peer {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        Peer {
+            stream,
+            rbuf: vec![],
+        }
+    }
+
+    /// next message, `None` once the channel is closed
+    fn recv(&mut self) -> Option<Message> {
+        loop {
+            if let Some((frame, len)) = split_frame(&self.rbuf).unwrap() {
+                let msg = Message::decode(frame).unwrap();
+                self.rbuf.drain(..len);
+                return Some(msg);
+            }
+            let mut chunk = [0u8; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return None,
+                Ok(n) => self.rbuf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => return None,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    /// id of the next request, which must be `req`
+    fn expect_request(&mut self, req: DataRequest) -> u32 {
+        match self.recv() {
+            Some(Message::Request { id, req: r, .. }) if r == req => id,
+            msg => panic!("expected {:?}, got {:?}", req, msg),
+        }
+    }
+
+    fn send(&mut self, msg: Message) {
+        self.stream.write_all(&msg.encode()).unwrap();
+    }
+}
+
+/// dispatch the connection until `reply` is answered
+fn wait(conn: &Connection, reply: &Reply) -> Response {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(response) = reply.try_take() {
+            return response;
+        }
+        assert!(Instant::now() < deadline, "no response");
+        conn.drive(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn slow_request_keeps_channel() {
+    let listener = TcpListener::bind("127.0.0.1:47391").unwrap();
+    let peer = thread::spawn(move || {
+        let mut peer = Peer::accept(&listener);
+        assert!(matches!(peer.recv(), Some(Message::Hello { port: 47390 })));
+        // never answered
+        peer.expect_request(DataRequest::KeyMap);
+        let id = peer.expect_request(DataRequest::Capabilities);
+        peer.send(Message::Response { id, data: None });
+        let id = peer.expect_request(DataRequest::Clipboard);
+        let data = Some(b"clipboard".to_vec());
+        peer.send(Message::Response { id, data });
+
+        // neither the request nor the keepalive answered
+        peer.expect_request(DataRequest::KeyMap);
+        peer.expect_request(DataRequest::Capabilities);
+        assert!(peer.recv().is_none());
+    });
+    let conn = connection(47390, Position::Left, 47391);
+
+    let start = Instant::now();
+    let reply = conn
+        .request(Position::Left, DataRequest::KeyMap, false)
+        .unwrap();
+    let e = wait(&conn, &reply).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    // answered on the same channel
+    let reply = conn
+        .request(Position::Left, DataRequest::Clipboard, false)
+        .unwrap();
+    assert_eq!(wait(&conn, &reply).unwrap(), Some(b"clipboard".to_vec()));
+
+    let reply = conn
+        .request(Position::Left, DataRequest::KeyMap, false)
+        .unwrap();
+    assert!(wait(&conn, &reply).is_err());
+    let deadline = Instant::now() + TIMEOUT;
+    while !peer.is_finished() {
+        assert!(Instant::now() < deadline, "channel not closed");
+        conn.drive(Duration::from_millis(50));
+    }
+    peer.join().unwrap();
+}
@@ -0,0 +1,309 @@
+//! Event datagrams exchanged with a scripted peer on a UDP socket.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connection, TIMEOUT};
+use lan_mouse::{
+    config::KeyboardMode,
+    event::{self, Capabilities, ControlEvent, Event, KeyboardEvent, PointerEvent},
+    protocol::{Connection, DataRequest, Decode, Encode, PeerInfo, Position},
+    stats::PeerStats,
+};
+use lan_mouse_proto::{
+    datagram::{Ack, Reliable, Sequenced},
+    message::{split_frame, Message},
+    PROTOCOL_VERSION,
+};
+
+const SESSION: u32 = 1;
+
+fn peer(port: u16) -> UdpSocket {
+    let sock = UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    sock.set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    sock
+}
+
+/// reliable datagram carrying `event`
+fn reliable(seq: u32, event: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    Reliable {
+        session: SESSION,
+        first: 0,
+        seq,
+    }
+    .write(&mut buf);
+    buf.extend_from_slice(event);
+    buf
+}
+
+/// numbered datagram carrying `event`
+fn sequenced(seq: u32, event: &Event) -> Vec<u8> {
+    let mut buf = vec![];
+    Sequenced {
+        session: SESSION,
+        seq,
+    }
+    .write(&mut buf);
+    buf.extend_from_slice(&event.encode());
+    buf
+}
+
+/// dispatch the connection until the peer receives an acknowledgement
+fn expect_ack(conn: &Connection, peer: &UdpSocket) -> Ack {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut buf = [0u8; 1500];
+    loop {
+        assert!(Instant::now() < deadline, "no acknowledgement");
+        conn.dispatch();
+        let len = match peer.recv(&mut buf) {
+            Ok(len) => len,
+            Err(_) => continue,
+        };
+        let (_, datagram) = Sequenced::parse(&buf[..len]).unwrap();
+        if let Some(ack) = Ack::parse(datagram) {
+            return ack;
+        }
+    }
+}
+
+/// events of the datagrams the peer receives until none arrive for a while
+fn received(peer: &UdpSocket) -> Vec<Vec<u8>> {
+    let mut buf = [0u8; 1500];
+    let mut events = vec![];
+    while let Ok(len) = peer.recv(&mut buf) {
+        let (_, datagram) = Sequenced::parse(&buf[..len]).unwrap();
+        events.push(datagram.to_vec());
+    }
+    events
+}
+
+/// answer the requests on the control channel like a peer older
+/// than the handshake, which only reports its capabilities
+fn pre_handshake_peer(listener: TcpListener) {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut rbuf = vec![];
+    let mut chunk = [0u8; 1024];
+    loop {
+        while let Some((frame, len)) = split_frame(&rbuf).unwrap() {
+            if let Message::Request { id, req, .. } = Message::decode(frame).unwrap() {
+                let data = match req {
+                    DataRequest::Capabilities => {
+                        Some(u32::from(Capabilities::ALL).to_ne_bytes().to_vec())
+                    }
+                    _ => None,
+                };
+                let response = Message::Response { id, data };
+                stream.write_all(&response.encode()).unwrap();
+            }
+            rbuf.drain(..len);
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => rbuf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+fn stats(conn: &Connection) -> PeerStats {
+    let peers = conn.stats().peers();
+    let (pos, stats) = &peers[0];
+    assert_eq!(*pos, Position::Left);
+    stats.clone()
+}
+
+#[test]
+fn reliable_events() {
+    let peer = peer(47401);
+    let conn = connection(47400, Position::Left, 47401);
+    peer.connect("127.0.0.1:47400").unwrap();
+
+    // not an event, but acknowledged anyway
+    peer.send(&reliable(0, &[0xff])).unwrap();
+    let ack = expect_ack(&conn, &peer);
+    assert_eq!((ack.session, ack.next), (SESSION, 1));
+    assert_eq!(stats(&conn).decode_errors, 1);
+
+    let key = Event::Keyboard(KeyboardEvent::Key {
+        time: 1,
+        key: 30,
+        state: event::KEY_PRESSED,
+    });
+    // retransmitted, delivered and counted once
+    for _ in 0..2 {
+        peer.send(&reliable(1, &key.encode())).unwrap();
+        let ack = expect_ack(&conn, &peer);
+        assert_eq!((ack.session, ack.next), (SESSION, 2));
+    }
+    assert_eq!(conn.receive_event(), Some(key));
+    assert_eq!(conn.receive_event(), None);
+    let stats = stats(&conn);
+    assert_eq!(stats.events_received.get("key"), Some(&1));
+    assert_eq!(stats.duplicates, 1);
+}
+
+fn local_info() -> PeerInfo {
+    PeerInfo {
+        hostname: "local".into(),
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::ALL,
+        backend: "scripted".into(),
+        outputs: vec![],
+        keymap_format: event::KEYMAP_NONE,
+        keyboard: KeyboardMode::Keycode,
+    }
+}
+
+#[test]
+fn late_motion() {
+    let peer = peer(47441);
+    let conn = connection(47440, Position::Left, 47441);
+    peer.connect("127.0.0.1:47440").unwrap();
+
+    let motion = Event::Pointer(PointerEvent::Motion {
+        time: 1,
+        dx: 1.0,
+        dy: 1.0,
+    });
+    let absolute = Event::Pointer(PointerEvent::Absolute {
+        time: 2,
+        x: 0.5,
+        y: 0.5,
+    });
+    let axis = Event::Pointer(PointerEvent::Axis {
+        time: 3,
+        axis: 0,
+        value: 1.0,
+    });
+    let frame = Event::Pointer(PointerEvent::Frame);
+    peer.send(&sequenced(0, &frame)).unwrap();
+    peer.send(&sequenced(4, &frame)).unwrap();
+    // overtaken by the second frame
+    for (seq, event) in [(1, &motion), (2, &absolute), (3, &axis)] {
+        peer.send(&sequenced(seq, event)).unwrap();
+    }
+
+    // the position is outdated, scrolling is not
+    let deadline = Instant::now() + TIMEOUT;
+    let mut events = vec![];
+    while events.len() < 3 {
+        assert!(Instant::now() < deadline, "only received {:?}", events);
+        conn.drive(Duration::from_millis(50));
+        events.extend(std::iter::from_fn(|| conn.receive_event()));
+    }
+    assert_eq!(events, [frame, frame, axis]);
+    assert_eq!(stats(&conn).reordered, 3);
+}
+
+#[test]
+fn older_peer() {
+    let peer = peer(47411);
+    let conn = connection(47410, Position::Left, 47411);
+    peer.connect("127.0.0.1:47410").unwrap();
+
+    // handshake of a peer speaking version 3
+    let info = PeerInfo {
+        hostname: "peer".into(),
+        version: 3,
+        capabilities: Capabilities::ALL,
+        backend: "scripted".into(),
+        outputs: vec![],
+        keymap_format: event::KEYMAP_NONE,
+        keyboard: KeyboardMode::Keycode,
+    };
+    let mut channel = TcpStream::connect("127.0.0.1:47410").unwrap();
+    let hello = Message::Hello { port: 47411 };
+    let handshake = Message::Request {
+        id: 0,
+        req: DataRequest::Handshake,
+        payload: info.encode(),
+    };
+    channel.write_all(&hello.encode()).unwrap();
+    channel.write_all(&handshake.encode()).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    while conn.peers().info(Position::Left).is_none() {
+        assert!(Instant::now() < deadline, "no handshake");
+        conn.drive(Duration::from_millis(50));
+    }
+    assert_eq!(conn.peers().version(Position::Left), 3);
+
+    let motion = Event::Pointer(PointerEvent::Motion {
+        time: 1,
+        dx: 1.5,
+        dy: -2.0,
+    });
+    let absolute = Event::Pointer(PointerEvent::Absolute {
+        time: 2,
+        x: 0.5,
+        y: 0.5,
+    });
+    let frame = Event::Pointer(PointerEvent::Frame);
+    conn.send_event_to(Position::Left, Event::Control(ControlEvent::Enter));
+    conn.send_event_to(Position::Left, motion);
+    conn.send_event_to(Position::Left, absolute);
+    conn.send_event_to(Position::Left, frame);
+
+    // motion in the old encoding and not batched,
+    // absolute motion and enter skipped
+    let events = received(&peer);
+    assert_eq!(events.len(), 2, "{:?}", events);
+    assert_eq!(events[0][0], event::MOTION_F64);
+    assert_eq!(Event::decode(&events[0]), Ok(motion));
+    assert_eq!(events[1], frame.encode());
+    let stats = stats(&conn);
+    assert_eq!(stats.events_sent.get("enter"), None);
+    assert_eq!(stats.events_sent.get("absolute"), None);
+}
+
+#[test]
+fn peer_without_handshake() {
+    let listener = TcpListener::bind("127.0.0.1:47431").unwrap();
+    thread::spawn(move || pre_handshake_peer(listener));
+    let peer = peer(47431);
+    let conn = connection(47430, Position::Left, 47431);
+    conn.set_local_info(&local_info());
+
+    // the handshake is answered without info,
+    // so the capabilities are queried instead
+    assert_eq!(conn.peers().version(Position::Left), PROTOCOL_VERSION);
+    assert_eq!(conn.handshake(Position::Left), None);
+    let deadline = Instant::now() + TIMEOUT;
+    while conn
+        .peers()
+        .get(Position::Left)
+        .unwrap()
+        .capabilities
+        .is_none()
+    {
+        assert!(Instant::now() < deadline, "no capabilities");
+        conn.drive(Duration::from_millis(50));
+    }
+    assert_eq!(conn.peers().version(Position::Left), 1);
+
+    let motion = Event::Pointer(PointerEvent::Motion {
+        time: 1,
+        dx: 1.5,
+        dy: -2.0,
+    });
+    let absolute = Event::Pointer(PointerEvent::Absolute {
+        time: 2,
+        x: 0.5,
+        y: 0.5,
+    });
+    conn.send_event_to(Position::Left, Event::Control(ControlEvent::Enter));
+    conn.send_event_to(Position::Left, motion);
+    conn.send_event_to(Position::Left, absolute);
+
+    // only the oldest encoding
+    let events = received(&peer);
+    assert_eq!(events.len(), 1, "{:?}", events);
+    assert_eq!(events[0][0], event::MOTION_F64);
+    assert_eq!(Event::decode(&events[0]), Ok(motion));
+}
@@ -0,0 +1,272 @@
+//! The D-Bus interface, exported on a private `dbus-daemon`.
+//! Skipped if `dbus-daemon` is not installed.
+
+mod common;
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::TcpStream,
+    os::unix::prelude::AsRawFd,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connection, private_bus, TIMEOUT};
+use lan_mouse::{
+    dbus::{self, Command, Role},
+    poll,
+    protocol::{Connection, DataRequest, Encode, Position},
+};
+use lan_mouse_proto::message::Message;
+use zbus::{
+    blocking::{self, MessageIterator},
+    dbus_proxy,
+    zvariant::OwnedValue,
+    CacheProperties, MatchRule, MessageType,
+};
+
+#[dbus_proxy(
+    interface = "org.lanmouse.Daemon",
+    default_path = "/org/lanmouse/Daemon"
+)]
+trait Daemon {
+    fn switch_to_peer(&self, peer: &str) -> zbus::Result<()>;
+
+    fn release(&self) -> zbus::Result<()>;
+
+    fn disable_peer(&self, peer: &str) -> zbus::Result<()>;
+
+    #[dbus_proxy(property)]
+    fn active_peer(&self) -> zbus::Result<String>;
+
+    #[dbus_proxy(property)]
+    fn peers(&self) -> zbus::Result<Vec<(String, String, bool, bool)>>;
+}
+
+fn proxy<'a>(conn: &blocking::Connection, name: &'a str) -> DaemonProxyBlocking<'a> {
+    DaemonProxyBlocking::builder(conn)
+        .destination(name)
+        .unwrap()
+        .cache_properties(CacheProperties::No)
+        .build()
+        .unwrap()
+}
+
+/// Signals of the daemon as (member, argument),
+/// `PropertiesChanged` once for every changed property.
+fn signals(conn: &blocking::Connection) -> mpsc::Receiver<(String, String)> {
+    let rule = MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .path(dbus::PATH)
+        .unwrap()
+        .build();
+    let messages = MessageIterator::for_match_rule(rule, conn, None).unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for msg in messages {
+            let msg = msg.unwrap();
+            let member = msg.member().unwrap().to_string();
+            let args = match member.as_str() {
+                "PropertiesChanged" => {
+                    let (_, changed, _): (String, HashMap<String, OwnedValue>, Vec<String>) =
+                        msg.body().unwrap();
+                    changed.into_keys().collect()
+                }
+                _ => vec![msg.body::<String>().unwrap()],
+            };
+            for arg in args {
+                if tx.send((member.clone(), arg)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// wait for the signal `member` with `arg`, skipping others
+fn expect(signals: &mpsc::Receiver<(String, String)>, member: &str, arg: &str) {
+    loop {
+        let (m, a) = signals
+            .recv_timeout(TIMEOUT)
+            .unwrap_or_else(|_| panic!("no {}({:?})", member, arg));
+        if m == member && a == arg {
+            return;
+        }
+    }
+}
+
+/// Stand-in for the event loop of the server,
+/// switches the peers without any capture backend.
+fn event_loop(connection: &Connection, commands: poll::Receiver<Command>) {
+    let peers = connection.peers();
+    thread::spawn(move || loop {
+        poll::wait(&poll::readable([commands.as_raw_fd()]), None).unwrap();
+        match commands.try_recv() {
+            Ok(Command::SwitchTo(pos, reply)) => {
+                let _ = reply.send(peers.switch_to(pos).map_err(|e| e.to_string()));
+            }
+            Ok(Command::Release) => peers.release(),
+            Err(mpsc::TryRecvError::Empty) => {}
+            Err(mpsc::TryRecvError::Disconnected) => return,
+        }
+    });
+}
+
+fn error_name(e: zbus::Error) -> String {
+    match e {
+        zbus::Error::MethodError(name, _, _) => name.to_string(),
+        e => panic!("not a method error: {}", e),
+    }
+}
+
+#[test]
+fn roles_have_their_own_names() {
+    let bus = match private_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let server = connection(47300, Position::Right, 47301);
+    let client = connection(47310, Position::Right, 47311);
+    let (commands, _rx) = poll::channel().unwrap();
+    let _server = dbus::serve(Role::Server(commands), server.peers(), server.stats()).unwrap();
+    let _client = dbus::serve(Role::Client, client.peers(), client.stats()).unwrap();
+
+    let conn = blocking::ConnectionBuilder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let bus_proxy = zbus::blocking::fdo::DBusProxy::new(&conn).unwrap();
+    for name in ["org.lanmouse.Server", "org.lanmouse.Client"] {
+        assert!(bus_proxy.name_has_owner(name.try_into().unwrap()).unwrap());
+    }
+}
+
+#[test]
+fn switching_goes_through_the_event_loop() {
+    let bus = match private_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let server = connection(47320, Position::Right, 47321);
+    let (commands, rx) = poll::channel().unwrap();
+    let _service = dbus::serve(Role::Server(commands), server.peers(), server.stats()).unwrap();
+    event_loop(&server, rx);
+
+    let conn = blocking::ConnectionBuilder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let signals = signals(&conn);
+    let daemon = proxy(&conn, "org.lanmouse.Server");
+
+    daemon.switch_to_peer("right").unwrap();
+    assert_eq!(server.peers().active().unwrap().to_string(), "right");
+    assert_eq!(daemon.active_peer().unwrap(), "right");
+    expect(&signals, "FocusMoved", "right");
+    expect(&signals, "PropertiesChanged", "ActivePeer");
+
+    let e = daemon.switch_to_peer("left").unwrap_err();
+    assert_eq!(error_name(e), "org.freedesktop.DBus.Error.Failed");
+    let e = daemon.switch_to_peer("nowhere").unwrap_err();
+    assert_eq!(error_name(e), "org.freedesktop.DBus.Error.InvalidArgs");
+
+    daemon.release().unwrap();
+    expect(&signals, "FocusMoved", "");
+    expect(&signals, "PropertiesChanged", "ActivePeer");
+    assert_eq!(daemon.active_peer().unwrap(), "");
+}
+
+#[test]
+fn client_does_not_capture() {
+    let bus = match private_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let client = connection(47330, Position::Right, 47331);
+    let _service = dbus::serve(Role::Client, client.peers(), client.stats()).unwrap();
+
+    let conn = blocking::ConnectionBuilder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let daemon = proxy(&conn, "org.lanmouse.Client");
+    let e = daemon.switch_to_peer("right").unwrap_err();
+    assert_eq!(error_name(e), "org.freedesktop.DBus.Error.NotSupported");
+    assert_eq!(client.peers().active(), None);
+}
+
+#[test]
+fn disabling_changes_peers() {
+    let bus = match private_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let server = connection(47340, Position::Right, 47341);
+    let (commands, _rx) = poll::channel().unwrap();
+    let _service = dbus::serve(Role::Server(commands), server.peers(), server.stats()).unwrap();
+
+    let conn = blocking::ConnectionBuilder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let signals = signals(&conn);
+    let daemon = proxy(&conn, "org.lanmouse.Server");
+    let peers = daemon.peers().unwrap();
+    assert_eq!(
+        peers,
+        [(
+            "right".to_string(),
+            "127.0.0.1:47341".to_string(),
+            true,
+            false
+        )]
+    );
+
+    daemon.disable_peer("right").unwrap();
+    expect(&signals, "PropertiesChanged", "Peers");
+    assert!(!daemon.peers().unwrap()[0].2);
+}
+
+#[test]
+fn clipboard_synced() {
+    let bus = match private_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let server = connection(47420, Position::Right, 47421);
+    server.offer_data(DataRequest::Clipboard, b"clipboard");
+    let (commands, _rx) = poll::channel().unwrap();
+    let _service = dbus::serve(Role::Server(commands), server.peers(), server.stats()).unwrap();
+
+    let conn = blocking::ConnectionBuilder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let signals = signals(&conn);
+
+    // the peer on the right fetches the clipboard
+    let mut channel = TcpStream::connect("127.0.0.1:47420").unwrap();
+    let hello = Message::Hello { port: 47421 };
+    let request = Message::Request {
+        id: 0,
+        req: DataRequest::Clipboard,
+        payload: vec![],
+    };
+    channel.write_all(&hello.encode()).unwrap();
+    channel.write_all(&request.encode()).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        assert!(Instant::now() < deadline, "no ClipboardSynced");
+        server.drive(Duration::from_millis(50));
+        match signals.try_recv() {
+            Ok((member, peer)) if member == "ClipboardSynced" => {
+                assert_eq!(peer, "right");
+                return;
+            }
+            _ => {}
+        }
+    }
+}
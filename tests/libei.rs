@@ -0,0 +1,81 @@
+//! libei emulation against a scripted EIS implementation on `LIBEI_SOCKET`,
+//! checking the requests on the wire.
+#![cfg(feature = "libei")]
+
+mod common;
+
+use std::{env, os::unix::net::UnixListener, thread};
+
+use common::{
+    eis::{new_id, u32s, Eis, CONNECTION, DEVICE, KEYBOARD, PING, POINTER},
+    TIMEOUT,
+};
+use lan_mouse::{
+    emulation::{libei::LibeiEmulation, InputEmulation},
+    event::{self, Event, KeyboardEvent, PointerEvent},
+};
+
+/// expect `ei_device.frame` with the given serial
+fn expect_frame(eis: &mut Eis, serial: u32) {
+    let (object, opcode, args) = eis.recv();
+    assert_eq!((object, opcode), (DEVICE, 3));
+    assert_eq!(args[..4], serial.to_ne_bytes());
+}
+
+#[test]
+fn scripted_eis() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("eis-0");
+    let listener = UnixListener::bind(&path).unwrap();
+    env::set_var("LIBEI_SOCKET", &path);
+    let eis = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut eis = Eis(stream);
+        eis.handshake(2);
+        eis.offer_seat();
+        eis.expect_bind();
+        eis.add_device(10);
+        eis
+    });
+    let mut emulation = LibeiEmulation::new().unwrap();
+    let mut eis = eis.join().unwrap();
+
+    emulation.consume(Event::Pointer(PointerEvent::Motion {
+        time: 0,
+        dx: 1.5,
+        dy: -2.0,
+    }));
+    // ei_device.start_emulating with the serial of resumed, sequence 1
+    assert_eq!(eis.recv(), (DEVICE, 1, u32s(&[10, 1])));
+    // ei_pointer.motion_relative
+    let motion = [1.5f32.to_ne_bytes(), (-2.0f32).to_ne_bytes()].concat();
+    assert_eq!(eis.recv(), (POINTER, 1, motion));
+    expect_frame(&mut eis, 10);
+
+    emulation.consume(Event::Keyboard(KeyboardEvent::Key {
+        time: 0,
+        key: 30,
+        state: event::KEY_PRESSED,
+    }));
+    // ei_keyboard.key, still emulating
+    assert_eq!(eis.recv(), (KEYBOARD, 1, u32s(&[30, 1])));
+    expect_frame(&mut eis, 10);
+
+    // ei_connection.ping is answered by ei_pingpong.done
+    eis.send(CONNECTION, 3, &new_id(PING));
+    emulation.dispatch().unwrap();
+    assert_eq!(eis.recv(), (PING, 0, 0u64.to_ne_bytes().to_vec()));
+
+    // paused and resumed, emulation starts over
+    eis.send(DEVICE, 8, &u32s(&[11]));
+    eis.send(DEVICE, 7, &u32s(&[12]));
+    emulation.consume(Event::Keyboard(KeyboardEvent::Key {
+        time: 0,
+        key: 30,
+        state: event::KEY_RELEASED,
+    }));
+    assert_eq!(eis.recv(), (DEVICE, 1, u32s(&[12, 2])));
+    assert_eq!(eis.recv(), (KEYBOARD, 1, u32s(&[30, 0])));
+    expect_frame(&mut eis, 12);
+}
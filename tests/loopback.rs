@@ -0,0 +1,232 @@
+//! Server and client on the loopback interface, both with the in-memory
+//! backends: input injected into the capture comes out of the emulation.
+
+mod common;
+
+use std::{
+    io::Write,
+    os::unix::prelude::AsRawFd,
+    sync::mpsc::{self, Receiver},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use common::{connection, TIMEOUT};
+use lan_mouse::{
+    capture::{self, CaptureEvent},
+    client::Client,
+    dbus::Command,
+    emulation::{self, memory::Emulated},
+    event::{self, Capabilities, Event, KeyboardEvent, PointerEvent},
+    poll,
+    protocol::{self, KeymapState, Position},
+    server::Server,
+};
+use memmap::Mmap;
+
+struct ServerHandle {
+    capture: poll::Sender<CaptureEvent>,
+    commands: poll::Sender<Command>,
+    thread: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// inject captured input
+    fn capture(&self, e: CaptureEvent) {
+        self.capture.send(e).unwrap();
+    }
+
+    /// stop the server by closing its capture backend
+    fn stop(self) {
+        drop(self.capture);
+        self.thread.join().unwrap();
+    }
+}
+
+/// run a server with the client on its right
+fn spawn_server(port: u16, client_port: u16) -> ServerHandle {
+    let (tx, rx) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        let (capture, inject) = capture::memory::new().unwrap();
+        let (commands_tx, commands) = poll::channel().unwrap();
+        let connection = connection(port, Position::Right, client_port);
+        let mut server = Server::new(connection, Box::new(capture));
+        tx.send((inject, commands_tx)).unwrap();
+        loop {
+            let mut fds = poll::readable([commands.as_raw_fd()]);
+            fds.extend(server.fds());
+            poll::wait(&fds, server.timeout()).unwrap();
+            while let Ok(c) = commands.try_recv() {
+                server.command(c);
+            }
+            if server.dispatch().is_err() {
+                return;
+            }
+        }
+    });
+    let (capture, commands) = rx.recv().unwrap();
+    ServerHandle {
+        capture,
+        commands,
+        thread,
+    }
+}
+
+/// a client with the server on its left
+fn client(port: u16, server_port: u16) -> (Client, Receiver<Emulated>) {
+    let (emulation, emulated) = emulation::memory::new();
+    let connection = connection(port, Position::Left, server_port);
+    (Client::new(connection, Box::new(emulation), None), emulated)
+}
+
+/// wait for the client's descriptors, at most briefly, and dispatch
+fn step(client: &mut Client) {
+    let timeout = client.timeout().unwrap_or(TIMEOUT);
+    poll::wait(&client.fds(), Some(timeout.min(Duration::from_millis(50)))).unwrap();
+    client.dispatch().unwrap();
+}
+
+/// Run the client until `n` events are emulated, keymaps are skipped.
+fn emulated(client: &mut Client, emulated: &Receiver<Emulated>, n: usize) -> Vec<Event> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut events = vec![];
+    while events.len() < n {
+        assert!(Instant::now() < deadline, "only emulated {:?}", events);
+        step(client);
+        events.extend(emulated.try_iter().filter_map(|e| match e {
+            Emulated::Event(e) => Some(e),
+            Emulated::KeyMap(_) => None,
+        }));
+    }
+    events
+}
+
+/// Offer a keymap on the server and wait until the client installed it,
+/// keys are dropped until then.
+fn exchange_keymap(server: &ServerHandle, client: &mut Client, emulated: &Receiver<Emulated>) {
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(b"xkb_keymap { };\n").unwrap();
+    let mmap = unsafe { Mmap::map(&file) }.unwrap();
+    server.capture(CaptureEvent::KeyMap(mmap));
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        assert!(Instant::now() < deadline, "keymap not installed");
+        step(client);
+        let installed = emulated
+            .try_iter()
+            .any(|e| e == Emulated::KeyMap(b"xkb_keymap { };\n".to_vec()));
+        if installed {
+            return;
+        }
+    }
+}
+
+fn key(time: u32, state: u32) -> Event {
+    Event::Keyboard(KeyboardEvent::Key {
+        time,
+        key: 30,
+        state,
+    })
+}
+
+fn button(time: u32, state: u32) -> Event {
+    Event::Pointer(PointerEvent::Button {
+        time,
+        button: event::BTN_LEFT,
+        state,
+    })
+}
+
+#[test]
+fn grabbed_input_is_emulated() {
+    let server = spawn_server(47360, 47361);
+    let (mut client, rx) = client(47361, 47360);
+    exchange_keymap(&server, &mut client, &rx);
+
+    server.capture(CaptureEvent::Begin(Position::Right));
+    let input = [
+        Event::Pointer(PointerEvent::Motion {
+            time: 1,
+            dx: 1.5,
+            dy: -2.0,
+        }),
+        Event::Pointer(PointerEvent::Frame),
+        button(2, event::BUTTON_PRESSED),
+        key(3, event::KEY_PRESSED),
+    ];
+    for e in input {
+        server.capture(CaptureEvent::Input(e));
+    }
+    assert_eq!(emulated(&mut client, &rx, input.len()), input);
+
+    // held keys and buttons are released on Leave
+    server.capture(CaptureEvent::End);
+    assert_eq!(
+        emulated(&mut client, &rx, 2),
+        [
+            key(0, event::KEY_RELEASED),
+            button(0, event::BUTTON_RELEASED)
+        ]
+    );
+    let stats = client.connection().stats().peers();
+    let (pos, stats) = &stats[0];
+    assert_eq!(*pos, Position::Left);
+    assert_eq!(stats.events_received.get("enter"), Some(&1));
+    assert_eq!(stats.events_received.get("leave"), Some(&1));
+    server.stop();
+}
+
+#[test]
+fn switching_grabs_and_releases() {
+    let server = spawn_server(47370, 47371);
+    let (mut client, rx) = client(47371, 47370);
+    exchange_keymap(&server, &mut client, &rx);
+
+    // not grabbed, input is not forwarded
+    server.capture(CaptureEvent::Input(key(1, event::KEY_PRESSED)));
+
+    let (reply, result) = mpsc::channel();
+    server
+        .commands
+        .send(Command::SwitchTo(Position::Top, reply.clone()))
+        .unwrap();
+    assert!(result.recv_timeout(TIMEOUT).unwrap().is_err());
+    server
+        .commands
+        .send(Command::SwitchTo(Position::Right, reply))
+        .unwrap();
+    assert_eq!(result.recv_timeout(TIMEOUT).unwrap(), Ok(()));
+
+    server.capture(CaptureEvent::Input(key(2, event::KEY_PRESSED)));
+    assert_eq!(emulated(&mut client, &rx, 1), [key(2, event::KEY_PRESSED)]);
+    server.commands.send(Command::Release).unwrap();
+    assert_eq!(emulated(&mut client, &rx, 1), [key(0, event::KEY_RELEASED)]);
+    server.stop();
+}
+
+#[test]
+fn handshake_is_retried() {
+    let (mut client, _rx) = client(47381, 47380);
+    // fails without server, including the fallback to capabilities
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(1500) {
+        step(&mut client);
+    }
+    assert!(client.connection().peers().info(Position::Left).is_none());
+
+    // a server that never starts a handshake itself
+    let server = connection(47380, Position::Right, 47381);
+    let info = protocol::local_info("memory", Capabilities::NONE, event::KEYMAP_NONE);
+    server.set_local_info(&info);
+    let deadline = Instant::now() + TIMEOUT;
+    while client.connection().peers().info(Position::Left).is_none() {
+        assert!(Instant::now() < deadline, "handshake not retried");
+        server.dispatch();
+        step(&mut client);
+    }
+    let peers = client.connection().peers();
+    assert_eq!(
+        peers.get(Position::Left).unwrap().keymap,
+        KeymapState::Unused
+    );
+}
@@ -0,0 +1,36 @@
+//! The prometheus endpoint.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use lan_mouse::stats::{self, Stats};
+
+fn scrape(port: u16) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn serves_metrics() {
+    stats::serve_http(Stats::new(), 47350).unwrap();
+    let response = scrape(47350);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+}
+
+#[test]
+fn silent_client_does_not_block_others() {
+    stats::serve_http(Stats::new(), 47351).unwrap();
+    // connects, but never sends a request
+    let _silent = TcpStream::connect(("127.0.0.1", 47351)).unwrap();
+    let start = Instant::now();
+    let response = scrape(47351);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,269 @@
+//! InputCapture portal capture against a mock portal on a private
+//! `dbus-daemon` and a scripted EIS implementation.
+//! Skipped if `dbus-daemon` is not installed.
+#![cfg(feature = "libei")]
+
+mod common;
+
+use std::{
+    collections::HashMap,
+    os::unix::{net::UnixStream, prelude::IntoRawFd},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{
+    eis::{u32s, Eis, DEVICE, KEYBOARD, POINTER},
+    private_bus, TIMEOUT,
+};
+use lan_mouse::{
+    capture::{portal::PortalCapture, CaptureEvent, InputCapture, RELEASE_MODIFIERS},
+    event::{self, Event, KeyboardEvent, PointerEvent},
+    poll,
+    protocol::Position,
+};
+use zbus::{
+    blocking, dbus_interface,
+    names::BusName,
+    zvariant::{ObjectPath, OwnedFd, OwnedObjectPath, OwnedValue, Structure, Value},
+    MessageHeader,
+};
+
+const DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PATH: &str = "/org/freedesktop/portal/desktop";
+const SESSION: &str = "/org/freedesktop/portal/desktop/session/1/lan_mouse";
+
+/// Calls of the backend, as seen by the portal
+#[derive(Debug, PartialEq)]
+enum Call {
+    SetPointerBarriers(Vec<(u32, (i32, i32, i32, i32))>),
+    Enable,
+    Release(Option<u32>),
+}
+
+/// InputCapture portal with two monitors side by side
+struct MockPortal {
+    calls: mpsc::Sender<Call>,
+    /// EIS end of the connection of every `ConnectToEIS`
+    eis: mpsc::Sender<UnixStream>,
+}
+
+/// emit the `Response` of the request with the caller's `handle_token`
+async fn respond(
+    conn: &zbus::Connection,
+    header: &MessageHeader<'_>,
+    options: &HashMap<String, OwnedValue>,
+    results: HashMap<&str, Value<'_>>,
+) -> OwnedObjectPath {
+    let sender = header.sender().unwrap().unwrap();
+    let token: &str = options["handle_token"].downcast_ref().unwrap();
+    let path = format!(
+        "{}/request/{}/{}",
+        PATH,
+        sender.trim_start_matches(':').replace('.', "_"),
+        token
+    );
+    conn.emit_signal(
+        None::<BusName<'_>>,
+        path.as_str(),
+        "org.freedesktop.portal.Request",
+        "Response",
+        &(0u32, results),
+    )
+    .await
+    .unwrap();
+    OwnedObjectPath::try_from(path).unwrap()
+}
+
+#[dbus_interface(name = "org.freedesktop.portal.InputCapture")]
+impl MockPortal {
+    async fn create_session(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] conn: &zbus::Connection,
+        _parent_window: &str,
+        options: HashMap<String, OwnedValue>,
+    ) -> OwnedObjectPath {
+        let session = ObjectPath::try_from(SESSION).unwrap();
+        let results = HashMap::from([("session_handle", Value::from(session))]);
+        respond(conn, &header, &options, results).await
+    }
+
+    async fn get_zones(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] conn: &zbus::Connection,
+        _session: OwnedObjectPath,
+        options: HashMap<String, OwnedValue>,
+    ) -> OwnedObjectPath {
+        let zones = vec![(1920u32, 1080u32, 0i32, 0i32), (1280, 1024, 1920, 0)];
+        let results = HashMap::from([
+            ("zones", Value::from(zones)),
+            ("zone_set", Value::from(1u32)),
+        ]);
+        respond(conn, &header, &options, results).await
+    }
+
+    async fn set_pointer_barriers(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] conn: &zbus::Connection,
+        _session: OwnedObjectPath,
+        options: HashMap<String, OwnedValue>,
+        barriers: Vec<HashMap<String, OwnedValue>>,
+        _zone_set: u32,
+    ) -> OwnedObjectPath {
+        let barriers = barriers
+            .into_iter()
+            .map(|b| {
+                let id: u32 = b["barrier_id"].clone().try_into().unwrap();
+                let position: Structure = b["position"].clone().try_into().unwrap();
+                (id, position.try_into().unwrap())
+            })
+            .collect();
+        self.calls.send(Call::SetPointerBarriers(barriers)).unwrap();
+        let results = HashMap::from([("failed_barriers", Value::from(Vec::<u32>::new()))]);
+        respond(conn, &header, &options, results).await
+    }
+
+    fn enable(&self, _session: OwnedObjectPath, _options: HashMap<String, OwnedValue>) {
+        self.calls.send(Call::Enable).unwrap();
+    }
+
+    fn release(&self, _session: OwnedObjectPath, options: HashMap<String, OwnedValue>) {
+        let activation = options
+            .get("activation_id")
+            .map(|id| id.clone().try_into().unwrap());
+        self.calls.send(Call::Release(activation)).unwrap();
+    }
+
+    #[dbus_interface(name = "ConnectToEIS")]
+    fn connect_to_eis(
+        &self,
+        _session: OwnedObjectPath,
+        _options: HashMap<String, OwnedValue>,
+    ) -> OwnedFd {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        self.eis.send(ours).unwrap();
+        unsafe { std::os::fd::FromRawFd::from_raw_fd(theirs.into_raw_fd()) }
+    }
+}
+
+/// dispatch until the backend reports an event
+fn next_event(capture: &mut PortalCapture) -> CaptureEvent {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(e) = capture.next_event() {
+            return e;
+        }
+        assert!(Instant::now() < deadline, "no capture event");
+        let fds = poll::readable(capture.fds());
+        poll::wait(&fds, Some(Duration::from_millis(50))).unwrap();
+        capture.dispatch().unwrap();
+    }
+}
+
+fn next_input(capture: &mut PortalCapture) -> Event {
+    match next_event(capture) {
+        CaptureEvent::Input(e) => e,
+        _ => panic!("not an input event"),
+    }
+}
+
+#[test]
+fn barrier_hit_captures_input() {
+    let bus = match private_bus() {
+        Some(bus) => bus,
+        None => return,
+    };
+    let (calls_tx, calls) = mpsc::channel();
+    let (eis_tx, eis) = mpsc::channel();
+    let portal = MockPortal {
+        calls: calls_tx,
+        eis: eis_tx,
+    };
+    let conn = blocking::ConnectionBuilder::address(bus.address.as_str())
+        .unwrap()
+        .name(DESTINATION)
+        .unwrap()
+        .serve_at(PATH, portal)
+        .unwrap()
+        .build()
+        .unwrap();
+    let eis = thread::spawn(move || {
+        let stream = eis.recv_timeout(TIMEOUT).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut eis = Eis(stream);
+        eis.handshake(1);
+        eis
+    });
+    let mut capture = PortalCapture::new(&[Position::Right, Position::Top]).unwrap();
+    let mut eis = eis.join().unwrap();
+
+    // only the outer edges of the monitors
+    let barriers = vec![
+        (1, (0, 0, 1919, 0)),
+        (2, (3200, 0, 3200, 1023)),
+        (3, (1920, 0, 3199, 0)),
+    ];
+    assert_eq!(
+        calls.recv_timeout(TIMEOUT).unwrap(),
+        Call::SetPointerBarriers(barriers)
+    );
+    assert_eq!(calls.recv_timeout(TIMEOUT).unwrap(), Call::Enable);
+
+    eis.offer_seat();
+    capture.dispatch().unwrap();
+    eis.expect_bind();
+    eis.add_device(1);
+
+    let options = HashMap::from([
+        ("activation_id", Value::from(7u32)),
+        ("barrier_id", Value::from(2u32)),
+    ]);
+    let session = ObjectPath::try_from(SESSION).unwrap();
+    conn.emit_signal(
+        None::<BusName<'_>>,
+        PATH,
+        "org.freedesktop.portal.InputCapture",
+        "Activated",
+        &(session, options),
+    )
+    .unwrap();
+    assert!(matches!(
+        next_event(&mut capture),
+        CaptureEvent::Begin(Position::Right)
+    ));
+
+    // ei_pointer.motion_relative, ei_keyboard.key, ei_device.frame
+    let motion = [1.5f32.to_ne_bytes(), (-2.0f32).to_ne_bytes()].concat();
+    eis.send(POINTER, 1, &motion);
+    eis.send(KEYBOARD, 2, &u32s(&[30, 1]));
+    eis.send(
+        DEVICE,
+        11,
+        &[&u32s(&[2])[..], &3000u64.to_ne_bytes()].concat(),
+    );
+    let motion = Event::Pointer(PointerEvent::Motion {
+        time: 3,
+        dx: 1.5,
+        dy: -2.0,
+    });
+    let key = Event::Keyboard(KeyboardEvent::Key {
+        time: 3,
+        key: 30,
+        state: event::KEY_PRESSED,
+    });
+    assert_eq!(next_input(&mut capture), motion);
+    assert_eq!(next_input(&mut capture), key);
+    assert_eq!(
+        next_input(&mut capture),
+        Event::Pointer(PointerEvent::Frame)
+    );
+
+    // ei_keyboard.modifiers with the release shortcut
+    eis.send(KEYBOARD, 3, &u32s(&[3, RELEASE_MODIFIERS, 0, 0, 0]));
+    assert!(matches!(next_event(&mut capture), CaptureEvent::End));
+    assert_eq!(calls.recv_timeout(TIMEOUT).unwrap(), Call::Release(Some(7)));
+}
@@ -0,0 +1,126 @@
+//! Recordings as end-to-end fixtures: replayed into the memory backend,
+//! a recording always produces the same input.
+
+use std::{
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+use lan_mouse::{
+    emulation::memory::{self, Emulated},
+    event::{self, ControlEvent, Event, KeyboardEvent, PointerEvent},
+    recording::{self, Player, Record, Recorder},
+};
+
+/// Keymap, Enter, motion, left button and `a` pressed,
+/// `a` released, shift pressed, Leave.
+/// Records are 10ms apart.
+const SESSION: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/session.lmrec");
+
+fn key(time: u32, key: u32, state: u32) -> Emulated {
+    Emulated::Event(Event::Keyboard(KeyboardEvent::Key { time, key, state }))
+}
+
+fn button(time: u32, state: u32) -> Emulated {
+    Emulated::Event(Event::Pointer(PointerEvent::Button {
+        time,
+        button: event::BTN_LEFT,
+        state,
+    }))
+}
+
+fn frame() -> Emulated {
+    Emulated::Event(Event::Pointer(PointerEvent::Frame))
+}
+
+fn replay(path: &str, speed: f64) -> Vec<Emulated> {
+    let (mut emulation, rx): (_, Receiver<Emulated>) = memory::new();
+    recording::replay(path, speed, &mut emulation).unwrap();
+    drop(emulation);
+    rx.iter().collect()
+}
+
+#[test]
+fn session_fixture() {
+    let expected = [
+        Emulated::KeyMap(b"xkb_keymap { };\n".to_vec()),
+        Emulated::Event(Event::Pointer(PointerEvent::Motion {
+            time: 1,
+            dx: 1.5,
+            dy: -2.0,
+        })),
+        frame(),
+        button(2, event::BUTTON_PRESSED),
+        frame(),
+        key(3, 30, event::KEY_PRESSED),
+        key(4, 30, event::KEY_RELEASED),
+        key(5, 42, event::KEY_PRESSED),
+        // released on Leave
+        key(0, 42, event::KEY_RELEASED),
+        button(0, event::BUTTON_RELEASED),
+    ];
+    assert_eq!(replay(SESSION, 0.0), expected);
+    assert_eq!(replay(SESSION, 0.0), expected);
+}
+
+#[test]
+fn original_timing() {
+    let mut player = Player::open(SESSION).unwrap();
+    let mut last = Duration::ZERO;
+    while let Some((t, _)) = player.next_record().unwrap() {
+        assert!(t >= last, "timestamps out of order");
+        last = t;
+    }
+    let start = Instant::now();
+    replay(SESSION, 2.0);
+    assert!(start.elapsed() >= last / 2);
+}
+
+#[test]
+fn record_and_replay() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.lmrec");
+    let path = path.to_str().unwrap();
+    let events = [
+        Event::Control(ControlEvent::Enter),
+        Event::Pointer(PointerEvent::Absolute {
+            time: 1,
+            // exact on the wire
+            x: 0.0,
+            y: 1.0,
+        }),
+        Event::Keyboard(KeyboardEvent::Modifiers {
+            mods_depressed: 1,
+            mods_latched: 0,
+            mods_locked: 2,
+            group: 0,
+        }),
+        Event::Control(ControlEvent::Leave),
+    ];
+    {
+        let mut recorder = Recorder::create(path).unwrap();
+        recorder.record_keymap(b"keymap").unwrap();
+        for e in &events {
+            recorder.record(e).unwrap();
+        }
+    }
+    let mut player = Player::open(path).unwrap();
+    match player.next_record().unwrap() {
+        Some((_, Record::KeyMap(keymap))) => assert_eq!(keymap, b"keymap"),
+        _ => panic!("keymap not recorded first"),
+    }
+    for e in events {
+        match player.next_record().unwrap() {
+            Some((_, Record::Event(recorded))) => assert_eq!(recorded, e),
+            _ => panic!("missing {:?}", e),
+        }
+    }
+    assert!(player.next_record().unwrap().is_none());
+}
+
+#[test]
+fn not_a_recording() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), b"GIF89a").unwrap();
+    assert!(Player::open(file.path().to_str().unwrap()).is_err());
+}
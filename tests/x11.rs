@@ -0,0 +1,183 @@
+//! XTest emulation against a private `Xvfb`.
+//! Skipped if `Xvfb` is not installed.
+#![cfg(feature = "x11")]
+
+mod common;
+
+use std::{
+    env,
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::TIMEOUT;
+use lan_mouse::{
+    emulation::{x11::XTest, InputEmulation},
+    event::{self, Event, KeyboardEvent, PointerEvent},
+};
+use x11rb::{
+    connection::Connection, protocol::xproto::ConnectionExt, rust_connection::RustConnection,
+};
+
+/// serializes the tests, the display is passed through the environment
+static ENV: Mutex<()> = Mutex::new(());
+
+struct Display {
+    server: Child,
+    conn: RustConnection,
+    root: u32,
+    _env: MutexGuard<'static, ()>,
+}
+
+impl Drop for Display {
+    fn drop(&mut self) {
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+    }
+}
+
+impl Display {
+    /// wait until the pointer of the display is at `pos`
+    fn expect_pointer(&self, pos: (i16, i16)) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let reply = self.conn.query_pointer(self.root).unwrap().reply().unwrap();
+            if (reply.root_x, reply.root_y) == pos {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "pointer at {:?}, not {:?}",
+                (reply.root_x, reply.root_y),
+                pos
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// wait until the X keycode is pressed or released
+    fn expect_key(&self, keycode: u8, pressed: bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let keys = self.conn.query_keymap().unwrap().reply().unwrap().keys;
+            let down = keys[keycode as usize / 8] & (1 << (keycode % 8)) != 0;
+            if down == pressed {
+                return;
+            }
+            assert!(Instant::now() < deadline, "keycode {} not updated", keycode);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// start a 640x480 display and make it the display of this process
+fn xvfb() -> Option<Display> {
+    let guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    let server = Command::new("Xvfb")
+        .args([
+            "-displayfd",
+            "1",
+            "-screen",
+            "0",
+            "640x480x24",
+            "-nolisten",
+            "tcp",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    let mut server = match server {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("skipping, could not run Xvfb: {}", e);
+            return None;
+        }
+    };
+    let mut display = String::new();
+    BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut display)
+        .unwrap();
+    let display = format!(":{}", display.trim());
+    env::set_var("DISPLAY", &display);
+    let (conn, screen) = x11rb::connect(Some(&display)).unwrap();
+    let root = conn.setup().roots[screen].root;
+    Some(Display {
+        server,
+        conn,
+        root,
+        _env: guard,
+    })
+}
+
+fn emulate(xtest: &mut XTest, events: impl IntoIterator<Item = Event>) {
+    for e in events {
+        xtest.consume(e);
+    }
+    xtest.flush().unwrap();
+}
+
+fn absolute(x: f64, y: f64) -> Event {
+    Event::Pointer(PointerEvent::Absolute { time: 0, x, y })
+}
+
+fn motion(dx: f64, dy: f64) -> Event {
+    Event::Pointer(PointerEvent::Motion { time: 0, dx, dy })
+}
+
+fn key(key: u32, state: u32) -> Event {
+    Event::Keyboard(KeyboardEvent::Key {
+        time: 0,
+        key,
+        state,
+    })
+}
+
+#[test]
+fn pointer_motion() {
+    let display = match xvfb() {
+        Some(display) => display,
+        None => return,
+    };
+    let mut xtest = XTest::new().unwrap();
+    emulate(&mut xtest, [absolute(1.0, 1.0)]);
+    display.expect_pointer((639, 479));
+    emulate(&mut xtest, [absolute(0.0, 0.5)]);
+    display.expect_pointer((0, 239));
+
+    // sub-pixel motion adds up
+    emulate(&mut xtest, [motion(10.5, 0.5), motion(0.5, 0.5)]);
+    display.expect_pointer((11, 240));
+
+    // out of the range of X coordinates
+    emulate(&mut xtest, [motion(1e9, -1e9)]);
+    display.expect_pointer((639, 0));
+    emulate(&mut xtest, [motion(-10.0, 10.0)]);
+    display.expect_pointer((629, 10));
+}
+
+#[test]
+fn key_presses() {
+    let display = match xvfb() {
+        Some(display) => display,
+        None => return,
+    };
+    let mut xtest = XTest::new().unwrap();
+    // KEY_A, keycode 38 in X
+    emulate(&mut xtest, [key(30, event::KEY_PRESSED)]);
+    display.expect_key(38, true);
+
+    // without X keycode, skipped
+    emulate(
+        &mut xtest,
+        [
+            key(248, event::KEY_PRESSED),
+            key(u32::MAX, event::KEY_PRESSED),
+        ],
+    );
+    emulate(&mut xtest, [key(30, event::KEY_RELEASED)]);
+    display.expect_key(38, false);
+    xtest.dispatch().unwrap();
+}